@@ -0,0 +1,13 @@
+//! The assignment of reads to the exons of a dense annotation, where every read overlaps the
+//! exons of many genes. Run with `cargo bench`.
+use criterion::{criterion_group, criterion_main, Criterion};
+use gensum::benchmark::DenseAnnotation;
+
+fn mapping(c: &mut Criterion) {
+    let annotation = DenseAnnotation::new(2_000, 10, 10_000);
+    c.bench_function("map_segments", |b| b.iter(|| annotation.map_segments()));
+    c.bench_function("assign_segments", |b| b.iter(|| annotation.assign_segments()));
+}
+
+criterion_group!(benches, mapping);
+criterion_main!(benches);
@@ -1,19 +1,25 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::io::{Write, BufWriter};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{Write, BufRead, BufReader, BufWriter};
 use std::ops::Range;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::cmp::{Ord, PartialOrd, Ordering};
 use std::time::Instant;
 
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::ValueEnum;
-use indexmap::IndexSet;
+use indexmap::{IndexMap, IndexSet};
 use nclist::{NClist, Interval};
-use rust_htslib::{bam, bam::Read, bam::record::Cigar};
+use rust_htslib::{bam, bam::Read, bam::record::{Cigar, CigarString, CigarStringView}, tbx, tbx::Read as _};
 
 use crate::Args;
-use crate::gtf::{GtfReader, GtfRecord, Strand};
+use crate::gtf::{Gff3Index, GtfExon, GtfReader, GtfRecord, Strand};
 
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
@@ -32,37 +38,338 @@ pub enum Strandness {
     Unstranded
 }
 
+/// A read property to split the gene counts on, for '--stratify'
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum Stratum {
+    /// Marked as duplicate or not
+    Dup,
+    /// The fragment on the strand of the gene, or antisense
+    Strand,
+    /// The mapping quality bucket, the lowest of the mates of a pair
+    Mapq,
+}
+
+impl Stratum {
+    const MAPQ_BUCKETS: [(u8, &'static str); 7] = [(0, "0"), (1, "1-9"), (10, "10-19"), (20, "20-29"),
+        (30, "30-39"), (40, "40-59"), (60, "60+")];
+
+    fn name(self) -> &'static str {
+        match self {
+            Stratum::Dup => "dup",
+            Stratum::Strand => "strand",
+            Stratum::Mapq => "mapq",
+        }
+    }
+
+    /// The stratum of a read or pair counted on a gene, as an index of `label`
+    fn index(self, r: &bam::Record, mate: Option<&bam::Record>, gene: &Gene) -> u8 {
+        match self {
+            Stratum::Dup => u8::from(r.is_duplicate() || matches!(mate, Some(m) if m.is_duplicate())),
+            Stratum::Strand => match (gene.strand, Strandness::fragment_forward(r)) {
+                (Strand::Unknown, _) => 2,
+                (Strand::Forward, true) | (Strand::Reverse, false) => 0,
+                _ => 1,
+            },
+            Stratum::Mapq => {
+                let mapq = r.mapq().min(mate.map_or(u8::MAX, |m| m.mapq()));
+                Stratum::MAPQ_BUCKETS.iter().rposition(|b| mapq >= b.0).unwrap_or(0) as u8
+            },
+        }
+    }
+
+    fn label(self, index: u8) -> &'static str {
+        match self {
+            Stratum::Dup => ["nodup", "dup"][index as usize],
+            Stratum::Strand => ["sense", "antisense", "unknown"][index as usize],
+            Stratum::Mapq => Stratum::MAPQ_BUCKETS[index as usize].1,
+        }
+    }
+}
+
+/// The metrics of '--fail-if' conditions. The fractions of alignment records are relative to all
+/// records, see '--funnel', except assigned_frac which is relative to the primary alignments,
+/// rrna_frac and mito_frac are fractions of the assigned fragments.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum QcMetric {
+    Total,
+    Assigned,
+    AssignedFrac,
+    UnmappedFrac,
+    DupFrac,
+    LowMapqFrac,
+    RrnaFrac,
+    MitoFrac,
+}
+
+impl QcMetric {
+    /// The metrics by their name in the conditions
+    const NAMES: [(&'static str, QcMetric); 8] = [("total", QcMetric::Total), ("assigned", QcMetric::Assigned),
+        ("assigned_frac", QcMetric::AssignedFrac), ("unmapped_frac", QcMetric::UnmappedFrac), ("dup_frac", QcMetric::DupFrac),
+        ("low_mapq_frac", QcMetric::LowMapqFrac), ("rrna_frac", QcMetric::RrnaFrac), ("mito_frac", QcMetric::MitoFrac)];
+
+    fn name(self) -> &'static str {
+        QcMetric::NAMES.iter().find(|(_, metric)| *metric == self).unwrap().0
+    }
+}
+
+/// A '--fail-if' expression: conditions like 'assigned_frac<0.5' joined by '&&' into groups, and
+/// groups joined by '||'. A sample fails when all conditions of a group hold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QcGate(Vec<Vec<QcCondition>>);
+
+impl QcGate {
+    /// Whether a condition of the gate compares `metric`
+    pub fn uses(&self, metric: QcMetric) -> bool {
+        self.0.iter().flatten().any(|c| c.metric == metric)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QcCondition {
+    metric: QcMetric,
+    op: QcOp,
+    value: f64,
+}
+
+/// The comparison of a '--fail-if' condition
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum QcOp {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl QcOp {
+    /// The operators by their symbol, the two character symbols first so they are found before
+    /// '<' and '>'
+    const SYMBOLS: [(&'static str, QcOp); 4] = [("<=", QcOp::LessOrEqual), (">=", QcOp::GreaterOrEqual),
+        ("<", QcOp::Less), (">", QcOp::Greater)];
+
+    fn symbol(self) -> &'static str {
+        QcOp::SYMBOLS.iter().find(|(_, op)| *op == self).unwrap().0
+    }
+}
+
+impl QcCondition {
+    fn holds(&self, value: f64) -> bool {
+        match self.op {
+            QcOp::Less => value < self.value,
+            QcOp::LessOrEqual => value <= self.value,
+            QcOp::Greater => value > self.value,
+            QcOp::GreaterOrEqual => value >= self.value,
+        }
+    }
+}
+
+impl std::str::FromStr for QcGate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<QcGate> {
+        let condition = |c: &str| {
+            let (pos, symbol, op) = QcOp::SYMBOLS.iter()
+                .find_map(|&(symbol, op)| c.find(symbol).map(|pos| (pos, symbol, op)))
+                .ok_or_else(|| anyhow!("No comparison (<, <=, > or >=) in '{}'", c.trim()))?;
+            let name = c[..pos].trim();
+            let metric = QcMetric::NAMES.iter().find(|(n, _)| *n == name).map(|&(_, metric)| metric)
+                .ok_or_else(|| {
+                    let names: Vec<_> = QcMetric::NAMES.iter().map(|(n, _)| *n).collect();
+                    anyhow!("Unknown metric '{}', use one of {}", name, names.join(", "))
+                })?;
+            let value = c[pos + symbol.len()..].trim().parse()
+                .map_err(|_| anyhow!("Invalid number in '{}'", c.trim()))?;
+            Ok(QcCondition { metric, op, value })
+        };
+        let groups = s.split("||")
+            .map(|group| group.split("&&").map(condition).collect::<Result<Vec<_>>>())
+            .collect::<Result<Vec<_>>>()?;
+        Ok(QcGate(groups))
+    }
+}
+
+/// The samples that failed the '--fail-if' gate, gensum exits with `QcFailure::EXIT_CODE`
+#[derive(Debug)]
+pub struct QcFailure(pub Vec<(String, String)>);
+
+impl QcFailure {
+    pub const EXIT_CODE: i32 = 3;
+}
+
+/// One tab separated line per failed sample: 'qc_fail', the sample and the conditions that hold
+impl std::fmt::Display for QcFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (sample, reason)) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "qc_fail\t{}\t{}", sample, reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for QcFailure {}
+
+/// What to do with paired reads whose mate is never found as a primary alignment, e.g. because the
+/// mate was filtered or is only present as a secondary or supplementary alignment
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum SingletonPolicy {
+    /// Report them as 'orphan'
+    Skip,
+    /// Count them as a singleton read
+    Count,
+}
+
+/// What to do with zero length or inverted exons in the GTF
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum BadExon {
+    /// Leave the exon out, the gene is kept
+    Skip,
+    /// Swap the start and end coordinates of inverted exons. Zero length exons, which end the
+    /// base before their start, are left out
+    Swap,
+    /// Stop with an error
+    Fail,
+}
+
+impl BadExon {
+    /// Whether an exon that ends before its start is swapped, see `BadExon::Swap`
+    fn swaps(self, start: i64, end: i64) -> bool {
+        self == BadExon::Swap && end < start - 1
+    }
+}
+
+/// Format of the annotation file
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum AnnotFormat {
+    /// Detected from the file name: .gff3 or .gff, .bed, .saf, .refflat, .genepred and
+    /// .interval_list, also when compressed, GTF otherwise
+    Auto,
+    Gtf,
+    Gff3,
+    /// A 6 column BED file, the name column is the gene id
+    Bed,
+    /// A featureCounts SAF file
+    Saf,
+    /// A UCSC refFlat file, the first column is the gene id
+    Refflat,
+    /// A UCSC genePred file, the name2 column of an extended genePred file is the gene id
+    #[value(name = "genepred")]
+    GenePred,
+    /// A Picard interval_list, the name column is the gene id
+    #[value(name = "interval_list")]
+    IntervalList,
+}
+
+impl AnnotFormat {
+    fn detect(self, p: &Path) -> AnnotFormat {
+        if self != AnnotFormat::Auto {
+            return self;
+        }
+        let name = p.file_name().map(|n| n.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+        let name = [".gz", ".bgz", ".bz2", ".xz", ".zst"].iter()
+            .find_map(|ext| name.strip_suffix(ext))
+            .unwrap_or(&name);
+        if name.ends_with(".gff3") || name.ends_with(".gff") {
+            AnnotFormat::Gff3
+        } else if name.ends_with(".bed") {
+            AnnotFormat::Bed
+        } else if name.ends_with(".saf") {
+            AnnotFormat::Saf
+        } else if name.ends_with(".refflat") {
+            AnnotFormat::Refflat
+        } else if name.ends_with(".genepred") {
+            AnnotFormat::GenePred
+        } else if name.ends_with(".interval_list") {
+            AnnotFormat::IntervalList
+        } else {
+            AnnotFormat::Gtf
+        }
+    }
+}
+
+/// Naming convention of the summary categories
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum SummaryStyle {
+    Gensum,
+    /// htseq-count, e.g. '__no_feature'
+    Htseq,
+    /// the featureCounts .summary file, e.g. 'Unassigned_NoFeatures'
+    Featurecounts,
+}
+
+impl SummaryStyle {
+    /// All summary categories of the style, in the order the tool writes them
+    fn categories(self) -> &'static [&'static str] {
+        match self {
+            SummaryStyle::Gensum => &[],
+            SummaryStyle::Htseq => &["__no_feature", "__ambiguous", "__too_low_aQual", "__not_aligned",
+                "__alignment_not_unique"],
+            SummaryStyle::Featurecounts => &["Assigned", "Unassigned_Unmapped", "Unassigned_Read_Type",
+                "Unassigned_Singleton", "Unassigned_MappingQuality", "Unassigned_Chimera",
+                "Unassigned_FragmentLength", "Unassigned_Duplicate", "Unassigned_MultiMapping",
+                "Unassigned_Secondary", "Unassigned_NonSplit", "Unassigned_NoFeatures",
+                "Unassigned_Overlapping_Length", "Unassigned_Ambiguity"],
+        }
+    }
+
+    /// The name of a summary category in this style, None if the other tool has no equivalent
+    fn rename(self, category: &str) -> Option<&'static str> {
+        match (self, category) {
+            (SummaryStyle::Htseq, "unmapped") => Some("__not_aligned"),
+            // htseq-count applies its quality filter before the assignment, like our filters
+            (SummaryStyle::Htseq, "low_mapq" | "low_hq_overlap" | "filtered") => Some("__too_low_aQual"),
+            (SummaryStyle::Htseq, "secondary_alignments") => Some("__alignment_not_unique"),
+            (SummaryStyle::Htseq, "ambiguous" | "ambiguous_pair") => Some("__ambiguous"),
+            // reads rejected on their introns overlap no gene they fit, like a read without feature
+            (SummaryStyle::Htseq, "chr_not_in_gtf" | "contaminant" | "wrong_strand" | "nohit" | "intronic"
+                | "long_intron" | "intron_mismatch") => Some("__no_feature"),
+            (SummaryStyle::Featurecounts, "unmapped") => Some("Unassigned_Unmapped"),
+            (SummaryStyle::Featurecounts, "filtered") => Some("Unassigned_Read_Type"),
+            (SummaryStyle::Featurecounts, "low_mapq") => Some("Unassigned_MappingQuality"),
+            (SummaryStyle::Featurecounts, "secondary_alignments") => Some("Unassigned_Secondary"),
+            (SummaryStyle::Featurecounts, "marked_duplicated") => Some("Unassigned_Duplicate"),
+            (SummaryStyle::Featurecounts, "ambiguous" | "ambiguous_pair") => Some("Unassigned_Ambiguity"),
+            (SummaryStyle::Featurecounts, "chr_not_in_gtf" | "contaminant" | "wrong_strand" | "nohit" | "intronic"
+                | "long_intron" | "intron_mismatch") => Some("Unassigned_NoFeatures"),
+            (SummaryStyle::Featurecounts, "orphan") => Some("Unassigned_Singleton"),
+            (SummaryStyle::Featurecounts, "low_hq_overlap") => Some("Unassigned_Overlapping_Length"),
+            _ => None,
+        }
+    }
+}
+
 impl Strandness {
+    /// Determine if the fragment of a read is forward, asuming a FR library ( --->____<--- ).
+    /// Computed once per read and passed to `matches`.
     #[inline]
-    fn matches_bam_record(self, r: &bam::Record, target: Strand) -> bool {
-        if self == Strandness::Unstranded {
-            return true;
-        }
-        
-        // Asuming a FR library ( --->____<--- )
-        // Determine if fragment is forward
-        let fragment_forward = if r.is_paired() {
+    fn fragment_forward(r: &bam::Record) -> bool {
+        if r.is_paired() {
             (r.is_first_in_template() && !r.is_reverse()) ||
                 (r.is_last_in_template() && r.is_reverse())
         } else {
             !r.is_reverse()
-        };
+        }
+    }
 
+    #[inline]
+    fn matches(self, fragment_forward: bool, target: Strand) -> bool {
         match (self, target) {
-            (_, Strand::Unknown) => true,
+            (Strandness::Unstranded, _) | (_, Strand::Unknown) => true,
             (Strandness::Forward, Strand::Forward) |
             (Strandness::Reverse, Strand::Reverse) => fragment_forward,
             (Strandness::Forward, Strand::Reverse) |
             (Strandness::Reverse, Strand::Forward) => !fragment_forward,
-            (Strandness::Unstranded, _) => unreachable!(),
         }
     }
 }
 
-/// Exon is defined by its coordinates and references a parent Gene
-#[derive(Debug, Eq, PartialEq)]
-struct Exon {
-    id: usize,
+/// Exon is defined by its coordinates and references a parent Gene.
+/// The gene index is stored as u32 to keep the exon small, the interval search is
+/// bound by memory access. The NClist keeps the exons of a chromosome in one vector.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Exon {
+    id: u32,
     strand: Strand,
     range: Range<i64>,
 }
@@ -77,9 +384,18 @@ impl Ord  for Exon {
 
 impl PartialOrd  for Exon {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.id.cmp(&other.id)
-            .then(self.range.start.cmp(&other.range.start))
-            .then(self.range.end.cmp(&other.range.end)))
+        Some(self.cmp(other))
+    }
+}
+
+impl Exon {
+    pub(crate) fn new(gene: usize, strand: Strand, range: Range<i64>) -> Exon {
+        Exon { id: gene as u32, strand, range }
+    }
+
+    #[inline]
+    fn gene(&self) -> usize {
+        self.id as usize
     }
 }
 
@@ -94,228 +410,3862 @@ impl Interval for Exon {
     }
 }
 
-fn get_index_or_insert_owned(map: &mut IndexSet<Vec<u8>>, v: &[u8]) -> usize {
-    if !map.contains(v) {
-        map.insert_full(v.to_owned()).0
-    } else {
-        map.get_full(v).unwrap().0
+/// An exon with its position in the transcripts of the gene, as a combination of the class bits
+#[derive(Debug)]
+struct ExonPosition {
+    id: u32,
+    class: u8,
+    range: Range<i64>,
+}
+
+impl ExonPosition {
+    const FIRST: u8 = 1;
+    const INTERNAL: u8 = 2;
+    const LAST: u8 = 4;
+}
+
+impl Interval for ExonPosition {
+    type Coord = i64;
+    fn start(&self) -> &Self::Coord {
+        &self.range.start
+    }
+
+    fn end(&self) -> &Self::Coord {
+        &self.range.end
     }
 }
 
-pub struct GeneMap {
-    genes: IndexSet<Vec<u8>>,
-    seq_names: IndexSet<Vec<u8>>,
-    intervals: Vec<NClist<Exon>>,
+/// Read a list of genes, one per line. Empty lines and lines starting with '#' are skipped.
+pub fn read_gene_list<P: AsRef<Path>>(p: P) -> Result<Vec<String>> {
+    let mut genes = Vec::new();
+    for line in BufReader::new(File::open(p)?).lines() {
+        let line = line?;
+        let gene = line.trim();
+        if !gene.is_empty() && !gene.starts_with('#') {
+            genes.push(gene.to_owned());
+        }
+    }
+    Ok(genes)
 }
 
-impl GeneMap {
-    pub fn from_gtf<P: AsRef<Path>>(p: P) -> Result<GeneMap> {
-        //open gtf
-        let t0 = Instant::now();
-        let (r, _compression) = niffler::from_path(p)?;
-        let mut reader = GtfReader::new(r);
-        
-        let mut genes = IndexSet::new();
-        let mut seq_names = IndexSet::new();
-        let mut exons = Vec::new();
+/// Read a table of genes and a value, e.g. their group, separated by a tab. A gene may be listed
+/// several times. Empty lines and lines starting with '#' are skipped.
+pub fn read_gene_table<P: AsRef<Path>>(p: P) -> Result<Vec<(String, String)>> {
+    let mut table = Vec::new();
+    for (n, line) in BufReader::new(File::open(p)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (gene, group) = line.split_once('\t')
+            .ok_or_else(|| anyhow!("Line {} does not have a gene and a second column: {}", n + 1, line))?;
+        table.push((gene.to_owned(), group.to_owned()));
+    }
+    Ok(table)
+}
 
+/// Read a truth table of gene ids or names and their expected counts, for `compare_truth`
+pub fn read_truth<P: AsRef<Path>>(p: P) -> Result<Vec<(String, f64)>> {
+    read_gene_table(p)?.into_iter()
+        .map(|(gene, expected)| match expected.parse() {
+            Ok(e) => Ok((gene, e)),
+            Err(_) => Err(anyhow!("Invalid expected count for {}: {}", gene, expected)),
+        })
+        .collect()
+}
 
-        //iterate records
-        let mut record = GtfRecord::new();
-        let mut n = 0;
-        loop {
-            if reader.read_record(&mut record)? == 0 {
-                break;
+/// Call `f` with every exon of an annotation file and the record it was parsed from, a refFlat or
+/// genePred record holds all exons of a transcript. A GFF3 file is read twice, first to find the
+/// genes of the transcripts. With `contigs` only those contigs are read from a tabix indexed file.
+/// The ids that '--strip-gene-version' merged from several versions are added to `merged`.
+/// Returns the number of records.
+fn read_exons<P, F>(p: P, format: AnnotFormat, contigs: Option<&IndexSet<Vec<u8>>>, options: &AnnotationOptions, merged: &mut IndexSet<Vec<u8>>, mut f: F) -> Result<usize>
+    where P: AsRef<Path>, F: FnMut(&GtfRecord, GtfExon<'_>) -> Result<()>
+{
+    let p = p.as_ref();
+    let format = format.detect(p);
+    if options.selects_transcripts() && format != AnnotFormat::Gtf {
+        return Err(anyhow!("--tag-filter and --min-tsl need the attributes of a GTF file, {} is not a GTF file", p.display()));
+    }
+    if options.selects_biotypes() && !matches!(format, AnnotFormat::Gtf | AnnotFormat::Gff3) {
+        return Err(anyhow!("--include-biotype and --exclude-biotype need the gene biotypes of a GTF or GFF3 file, {} has none", p.display()));
+    }
+    // the first versioned id of each id without version
+    let mut versions: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    let mut f = |record: &GtfRecord, exon: GtfExon<'_>| {
+        if !options.accepts(&exon) {
+            return Ok(());
+        }
+        if options.strip_gene_version {
+            let id = strip_gene_version(exon.id);
+            match versions.get(id.as_ref()) {
+                Some(v) if v != exon.id && !merged.contains(id.as_ref()) => {
+                    merged.insert(id.to_vec());
+                },
+                Some(_) => (),
+                None => {
+                    versions.insert(id.to_vec(), exon.id.to_vec());
+                },
             }
-            n += 1;
+            return f(record, GtfExon { id: &id, ..exon });
+        }
+        f(record, exon)
+    };
+    if let Some(contigs) = contigs {
+        return read_indexed_exons(p, format, contigs, &options.id_attrs, f);
+    }
+    // a GFF3 file is read twice
+    let _stdin = if format == AnnotFormat::Gff3 { BufferedStdin::new(p)? } else { None };
+    let index = match format {
+        AnnotFormat::Gff3 => Some(Gff3Index::read(open_annotation(p)?)?),
+        _ => None,
+    };
 
-            if let Some(r) = record.parse_exon()? {
-                let gene_idx = get_index_or_insert_owned(&mut genes, r.id);
-                let chr_idx = get_index_or_insert_owned(&mut seq_names, r.seq_name);
+    let mut reader = GtfReader::new(open_annotation(p)?);
+    let mut record = GtfRecord::new();
+    let mut n = 0;
+    while reader.read_record(&mut record)? > 0 {
+        n += 1;
+        parse_exons(&record, format, index.as_ref(), &options.id_attrs, &mut f)
+            .with_context(|| format!("Line {} of {}", reader.line(), p.display()))?;
+    }
+    Ok(n)
+}
+
+/// Check an annotation file without stopping at the first problem. Writes a line per problem with
+/// its line number, kind and description: records that can not be parsed, invalid columns of GTF
+/// and GFF3 lines of any feature type, zero length or inverted exons, exons listed twice for a
+/// transcript, genes on several chromosomes or strands and GTF attributes the parser works
+/// around. A GFF3 file that can not be indexed is reported at line 0. Returns the number of
+/// problems.
+pub fn validate_annotation<W: Write>(p: &Path, format: AnnotFormat, o: W) -> Result<usize> {
+    struct Found {
+        gene: Vec<u8>,
+        chr: Vec<u8>,
+        range: (i64, i64),
+        strand: Strand,
+        transcript: Option<Vec<u8>>,
+        attribute_problems: Vec<String>,
+    }
 
-                 if r.end - r.start < 0 {
-                     eprintln!("Skipping zero/negative width exon: {}", record);
-                     continue;
-                 }
+    let format = format.detect(p);
+    let _stdin = if format == AnnotFormat::Gff3 { BufferedStdin::new(p)? } else { None };
+    let index = match format {
+        AnnotFormat::Gff3 => Some(Gff3Index::read(open_annotation(p)?)),
+        _ => None,
+    };
+    let id_attrs = [b"gene_id".to_vec()];
+    // the chromosome, strand and first line of each gene, and the first line of each exon of
+    // a transcript
+    let mut genes: HashMap<Vec<u8>, (Vec<u8>, Strand, usize)> = HashMap::new();
+    let mut exons: HashMap<(Vec<u8>, Vec<u8>, i64, i64), usize> = HashMap::new();
 
-                if exons.len() == chr_idx {
-                    exons.push(Vec::new());
+    let mut w = BufWriter::new(o);
+    let mut problems = 0;
+    let mut report = |line: usize, kind: &str, message: &str| -> Result<()> {
+        problems += 1;
+        writeln!(w, "{}\t{}\t{}", line, kind, message)?;
+        Ok(())
+    };
+    let index = match index {
+        Some(Err(e)) => {
+            report(0, "malformed", &format!("the GFF3 file can not be indexed: {:#}", e))?;
+            w.flush()?;
+            eprintln!("{} problems, the file was not checked further", problems);
+            return Ok(problems);
+        },
+        Some(Ok(index)) => Some(index),
+        None => None,
+    };
+    let columns = matches!(format, AnnotFormat::Gtf | AnnotFormat::Gff3);
+    let mut reader = GtfReader::new(open_annotation(p)?);
+    let mut record = GtfRecord::new();
+    let mut found = Vec::new();
+    while reader.read_record(&mut record)? > 0 {
+        let line = reader.line();
+        found.clear();
+        let parsed = parse_exons(&record, format, index.as_ref(), &id_attrs, &mut |_, exon| {
+            found.push(Found {
+                gene: exon.id.to_vec(),
+                chr: exon.seq_name.to_vec(),
+                range: (exon.start, exon.end),
+                strand: exon.strand,
+                transcript: exon.transcript_id.map(|t| t.to_vec()),
+                attribute_problems: exon.attribute_problems(),
+            });
+            Ok(())
+        });
+        if let Err(e) = parsed {
+            // the message ends with the record, keep it on one line of the list
+            report(line, "malformed", format!("{:#}", e).trim_end().replace('\t', " ").as_str())?;
+            continue;
+        }
+        if columns {
+            for problem in record.column_problems() {
+                report(line, "columns", &problem)?;
+            }
+        }
+        for exon in found.drain(..) {
+            let gene = String::from_utf8_lossy(&exon.gene).into_owned();
+            for problem in &exon.attribute_problems {
+                report(line, "attribute", &format!("{} of gene {}", problem, gene))?;
+            }
+            if exon.range.1 < exon.range.0 {
+                report(line, "inverted_exon", &format!("zero length or inverted exon {}-{} of gene {}", exon.range.0, exon.range.1, gene))?;
+            }
+            match genes.get(&exon.gene) {
+                Some((chr, _, first)) if *chr != exon.chr => report(line, "gene_chromosomes", &format!("gene {} on {} and on {} at line {}",
+                    gene, String::from_utf8_lossy(&exon.chr), String::from_utf8_lossy(chr), first))?,
+                Some((_, strand, first)) if *strand != exon.strand => report(line, "gene_strands", &format!("gene {} on both strands, see line {}", gene, first))?,
+                Some(_) => (),
+                None => {
+                    genes.insert(exon.gene.clone(), (exon.chr.clone(), exon.strand, line));
+                },
+            }
+            if let Some(transcript) = exon.transcript {
+                let key = (transcript, exon.chr, exon.range.0, exon.range.1);
+                if let Some(first) = exons.get(&key) {
+                    report(line, "duplicate_exon", &format!("exon {}-{} of transcript {} is also on line {}",
+                        exon.range.0, exon.range.1, String::from_utf8_lossy(&key.0), first))?;
+                } else {
+                    exons.insert(key, line);
                 }
+            }
+        }
+    }
+    w.flush()?;
+    eprintln!("{} problems in the exons of {} genes", problems, genes.len());
+    Ok(problems)
+}
 
-                // gtf exon coordinates are 1 based and closed end
-                // bam files are 0 based, and nclist expects half open
-                exons[chr_idx].push(Exon {id: gene_idx, strand: r.strand, range: r.start-1..r.end });
+/// Write the records of a GTF file of the genes with at least `min_count` reads in a gensum
+/// output, in any sample column of a count matrix. The gene id of a record is found like when
+/// counting, by `id_attrs` and without version when `strip_version`. Comment lines are kept.
+pub fn subset_gtf<W: Write>(gtf: &Path, counts: &Path, min_count: f64, id_attrs: &[String], strip_version: bool, o: W) -> Result<()> {
+    let mut expressed = HashSet::new();
+    // without a header line every column is a count, with one only the sample columns
+    let mut count_columns: Option<Vec<bool>> = None;
+    for line in BufReader::new(File::open(counts)?).lines() {
+        let line = line?;
+        let mut fields = line.split('\t');
+        let gene = fields.next().unwrap_or_default();
+        if gene == "gene_id" && count_columns.is_none() {
+            count_columns = Some(fields.map(|c| c != "gc" && !c.ends_with("_tss") && !c.ends_with("_reads")).collect());
+            continue;
+        }
+        let expressed_in = |(i, v): (usize, &str)| {
+            let counted = match count_columns.as_ref() {
+                Some(columns) => columns.get(i) == Some(&true),
+                None => true,
+            };
+            counted && matches!(v.parse::<f64>(), Ok(c) if c >= min_count)
+        };
+        if !gene.starts_with('#') && fields.enumerate().any(expressed_in) {
+            expressed.insert(gene.as_bytes().to_vec());
+        }
+    }
+
+    let mut reader = BufReader::new(open_annotation(gtf)?);
+    let mut w = BufWriter::new(o);
+    let mut record = GtfRecord::new();
+    let (mut genes, mut kept) = (HashSet::new(), 0);
+    while reader.read_until(b'\n', record.clear_buf_mut())? > 0 {
+        let id = record.gene_key(id_attrs).map(|id| if strip_version { strip_gene_version(id) } else { Cow::Borrowed(id) });
+        let keep = match id {
+            Some(id) if expressed.contains(id.as_ref()) => {
+                genes.insert(id.into_owned());
+                true
+            },
+            _ => record.is_comment(),
+        };
+        if keep {
+            kept += 1;
+            w.write_all(record.as_bytes())?;
+        }
+    }
+    w.flush()?;
+    eprintln!("Wrote {} records of {} genes with at least {} reads", kept, genes.len(), min_count);
+    Ok(())
+}
+
+/// The start of a file written by `GeneMap::write_index`, the last byte is the format version.
+/// Version 2 added the gene source.
+const INDEX_MAGIC: &[u8; 8] = b"GSUMIDX\x02";
+
+/// Whether a file is a gensum index of any version, by its first bytes
+fn is_index(p: &Path) -> Result<bool> {
+    use std::io::Read as _;
+    if p == Path::new("-") {
+        return Ok(false);
+    }
+    let mut magic = Vec::with_capacity(INDEX_MAGIC.len());
+    File::open(p)?.take(INDEX_MAGIC.len() as u64).read_to_end(&mut magic)?;
+    Ok(magic.len() == INDEX_MAGIC.len() && magic[..magic.len() - 1] == INDEX_MAGIC[..INDEX_MAGIC.len() - 1])
+}
+
+fn strand_code(s: Strand) -> i64 {
+    match s {
+        Strand::Forward => 0,
+        Strand::Reverse => 1,
+        Strand::Unknown => 2,
+    }
+}
+
+fn strand_from_code(c: i64) -> Option<Strand> {
+    [Strand::Forward, Strand::Reverse, Strand::Unknown].get(usize::try_from(c).ok()?).copied()
+}
+
+fn put_int<W: Write>(w: &mut W, v: i64) -> Result<()> {
+    w.write_all(&v.to_le_bytes())?;
+    Ok(())
+}
+
+fn put_bytes<W: Write>(w: &mut W, b: &[u8]) -> Result<()> {
+    put_int(w, b.len() as i64)?;
+    w.write_all(b)?;
+    Ok(())
+}
+
+fn take_int<R: std::io::Read>(r: &mut R, invalid: &dyn Fn() -> anyhow::Error) -> Result<i64> {
+    let mut b = [0; 8];
+    r.read_exact(&mut b).map_err(|_| invalid())?;
+    Ok(i64::from_le_bytes(b))
+}
+
+/// Read a value written by `put_bytes`, or None for a negative length
+fn take_bytes<R: std::io::Read>(r: &mut R, invalid: &dyn Fn() -> anyhow::Error) -> Result<Option<Vec<u8>>> {
+    use std::io::Read as _;
+    let len = match usize::try_from(take_int(r, invalid)?) {
+        Ok(len) => len,
+        Err(_) => return Ok(None),
+    };
+    let mut b = Vec::new();
+    r.take(len as u64).read_to_end(&mut b)?;
+    if b.len() != len {
+        return Err(invalid());
+    }
+    Ok(Some(b))
+}
+
+/// The annotation read from stdin while a `BufferedStdin` keeps it for another pass
+static STDIN: Mutex<Option<Arc<[u8]>>> = Mutex::new(None);
+
+/// Keeps the annotation of stdin in memory, so `open_annotation` can read it again, until dropped
+struct BufferedStdin;
+
+impl BufferedStdin {
+    /// Read stdin into memory when the annotation is read from it, `None` when it is not or when
+    /// it is already buffered
+    fn new(p: &Path) -> Result<Option<BufferedStdin>> {
+        use std::io::Read as _;
+        if p != Path::new("-") || STDIN.lock().unwrap().is_some() {
+            return Ok(None);
+        }
+        let mut buf = Vec::new();
+        niffler::get_reader(Box::new(std::io::stdin()))?.0.read_to_end(&mut buf)?;
+        *STDIN.lock().unwrap() = Some(buf.into());
+        Ok(Some(BufferedStdin))
+    }
+}
+
+impl Drop for BufferedStdin {
+    fn drop(&mut self) {
+        *STDIN.lock().unwrap() = None;
+    }
+}
+
+/// Open an annotation file and decompress it. The path '-' is stdin, which can only be read once
+/// unless a `BufferedStdin` holds it.
+fn open_annotation(p: &Path) -> Result<Box<dyn std::io::Read + Send>> {
+    if p != Path::new("-") {
+        return Ok(niffler::from_path(p)?.0);
+    }
+    match STDIN.lock().unwrap().clone() {
+        Some(buf) => Ok(Box::new(std::io::Cursor::new(buf))),
+        None => Ok(niffler::get_reader(Box::new(std::io::stdin()))?.0),
+    }
+}
+
+/// Call `f` with the exons of the `contigs` of a tabix indexed annotation file, like `read_exons`
+fn read_indexed_exons<F>(p: &Path, format: AnnotFormat, contigs: &IndexSet<Vec<u8>>, id_attrs: &[Vec<u8>], mut f: F) -> Result<usize>
+    where F: FnMut(&GtfRecord, GtfExon<'_>) -> Result<()>
+{
+    if format == AnnotFormat::Gff3 {
+        return Err(anyhow!("A GFF3 file can not be read by contig, the parents of the exons may be anywhere in the file"));
+    }
+    if p == Path::new("-") {
+        return Err(anyhow!("--indexed-gtf requires an indexed file, it can not be used with stdin"));
+    }
+    let mut reader = tbx::Reader::from_path(p)
+        .map_err(|e| anyhow!("Cannot read the tabix index of {}: {}", p.display(), e))?;
+    let mut record = GtfRecord::new();
+    let mut n = 0;
+    for contig in contigs {
+        let tid = match std::str::from_utf8(contig).ok().and_then(|c| reader.tid(c).ok()) {
+            Some(tid) => tid,
+            None => continue,
+        };
+        reader.fetch(tid, 0, i64::MAX as u64)?;
+        while reader.read(record.clear_buf_mut())? {
+            n += 1;
+            parse_exons(&record, format, None, id_attrs, &mut f)
+                .with_context(|| format!("Contig {} of {}", String::from_utf8_lossy(contig), p.display()))?;
+        }
+    }
+    Ok(n)
+}
+
+/// How the exons are read from an annotation file: the transcripts selected by '--tag-filter'
+/// and '--min-tsl', the genes selected by '--include-biotype' and '--exclude-biotype', and the
+/// gene ids from the '--id-attrs' attributes, without version with '--strip-gene-version'
+#[derive(Debug)]
+struct AnnotationOptions {
+    tag: Option<Vec<u8>>,
+    max_support_level: Option<u8>,
+    include_biotypes: Vec<Vec<u8>>,
+    exclude_biotypes: Vec<Vec<u8>>,
+    id_attrs: Vec<Vec<u8>>,
+    strip_gene_version: bool,
+    /// the '--intron-tolerance' of '--validate-introns', the introns are read with the exons
+    intron_tolerance: Option<f64>,
+}
+
+impl Default for AnnotationOptions {
+    fn default() -> AnnotationOptions {
+        AnnotationOptions {
+            tag: None,
+            max_support_level: None,
+            include_biotypes: Vec::new(),
+            exclude_biotypes: Vec::new(),
+            id_attrs: vec![b"gene_id".to_vec()],
+            strip_gene_version: false,
+            intron_tolerance: None,
+        }
+    }
+}
+
+impl AnnotationOptions {
+    fn new(config: &Args) -> AnnotationOptions {
+        AnnotationOptions {
+            tag: config.tag_filter.as_ref().map(|t| t.as_bytes().to_vec()),
+            max_support_level: config.min_tsl,
+            include_biotypes: config.include_biotype.iter().map(|b| b.as_bytes().to_vec()).collect(),
+            exclude_biotypes: config.exclude_biotype.iter().map(|b| b.as_bytes().to_vec()).collect(),
+            id_attrs: config.id_attrs.iter().map(|a| a.as_bytes().to_vec()).collect(),
+            strip_gene_version: config.strip_gene_version,
+            intron_tolerance: Some(config.intron_tolerance).filter(|_| config.validate_introns),
+        }
+    }
+
+    fn is_default(&self) -> bool {
+        !self.selects_transcripts() && !self.selects_biotypes() && !self.strip_gene_version && self.id_attrs == [b"gene_id"]
+    }
+
+    fn selects_biotypes(&self) -> bool {
+        !self.include_biotypes.is_empty() || !self.exclude_biotypes.is_empty()
+    }
+
+    fn selects_transcripts(&self) -> bool {
+        self.tag.is_some() || self.max_support_level.is_some()
+    }
+
+    /// Transcripts without a support level (NA) are rejected by '--min-tsl', genes without a
+    /// biotype by '--include-biotype'
+    fn accepts(&self, exon: &GtfExon<'_>) -> bool {
+        if self.selects_biotypes() {
+            let listed = |biotypes: &[Vec<u8>]| matches!(exon.biotype, Some(b) if biotypes.iter().any(|t| t == b));
+            if (!self.include_biotypes.is_empty() && !listed(&self.include_biotypes)) || listed(&self.exclude_biotypes) {
+                return false;
+            }
+        }
+        let tagged = match &self.tag {
+            Some(t) => exon.has_tag(t),
+            None => true,
+        };
+        let supported = match self.max_support_level {
+            Some(max) => matches!(exon.support_level(), Some(l) if l <= max),
+            None => true,
+        };
+        tagged && supported
+    }
+}
+
+/// The gene id without the version of Ensembl and GENCODE ids, e.g. ENSG00000182378 for
+/// ENSG00000182378.14, and ENSG00000182378_PAR_Y for the GENCODE id ENSG00000182378.14_PAR_Y.
+/// Only ids of letters followed by digits have a version, the dot of e.g. RP11-34P13.5 is kept.
+fn strip_gene_version(id: &[u8]) -> Cow<'_, [u8]> {
+    let dot = match memchr::memrchr(b'.', id) {
+        Some(dot) => dot,
+        None => return Cow::Borrowed(id),
+    };
+    let letters = id[..dot].iter().take_while(|c| c.is_ascii_alphabetic()).count();
+    if letters == 0 || letters == dot || !id[letters..dot].iter().all(u8::is_ascii_digit) {
+        return Cow::Borrowed(id);
+    }
+    let digits = id[dot + 1..].iter().take_while(|c| c.is_ascii_digit()).count();
+    let rest = &id[dot + 1 + digits..];
+    if digits == 0 || !(rest.is_empty() || rest.starts_with(b"_")) {
+        return Cow::Borrowed(id);
+    }
+    match rest.is_empty() {
+        true => Cow::Borrowed(&id[..dot]),
+        false => Cow::Owned([&id[..dot], rest].concat()),
+    }
+}
+
+/// Call `f` with the exons of an annotation record, see `GtfRecord::parse_exon` for `id_attrs`
+fn parse_exons<F>(record: &GtfRecord, format: AnnotFormat, index: Option<&Gff3Index>, id_attrs: &[Vec<u8>], f: &mut F) -> Result<()>
+    where F: FnMut(&GtfRecord, GtfExon<'_>) -> Result<()>
+{
+    let exon = match (format, index) {
+        (AnnotFormat::Gff3, Some(index)) => record.parse_gff3_exon(index)?,
+        (AnnotFormat::Bed, _) => record.parse_bed_exon()?,
+        (AnnotFormat::Saf, _) => record.parse_saf_exon()?,
+        (AnnotFormat::IntervalList, _) => record.parse_interval_list_exon()?,
+        (AnnotFormat::Refflat | AnnotFormat::GenePred, _) => {
+            for exon in record.parse_gene_pred_exons(format == AnnotFormat::Refflat)? {
+                f(record, exon)?;
+            }
+            return Ok(());
+        },
+        _ => record.parse_exon(id_attrs)?,
+    };
+    if let Some(exon) = exon {
+        f(record, exon)?;
+    }
+    Ok(())
+}
+
+/// The contig names in the headers of the bam files
+fn bam_contigs(bams: &[PathBuf], aliases: bool) -> Result<IndexSet<Vec<u8>>> {
+    let mut contigs = IndexSet::new();
+    for p in bams {
+        let bam = match bam_url(p)? {
+            Some(url) => bam::Reader::from_url(&url)?,
+            None if p == Path::new("-") => return Err(anyhow!("--indexed-gtf reads the BAM header first, it can not be used with stdin")),
+            None => bam::Reader::from_path(p)?,
+        };
+        contigs.extend(bam.header().target_names().into_iter().map(|n| n.to_vec()));
+    }
+    if aliases {
+        let aliases: Vec<_> = contigs.iter().filter_map(|c| chr_alias(c)).collect();
+        contigs.extend(aliases);
+    }
+    Ok(contigs)
+}
+
+/// The name of a chromosome in the other of the UCSC and Ensembl conventions, e.g. chr1 and 1,
+/// chrM and MT
+fn chr_alias(name: &[u8]) -> Option<Vec<u8>> {
+    match name {
+        b"chrM" => Some(b"MT".to_vec()),
+        b"MT" => Some(b"chrM".to_vec()),
+        _ => match name.strip_prefix(b"chr") {
+            Some(n) if !n.is_empty() => Some(n.to_vec()),
+            Some(_) => None,
+            None => Some([&b"chr"[..], name].concat()),
+        },
+    }
+}
+
+fn strand_char(strand: Strand) -> char {
+    match strand {
+        Strand::Forward => '+',
+        Strand::Reverse => '-',
+        Strand::Unknown => '.',
+    }
+}
+
+/// The GC fraction of the called bases of `seq` in `regions`
+fn gc_fraction(seq: &[u8], regions: &[Range<i64>]) -> Option<f64> {
+    let (mut gc, mut called) = (0u64, 0u64);
+    for r in regions {
+        let end = (r.end.max(0) as usize).min(seq.len());
+        let start = (r.start.max(0) as usize).min(end);
+        for b in &seq[start..end] {
+            match b.to_ascii_uppercase() {
+                b'G' | b'C' => { gc += 1; called += 1 },
+                b'A' | b'T' => called += 1,
+                _ => (),
             }
         }
-        let gtftime = t0.elapsed();
+    }
+    (called > 0).then(|| gc as f64 / called as f64)
+}
+
+/// The length of the read starts and gene windows compared by '--gc-bias'. The GC of a fragment
+/// is estimated by the first bases of its reads: the expected distribution needs windows of a
+/// fixed length along the gene models, and the fragment length varies and is unknown for
+/// single-end reads. The read bases are also counted without the reference in memory.
+const GC_WINDOW: usize = 50;
+
+/// The '--gc-bias' distance above which a warning is given, a quarter of the reads would have to
+/// move to another GC percentage to remove the bias
+pub const GC_BIAS_WARNING: f64 = 0.25;
+
+/// The minimum number of sense and antisense reads of a gene in the '--strand-report', below it
+/// the antisense fraction of a gene is too noisy to compare with the sample
+const STRAND_REPORT_MIN_READS: Count = 20;
+
+/// The antisense fraction above the median of the sample from which a gene is in the
+/// '--strand-report'
+pub const STRAND_REPORT_EXCESS: f64 = 0.5;
+
+/// The median antisense fraction above which most reads are antisense, and the '--strandness' of
+/// the library is more likely to be swapped than the genes
+pub const STRAND_REPORT_SWAPPED_MEDIAN: f64 = 0.5;
+
+/// The number of windows of `GC_WINDOW` bases along the concatenated `regions` of `seq` by their
+/// number of G and C bases. Windows with other bases than A, C, G and T are left out.
+fn gc_windows(seq: &[u8], regions: &[Range<i64>]) -> Vec<u32> {
+    let mut windows = vec![0; GC_WINDOW + 1];
+    let bases: Vec<u8> = regions.iter()
+        .flat_map(|r| {
+            let end = (r.end.max(0) as usize).min(seq.len());
+            let start = (r.start.max(0) as usize).min(end);
+            &seq[start..end]
+        })
+        .map(|b| b.to_ascii_uppercase())
+        .collect();
+    let (mut gc, mut other) = (0, 0);
+    for (i, b) in bases.iter().enumerate() {
+        match b {
+            b'G' | b'C' => gc += 1,
+            b'A' | b'T' => (),
+            _ => other += 1,
+        }
+        if i >= GC_WINDOW {
+            match bases[i - GC_WINDOW] {
+                b'G' | b'C' => gc -= 1,
+                b'A' | b'T' => (),
+                _ => other -= 1,
+            }
+        }
+        if i + 1 >= GC_WINDOW && other == 0 {
+            windows[gc] += 1;
+        }
+    }
+    windows
+}
+
+/// The number of G and C bases in the first `GC_WINDOW` bases of a read, None for shorter reads
+/// or with other bases than A, C, G and T
+fn read_gc(r: &bam::Record) -> Option<usize> {
+    let seq = r.seq();
+    if seq.len() < GC_WINDOW {
+        return None;
+    }
+    let mut gc = 0;
+    for i in 0..GC_WINDOW {
+        match seq[i] {
+            b'G' | b'C' => gc += 1,
+            b'A' | b'T' => (),
+            _ => return None,
+        }
+    }
+    Some(gc)
+}
+
+fn get_index_or_insert_owned(map: &mut IndexSet<Vec<u8>>, v: &[u8]) -> usize {
+    if !map.contains(v) {
+        map.insert_full(v.to_owned()).0
+    } else {
+        map.get_full(v).unwrap().0
+    }
+}
+
+/// The distinct values of a gene attribute with few values, e.g. the biotype, shared by the genes
+/// instead of a copy per gene
+#[derive(Default)]
+struct Labels(HashSet<Arc<[u8]>>);
+
+impl Labels {
+    fn intern(&mut self, v: &[u8]) -> Arc<[u8]> {
+        match self.0.get(v) {
+            Some(label) => Arc::clone(label),
+            None => {
+                let label: Arc<[u8]> = Arc::from(v);
+                self.0.insert(Arc::clone(&label));
+                label
+            },
+        }
+    }
+}
+
+/// Gene level annotation, derived from the exons of the gene
+struct Gene {
+    name: Option<Vec<u8>>,
+    biotype: Option<Arc<[u8]>>,
+    source: Option<Arc<[u8]>>,
+    /// the annotation file of the gene, when several are merged
+    annotation: usize,
+    chr: usize,
+    strand: Strand,
+    /// genomic span of the exons
+    span: Range<i64>,
+}
+
+impl Gene {
+    /// The window of `w` bases up- and downstream of the transcription start site
+    fn tss_window(&self, w: i64) -> Range<i64> {
+        let tss = if self.strand == Strand::Reverse { self.span.end - 1 } else { self.span.start };
+        (tss - w).max(0)..tss + w + 1
+    }
+
+    /// The gene span without the first `five` and last `three` bases, on the strand of the gene
+    fn locus(&self, five: i64, three: i64) -> Range<i64> {
+        if self.strand == Strand::Reverse {
+            self.span.start + three..self.span.end - five
+        } else {
+            self.span.start + five..self.span.end - three
+        }
+    }
+}
+
+/// The longest intron allowed in the reads on each gene for '--validate-introns': the longest
+/// intron of the transcripts of the gene, plus the tolerance as a fraction of its length. Genes
+/// without a transcript_id or without introns allow any intron, a spliced read on a single exon
+/// gene may be of an unannotated transcript.
+fn max_introns(transcripts: IndexMap<Vec<u8>, (usize, Vec<Range<i64>>)>, n_genes: usize, tolerance: f64) -> Vec<i64> {
+    if transcripts.is_empty() {
+        eprintln!("Warning: no exons with a transcript_id attribute for --validate-introns");
+    }
+    let mut max_introns = vec![0; n_genes];
+    for (gene, mut exons) in transcripts.into_values() {
+        exons.sort_by_key(|e| e.start);
+        let longest = exons.windows(2).map(|e| e[1].start - e[0].end).max().unwrap_or(0);
+        max_introns[gene] = longest.max(max_introns[gene]);
+    }
+    max_introns.into_iter()
+        .map(|m| if m > 0 { m + (m as f64 * tolerance) as i64 } else { i64::MAX })
+        .collect()
+}
+
+/// Build interval lists per chromosome with one region per gene, for all genes with exons.
+/// Genes with an empty region are left out.
+fn gene_regions<F>(gene_info: &[Gene], n_chr: usize, region: F) -> Result<Vec<NClist<Exon>>>
+    where F: Fn(&Gene) -> Range<i64>
+{
+    let mut regions: Vec<Vec<Exon>> = (0..n_chr).map(|_| Vec::new()).collect();
+    for (id, g) in gene_info.iter().enumerate().filter(|(_, g)| g.span.start < g.span.end) {
+        let range = region(g);
+        if range.start < range.end {
+            regions[g.chr].push(Exon { id: id as u32, strand: g.strand, range });
+        }
+    }
+    regions.into_iter().map(NClist::from_vec).collect::<Result<_, _>>()
+        .map_err(|_| anyhow!("Cannot create interval search list of the gene regions"))
+}
+
+pub struct GeneMap {
+    genes: IndexSet<Vec<u8>>,
+    gene_info: Vec<Gene>,
+    seq_names: IndexSet<Vec<u8>>,
+    /// the names of the merged annotation files, see `annotation_name`
+    annotations: Vec<String>,
+    intervals: Vec<NClist<Exon>>,
+    /// the gene spans per chromosome, exon from first to last base of the gene
+    spans: Vec<NClist<Exon>>,
+    /// the TSS windows per chromosome, when counted next to the gene body
+    tss: Vec<NClist<Exon>>,
+    /// the exons per chromosome with their position in the transcripts, for '--exon-usage'
+    exon_positions: Vec<NClist<ExonPosition>>,
+    /// the merged counting regions per gene in genomic order, for '--gene-windows'
+    models: Vec<Vec<Range<i64>>>,
+    /// the longest intron allowed in the reads on each gene, for '--validate-introns'
+    max_introns: Vec<i64>,
+    /// bitmaps per chromosome of the bins that overlap an exon, see `annotated`
+    annotated_bins: Vec<Vec<u64>>,
+    /// bitmaps per chromosome of the bins that overlap a TSS window, see `near_tss`
+    tss_bins: Vec<Vec<u64>>,
+    /// the gene of each transcript id, for transcriptome alignments with '--tx2gene'
+    transcript_genes: HashMap<Vec<u8>, usize>,
+    /// the first gene of each gene_name, indexed on the first lookup by `find_gene`
+    gene_names: OnceLock<HashMap<Vec<u8>, usize>>,
+    /// the '--qc-genes' found in the annotation with their gene, in the order of the file
+    qc_genes: Vec<(String, usize)>,
+    /// the female and male marker genes of '--sex-check' found in the annotation
+    female_genes: Vec<usize>,
+    male_genes: Vec<usize>,
+    /// the GC fraction of the counting regions of each gene, for '--gc-content'
+    gc_content: Vec<Option<f64>>,
+    /// the `GC_WINDOW` base windows along the counting regions of each gene by their GC bases,
+    /// for '--gc-bias'
+    gc_windows: Vec<Vec<u32>>,
+}
+
+impl GeneMap {
+    /// Load a GTF file, or a GFF3 file with a .gff3 or .gff extension
+    pub fn from_gtf<P: AsRef<Path>>(p: P) -> Result<GeneMap> {
+        let mut gm = GeneMap::load_gtf(&[p], AnnotFormat::Auto, BadExon::Skip, None, &AnnotationOptions::default())?;
+        gm.annotated_bins = region_bins(&gm.intervals);
+        Ok(gm)
+    }
+
+    /// Load an annotation file in the given format, without the configuration of `with_config`
+    pub fn from_annotation<P: AsRef<Path>>(p: P, format: AnnotFormat, bad_exon: BadExon) -> Result<GeneMap> {
+        GeneMap::load_gtf(&[p], format, bad_exon, None, &AnnotationOptions::default())
+    }
+
+    /// Load and merge the genes of the annotation files, the first file of a gene is its
+    /// annotation
+    fn load_gtf<P: AsRef<Path>>(paths: &[P], format: AnnotFormat, bad_exon: BadExon, contigs: Option<&IndexSet<Vec<u8>>>, options: &AnnotationOptions) -> Result<GeneMap> {
+        if format == AnnotFormat::Auto {
+            match paths {
+                [p] if is_index(p.as_ref())? && !options.is_default() => {
+                    return Err(anyhow!("--tag-filter, --min-tsl, the biotype filters, --id-attrs and --strip-gene-version can not be used with the gensum index {}", p.as_ref().display()));
+                },
+                [p] if is_index(p.as_ref())? && contigs.is_some() => {
+                    return Err(anyhow!("--indexed-gtf reads a tabix indexed annotation file, it can not be used with the gensum index {}", p.as_ref().display()));
+                },
+                [p] if is_index(p.as_ref())? => return GeneMap::read_index(p.as_ref()),
+                _ => for p in paths {
+                    if is_index(p.as_ref())? {
+                        return Err(anyhow!("The gensum index {} can not be merged with other annotation files", p.as_ref().display()));
+                    }
+                },
+            }
+        }
+        let t0 = Instant::now();
+        let mut genes = IndexSet::new();
+        let mut gene_info = Vec::new();
+        let mut seq_names = IndexSet::new();
+        let mut exons = Vec::new();
+        let mut bad_exons = 0;
+        let mut shared = IndexSet::new();
+        let mut split = IndexSet::new();
+        let mut merged = IndexSet::new();
+        let mut labels = Labels::default();
+        // the exons of each transcript and its gene, for '--validate-introns'
+        let mut transcripts: IndexMap<Vec<u8>, (usize, Vec<Range<i64>>)> = IndexMap::new();
+        let mut n = 0;
+        for (annotation, p) in paths.iter().enumerate() {
+            n += read_exons(p, format, contigs, options, &mut merged, |record, mut r| {
+                let gene_idx = get_index_or_insert_owned(&mut genes, r.id);
+                let chr_idx = get_index_or_insert_owned(&mut seq_names, r.seq_name);
+                // chromosomes may appear in any order, and interleaved in concatenated GTFs
+                if exons.len() <= chr_idx {
+                    exons.resize_with(chr_idx + 1, Vec::new);
+                }
+                if gene_info.len() == gene_idx {
+                    gene_info.push(Gene {
+                        name: r.name.map(|n| n.to_owned()),
+                        biotype: r.biotype.map(|b| labels.intern(b)),
+                        source: r.source.map(|s| labels.intern(s)),
+                        annotation,
+                        chr: chr_idx,
+                        strand: r.strand,
+                        span: Range { start: i64::MAX, end: i64::MIN },
+                    });
+                }
+
+                if gene_info[gene_idx].annotation != annotation {
+                    shared.insert(gene_idx);
+                }
+                // e.g. the same gene_name on chrX and chrY, the span is of the first chromosome
+                let other_chr = gene_info[gene_idx].chr != chr_idx;
+                if other_chr {
+                    split.insert(gene_idx);
+                }
+
+                if r.end < r.start {
+                    let gene = String::from_utf8_lossy(r.id);
+                    bad_exons += 1;
+                    match bad_exon {
+                        BadExon::Swap if bad_exon.swaps(r.start, r.end) => {
+                            eprintln!("Swapping start and end of inverted exon of gene {}: {}", gene, record);
+                            std::mem::swap(&mut r.start, &mut r.end);
+                        },
+                        BadExon::Skip | BadExon::Swap => {
+                            eprintln!("Skipping zero length or inverted exon of gene {}: {}", gene, record);
+                            return Ok(());
+                        },
+                        BadExon::Fail => return Err(anyhow!("Zero length or inverted exon of gene {}: {}", gene, record)),
+                    }
+                }
+
+                // gtf exon coordinates are 1 based and closed end
+                // bam files are 0 based, and nclist expects half open
+                if !other_chr {
+                    let span = &mut gene_info[gene_idx].span;
+                    *span = span.start.min(r.start-1)..span.end.max(r.end);
+                }
+                if let Some(t) = r.transcript_id.filter(|_| options.intron_tolerance.is_some()) {
+                    transcripts.entry(t.to_owned()).or_insert_with(|| (gene_idx, Vec::new())).1.push(r.start-1..r.end);
+                }
+                let id = u32::try_from(gene_idx).map_err(|_| anyhow!("Too many genes in GTF"))?;
+                exons[chr_idx].push(Exon {id, strand: r.strand, range: r.start-1..r.end });
+                Ok(())
+            })?;
+        }
+        let gtftime = t0.elapsed();
+        if bad_exons > 0 {
+            eprintln!("Warning: {} zero length or inverted exons in GTF ({:?})", bad_exons, bad_exon);
+        }
+        if !shared.is_empty() {
+            eprintln!("Warning: {} gene_ids are in several annotation files, their exons are merged into one gene", shared.len());
+        }
+        if !split.is_empty() {
+            let examples: Vec<_> = split.iter().take(3).map(|&g| String::from_utf8_lossy(&genes[g])).collect();
+            eprintln!("Warning: {} gene ids have exons on several chromosomes and are counted as one gene, e.g. {}", split.len(), examples.join(", "));
+        }
+        if !merged.is_empty() {
+            let examples: Vec<_> = merged.iter().take(3).map(|g| String::from_utf8_lossy(g)).collect();
+            eprintln!("--strip-gene-version merged the versions of {} gene ids, e.g. {}", merged.len(), examples.join(", "));
+        }
+        // e.g. a typo, or lncRNA in an annotation that has lincRNA
+        for biotype in &options.include_biotypes {
+            if !gene_info.iter().any(|g| g.biotype.as_deref() == Some(biotype.as_slice())) {
+                eprintln!("Warning: --include-biotype {} matches no gene", String::from_utf8_lossy(biotype));
+            }
+        }
+        // files with the same name, e.g. host/genes.gtf and virus/genes.gtf, are numbered
+        let mut annotations: Vec<String> = Vec::new();
+        for p in paths {
+            let name = annotation_name(p.as_ref());
+            let mut unique = name.clone();
+            for i in 2.. {
+                if !annotations.contains(&unique) {
+                    break;
+                }
+                unique = format!("{}_{}", name, i);
+            }
+            annotations.push(unique);
+        }
+
+        //Create the NClists
+        let mut numexons = 0;
+        let mut numexonsdd = 0;
+        let intervals: Vec<NClist<Exon>> = exons.into_iter()
+            .map(|mut v| {
+                numexons += v.len();
+                v.sort();
+                v.dedup(); //deduplication saves around 50% because of comparable isoforms (and havanna entries)
+            numexonsdd += v.len();
+            NClist::from_vec(v) })
+            .collect::<Result<_, _>>()
+            .map_err(|_| anyhow!("Cannot create interval search list, all ranges must be > 1"))?;
+
+        eprintln!("{} lines in GTF, parsed {} exons, {} unique geneid-exon ranges ({:?})", n, numexons, numexonsdd, gtftime);
+
+        let spans = gene_regions(&gene_info, intervals.len(), |g| g.span.clone())?;
+        let max_introns = match options.intron_tolerance {
+            Some(tolerance) => max_introns(transcripts, genes.len(), tolerance),
+            None => Vec::new(),
+        };
+
+        Ok(GeneMap { genes, gene_info, seq_names, annotations, intervals, spans, tss: Vec::new(), exon_positions: Vec::new(), models: Vec::new(), max_introns, annotated_bins: Vec::new(), tss_bins: Vec::new(), transcript_genes: HashMap::new(), gene_names: OnceLock::new(), qc_genes: Vec::new(), female_genes: Vec::new(), male_genes: Vec::new(), gc_content: Vec::new(), gc_windows: Vec::new() })
+    }
+
+    /// Write the genes and exons as a binary index, which `from_gtf` and `with_config` load instead
+    /// of the annotation file without parsing it
+    pub fn write_index<W: Write>(&self, o: W) -> Result<()> {
+        let mut w = BufWriter::new(o);
+        w.write_all(INDEX_MAGIC)?;
+        put_int(&mut w, self.seq_names.len() as i64)?;
+        for name in &self.seq_names {
+            put_bytes(&mut w, name)?;
+        }
+        put_int(&mut w, self.genes.len() as i64)?;
+        for (id, g) in self.genes.iter().zip(&self.gene_info) {
+            put_bytes(&mut w, id)?;
+            for v in [g.name.as_deref(), g.biotype.as_deref(), g.source.as_deref()] {
+                put_int(&mut w, v.map_or(-1, |v| v.len() as i64))?;
+                w.write_all(v.unwrap_or_default())?;
+            }
+            for v in [g.chr as i64, strand_code(g.strand), g.span.start, g.span.end] {
+                put_int(&mut w, v)?;
+            }
+        }
+        let all = i64::MIN..i64::MAX;
+        for map in &self.intervals {
+            let exons: Vec<_> = map.overlaps(&all).collect();
+            put_int(&mut w, exons.len() as i64)?;
+            for e in exons {
+                for v in [i64::from(e.id), strand_code(e.strand), e.range.start, e.range.end] {
+                    put_int(&mut w, v)?;
+                }
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    fn read_index(p: &Path) -> Result<GeneMap> {
+        use std::io::Read as _;
+        let t0 = Instant::now();
+        let invalid = || anyhow!("Invalid gensum index {}", p.display());
+        let mut r = BufReader::new(File::open(p)?);
+        let mut magic = [0; INDEX_MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if &magic != INDEX_MAGIC {
+            return Err(anyhow!("{} is not a gensum index of this version, create it again with 'gensum index'", p.display()));
+        }
+
+        let mut seq_names = IndexSet::new();
+        for _ in 0..take_int(&mut r, &invalid)? {
+            seq_names.insert(take_bytes(&mut r, &invalid)?.ok_or_else(invalid)?);
+        }
+        let (mut genes, mut gene_info, mut labels) = (IndexSet::new(), Vec::new(), Labels::default());
+        for _ in 0..take_int(&mut r, &invalid)? {
+            genes.insert(take_bytes(&mut r, &invalid)?.ok_or_else(invalid)?);
+            let name = take_bytes(&mut r, &invalid)?;
+            let biotype = take_bytes(&mut r, &invalid)?.map(|b| labels.intern(&b));
+            let source = take_bytes(&mut r, &invalid)?.map(|s| labels.intern(&s));
+            let chr = usize::try_from(take_int(&mut r, &invalid)?).map_err(|_| invalid())?;
+            let strand = strand_from_code(take_int(&mut r, &invalid)?).ok_or_else(invalid)?;
+            let span = take_int(&mut r, &invalid)?..take_int(&mut r, &invalid)?;
+            gene_info.push(Gene { name, biotype, source, annotation: 0, chr, strand, span });
+        }
+        let mut intervals = Vec::with_capacity(seq_names.len());
+        for _ in 0..seq_names.len() {
+            let mut exons = Vec::new();
+            for _ in 0..take_int(&mut r, &invalid)? {
+                let id = u32::try_from(take_int(&mut r, &invalid)?).map_err(|_| invalid())?;
+                let strand = strand_from_code(take_int(&mut r, &invalid)?).ok_or_else(invalid)?;
+                exons.push(Exon { id, strand, range: take_int(&mut r, &invalid)?..take_int(&mut r, &invalid)? });
+            }
+            exons.sort();
+            intervals.push(NClist::from_vec(exons).map_err(|_| invalid())?);
+        }
+        if gene_info.iter().any(|g| g.chr >= intervals.len()) {
+            return Err(invalid());
+        }
+        eprintln!("Loaded index with {} genes on {} contigs ({:?})", genes.len(), seq_names.len(), t0.elapsed());
+
+        let spans = gene_regions(&gene_info, intervals.len(), |g| g.span.clone())?;
+        let annotations = vec![annotation_name(p)];
+        Ok(GeneMap { genes, gene_info, seq_names, annotations, intervals, spans, tss: Vec::new(), exon_positions: Vec::new(), models: Vec::new(), max_introns: Vec::new(), annotated_bins: Vec::new(), tss_bins: Vec::new(), transcript_genes: HashMap::new(), gene_names: OnceLock::new(), qc_genes: Vec::new(), female_genes: Vec::new(), male_genes: Vec::new(), gc_content: Vec::new(), gc_windows: Vec::new() })
+    }
+
+    /// Load the GTF and set up the regions reads are counted on for the configuration
+    pub fn with_config<P: AsRef<Path>>(p: P, config: &Args) -> Result<GeneMap> {
+        GeneMap::with_config_merged(&[p], config)
+    }
+
+    /// Load and merge several annotation files like `with_config`, e.g. of the host genome, a
+    /// virus and spike-ins of a combined reference. The summary reports the reads assigned to the
+    /// genes of each file.
+    pub fn with_config_merged<P: AsRef<Path>>(paths: &[P], config: &Args) -> Result<GeneMap> {
+        if config.exon_usage.is_some() || config.validate_introns {
+            for p in paths {
+                if is_index(p.as_ref())? {
+                    return Err(anyhow!("--exon-usage and --validate-introns read the transcripts of the annotation file, they can not be used with an index"));
+                }
+            }
+        }
+        let chr_aliases = match config.chr_alias.as_ref() {
+            Some(f) => read_gene_table(f)?,
+            None => Vec::new(),
+        };
+        let mut contigs = if config.indexed_gtf { Some(bam_contigs(&config.bam, !config.exact_chr_names)?) } else { None };
+        if let Some(contigs) = contigs.as_mut() {
+            // the annotation names of the bam contigs
+            let renamed: Vec<_> = chr_aliases.iter()
+                .filter(|(_, to)| contigs.contains(to.as_bytes()))
+                .map(|(from, _)| from.as_bytes().to_vec())
+                .collect();
+            contigs.extend(renamed);
+        }
+        let contigs = contigs.as_ref();
+        let options = AnnotationOptions::new(config);
+        // the exon positions are read in another pass, stdin is kept until then
+        let mut stdin = Vec::new();
+        if config.exon_usage.is_some() {
+            for p in paths {
+                stdin.extend(BufferedStdin::new(p.as_ref())?);
+            }
+        }
+        let mut gm = GeneMap::load_gtf(paths, config.annot_format, config.bad_exon, contigs, &options)?;
+        if config.exon_usage.is_some() {
+            gm.exon_positions = gm.read_exon_positions(paths, config.annot_format, config.bad_exon, contigs, &options)?;
+        }
+        drop(stdin);
+        if config.strand_report.is_some() && config.strandness == Strandness::Unstranded {
+            eprintln!("Warning: --strand-report on an unstranded library has no antisense reads, set the --strandness");
+        }
+        if config.nascent {
+            if config.strandness == Strandness::Unstranded {
+                eprintln!("Warning: --nascent on an unstranded library counts antisense transcription on the gene");
+            }
+            let (five, three) = (config.exclude_5p, config.exclude_3p);
+            gm.intervals = gene_regions(&gm.gene_info, gm.intervals.len(), |g| g.locus(five, three))?;
+        }
+        if let Some(w) = config.tss_window {
+            let tss = gene_regions(&gm.gene_info, gm.intervals.len(), |g| g.tss_window(w))?;
+            if config.dual_counts {
+                gm.tss = tss;
+            } else {
+                gm.intervals = tss;
+            }
+        }
+        if config.gene_windows.is_some() {
+            gm.models = gm.collapsed_models();
+        }
+        if let Some(f) = config.tx2gene.as_ref() {
+            gm.transcript_genes = gm.read_tx2gene(f)?;
+        }
+        if !chr_aliases.is_empty() {
+            gm.rename_chromosomes(&chr_aliases)?;
+        }
+        if let Some(f) = config.qc_genes.as_ref() {
+            for gene in read_gene_list(f)? {
+                match gm.find_gene(gene.as_bytes()) {
+                    Some(idx) => gm.qc_genes.push((gene, idx)),
+                    None => eprintln!("QC gene {} not found in GTF", gene),
+                }
+            }
+        }
+        if config.sex_check {
+            gm.female_genes = gm.find_markers(&config.female_genes);
+            gm.male_genes = gm.find_markers(&config.male_genes);
+        }
+        if let Some(fasta) = config.reference.as_ref().filter(|_| config.gc_content || config.gc_bias.is_some()) {
+            let n = gm.genes.len();
+            let (mut content, mut windows) = (vec![None; n], vec![Vec::new(); n]);
+            gm.read_gene_sequences(fasta, config.exact_chr_names, |gene, seq, model| {
+                if config.gc_content {
+                    content[gene] = gc_fraction(seq, model);
+                }
+                if config.gc_bias.is_some() {
+                    windows[gene] = gc_windows(seq, model);
+                }
+            })?;
+            if config.gc_content {
+                gm.gc_content = content;
+            }
+            if config.gc_bias.is_some() {
+                gm.gc_windows = windows;
+            }
+        }
+        gm.annotated_bins = region_bins(&gm.intervals);
+        gm.tss_bins = region_bins(&gm.tss);
+        Ok(gm)
+    }
+
+    /// Rename the chromosomes of the annotation to the contig names of the bam file, by a table of
+    /// annotation and bam names. The annotation is not read again after renaming.
+    fn rename_chromosomes(&mut self, aliases: &[(String, String)]) -> Result<()> {
+        let aliases: HashMap<&[u8], &[u8]> = aliases.iter().map(|(from, to)| (from.as_bytes(), to.as_bytes())).collect();
+        let mut renamed = IndexSet::with_capacity(self.seq_names.len());
+        let mut n = 0;
+        for name in &self.seq_names {
+            let new = match aliases.get(name.as_slice()) {
+                Some(&to) => {
+                    n += 1;
+                    to
+                },
+                None => name.as_slice(),
+            };
+            if !renamed.insert(new.to_vec()) {
+                return Err(anyhow!("--chr-alias gives several chromosomes the name {}", String::from_utf8_lossy(new)));
+            }
+        }
+        eprintln!("Renamed {} of {} GTF chromosomes with --chr-alias", n, renamed.len());
+        self.seq_names = renamed;
+        Ok(())
+    }
+
+    /// Read the gene of each transcript from a tx2gene table. Transcripts of genes that are not in
+    /// the annotation are left out.
+    fn read_tx2gene(&self, p: &Path) -> Result<HashMap<Vec<u8>, usize>> {
+        let mut transcripts = HashMap::new();
+        let mut missing = 0;
+        for (transcript, gene) in read_gene_table(p)? {
+            // tximport tables may have the gene name in a third column
+            let gene = gene.split('\t').next().unwrap_or_default();
+            // versioned ids of the table also match the genes of '--strip-gene-version'
+            let idx = self.genes.get_index_of(gene.as_bytes())
+                .or_else(|| self.genes.get_index_of(strip_gene_version(gene.as_bytes()).as_ref()));
+            match idx {
+                Some(idx) => { transcripts.insert(transcript.into_bytes(), idx); },
+                None => missing += 1,
+            }
+        }
+        if missing > 0 {
+            eprintln!("Warning: {} transcripts in {} have a gene_id that is not in the GTF", missing, p.display());
+        }
+        Ok(transcripts)
+    }
+
+    /// Whether a region of chromosome `chr` may overlap an exon. Most reads in unannotated space
+    /// are rejected by the bitmap, without searching the intervals. Always true without bitmaps.
+    fn annotated(&self, chr: usize, region: Range<i64>) -> bool {
+        in_bins(self.annotated_bins.get(chr), region)
+    }
+
+    /// Whether a region of chromosome `chr` may overlap a TSS window, like `annotated`
+    fn near_tss(&self, chr: usize, region: Range<i64>) -> bool {
+        in_bins(self.tss_bins.get(chr), region)
+    }
+
+    /// The collapsed model of each gene: its counting regions, with overlapping regions merged
+    fn collapsed_models(&self) -> Vec<Vec<Range<i64>>> {
+        let mut models: Vec<Vec<Range<i64>>> = vec![Vec::new(); self.genes.len()];
+        let all = i64::MIN..i64::MAX;
+        for e in self.intervals.iter().flat_map(|map| map.overlaps(&all)) {
+            models[e.gene()].push(e.range.clone());
+        }
+        for model in models.iter_mut() {
+            model.sort_by_key(|r| r.start);
+            let mut merged: Vec<Range<i64>> = Vec::with_capacity(model.len());
+            for r in model.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+                    _ => merged.push(r),
+                }
+            }
+            *model = merged;
+        }
+        models
+    }
+
+    /// The exonic parts of each gene: its exons split at every exon start and end of its
+    /// transcripts, so a part is either in all or in none of the exons that overlap it
+    fn exonic_parts(&self) -> Vec<Vec<Range<i64>>> {
+        let mut exons: Vec<Vec<Range<i64>>> = vec![Vec::new(); self.genes.len()];
+        let all = i64::MIN..i64::MAX;
+        for e in self.intervals.iter().flat_map(|map| map.overlaps(&all)) {
+            exons[e.gene()].push(e.range.clone());
+        }
+        exons.into_iter().map(|exons| {
+            let mut bounds: Vec<i64> = exons.iter().flat_map(|r| [r.start, r.end]).collect();
+            bounds.sort_unstable();
+            bounds.dedup();
+            bounds.windows(2)
+                .map(|b| b[0]..b[1])
+                .filter(|part| exons.iter().any(|e| e.start <= part.start && e.end >= part.end))
+                .collect()
+        }).collect()
+    }
+
+    /// Call `f` with each gene, the sequence of its chromosome and its collapsed counting regions,
+    /// from a (gzipped) FASTA file read one sequence at a time. Genes on a chromosome that is not
+    /// in the FASTA are skipped.
+    fn read_gene_sequences<F>(&self, fasta: &Path, exact: bool, mut f: F) -> Result<()>
+        where F: FnMut(usize, &[u8], &[Range<i64>])
+    {
+        let models = self.collapsed_models();
+        let mut chr_genes = vec![Vec::new(); self.seq_names.len()];
+        for (id, g) in self.gene_info.iter().enumerate() {
+            chr_genes[g.chr].push(id);
+        }
+        let mut found = vec![false; self.seq_names.len()];
+
+        let mut reader = BufReader::new(niffler::from_path(fasta)?.0);
+        let (mut line, mut name, mut seq) = (Vec::new(), Vec::new(), Vec::new());
+        loop {
+            line.clear();
+            let eof = reader.read_until(b'\n', &mut line)? == 0;
+            if !eof && !line.starts_with(b">") {
+                seq.extend(line.iter().filter(|b| !b.is_ascii_whitespace()));
+                continue;
+            }
+            // an exact name match takes precedence over a chr alias
+            let chr = self.seq_names.get_index_of(&name).or_else(|| {
+                let alias = chr_alias(&name).filter(|_| !exact)?;
+                self.seq_names.get_index_of(&alias).filter(|&c| !found[c])
+            });
+            if let Some(chr) = chr {
+                found[chr] = true;
+                for &id in &chr_genes[chr] {
+                    f(id, &seq, &models[id]);
+                }
+            }
+            if eof {
+                break;
+            }
+            name = line[1..].split(|b| b.is_ascii_whitespace()).next().unwrap_or_default().to_vec();
+            seq.clear();
+        }
+        let missing = self.seq_names.iter().zip(&found).filter(|(_, &f)| !f).count();
+        if missing > 0 {
+            eprintln!("Warning: {} annotated chromosomes are not in {}, their genes have no GC content", missing, fasta.display());
+        }
+        Ok(())
+    }
+
+    /// Read the GTF again to classify the exons as first, internal or last exon of their
+    /// transcripts, by the exon_number attribute. An exon shared by transcripts gets all classes.
+    fn read_exon_positions<P: AsRef<Path>>(&self, paths: &[P], format: AnnotFormat, bad_exon: BadExon, contigs: Option<&IndexSet<Vec<u8>>>, options: &AnnotationOptions) -> Result<Vec<NClist<ExonPosition>>> {
+        // the highest exon number per transcript, and the numbered exons
+        let mut transcripts: IndexMap<Vec<u8>, u32> = IndexMap::new();
+        let mut numbered = Vec::new();
+        for p in paths {
+            read_exons(p, format, contigs, options, &mut IndexSet::new(), |_, mut r| {
+                if r.end < r.start {
+                    if !bad_exon.swaps(r.start, r.end) {
+                        return Ok(());
+                    }
+                    std::mem::swap(&mut r.start, &mut r.end);
+                }
+                if let (Some(t), Some(n), Some(chr), Some(gene)) = (r.transcript_id, r.exon_number,
+                    self.seq_names.get_index_of(r.seq_name), self.genes.get_index_of(r.id)) {
+                    let entry = transcripts.entry(t.to_owned());
+                    let t = entry.index();
+                    let max = entry.or_insert(0);
+                    *max = (*max).max(n);
+                    numbered.push((chr, t, n, ExonPosition { id: gene as u32, class: 0, range: r.start-1..r.end }));
+                }
+                Ok(())
+            })?;
+        }
+        if numbered.is_empty() {
+            eprintln!("Warning: no exons with transcript_id and exon_number attributes for --exon-usage");
+        }
+
+        let mut exons: Vec<Vec<ExonPosition>> = (0..self.intervals.len()).map(|_| Vec::new()).collect();
+        for (chr, t, n, mut e) in numbered {
+            let last = transcripts[t];
+            e.class = if n == 1 { ExonPosition::FIRST } else { 0 } | if n == last { ExonPosition::LAST } else { 0 };
+            if e.class == 0 {
+                e.class = ExonPosition::INTERNAL;
+            }
+            exons[chr].push(e);
+        }
+        exons.into_iter()
+            .map(|mut v| {
+                v.sort_by_key(|e| (e.id, e.range.start, e.range.end));
+                v.dedup_by(|a, b| {
+                    let same = a.id == b.id && a.range == b.range;
+                    if same {
+                        b.class |= a.class;
+                    }
+                    same
+                });
+                NClist::from_vec(v)
+            })
+            .collect::<Result<_, _>>()
+            .map_err(|_| anyhow!("Cannot create interval search list of the exon positions"))
+    }
+
+    /// Write the deduplicated intervals reads are counted on as BED, sorted by position within
+    /// each chromosome, for viewing next to the alignments in a genome browser
+    pub fn write_model<W: Write>(&self, o: W) -> Result<()> {
+        let mut w = BufWriter::new(o);
+        let all = i64::MIN..i64::MAX;
+        for (chr, map) in self.seq_names.iter().zip(&self.intervals) {
+            let mut exons: Vec<&Exon> = map.overlaps(&all).collect();
+            exons.sort_by_key(|e| (e.range.start, e.range.end, e.id));
+            for e in exons {
+                w.write_all(chr)?;
+                write!(w, "\t{}\t{}\t", e.range.start, e.range.end)?;
+                w.write_all(&self.genes[e.gene()])?;
+                writeln!(w, "\t0\t{}", strand_char(e.strand))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the exons of each gene as numbered disjoint exon bins, like the exonic parts of the
+    /// flattened annotation of DEXSeq. The GTF has the bin id as 'exon_id' next to the gene_id, so
+    /// it counts genes by default and exon bins with '--id-attr exon_id'. The SAF has the bin ids
+    /// as GeneID.
+    pub fn write_flattened<W: Write>(&self, o: W, saf: bool) -> Result<()> {
+        let mut w = BufWriter::new(o);
+        let models = self.exonic_parts();
+        let mut order: Vec<usize> = (0..self.genes.len()).filter(|&g| !models[g].is_empty()).collect();
+        order.sort_by_key(|&g| (self.gene_info[g].chr, self.gene_info[g].span.start));
+        if saf {
+            writeln!(w, "GeneID\tChr\tStart\tEnd\tStrand")?;
+        }
+        let mut bins = 0;
+        for g in order {
+            let (id, info) = (&self.genes[g], &self.gene_info[g]);
+            let chr = &self.seq_names[info.chr];
+            let strand = strand_char(info.strand);
+            let mut attrs = Vec::new();
+            if !saf {
+                if let Some(name) = &info.name {
+                    attrs.extend_from_slice(b" gene_name \"");
+                    attrs.extend_from_slice(name);
+                    attrs.extend_from_slice(b"\";");
+                }
+                if let Some(biotype) = &info.biotype {
+                    attrs.extend_from_slice(b" gene_biotype \"");
+                    attrs.extend_from_slice(biotype);
+                    attrs.extend_from_slice(b"\";");
+                }
+                w.write_all(chr)?;
+                write!(w, "\tgensum\tgene\t{}\t{}\t.\t{}\t.\tgene_id \"", info.span.start + 1, info.span.end, strand)?;
+                w.write_all(id)?;
+                w.write_all(b"\";")?;
+                w.write_all(&attrs)?;
+                w.write_all(b"\n")?;
+            }
+            // bins are numbered in genomic order on both strands, like DEXSeq
+            for (n, r) in models[g].iter().enumerate() {
+                if saf {
+                    w.write_all(id)?;
+                    write!(w, ":E{:03}\t", n + 1)?;
+                    w.write_all(chr)?;
+                    writeln!(w, "\t{}\t{}\t{}", r.start + 1, r.end, strand)?;
+                } else {
+                    w.write_all(chr)?;
+                    write!(w, "\tgensum\texon\t{}\t{}\t.\t{}\t.\tgene_id \"", r.start + 1, r.end, strand)?;
+                    w.write_all(id)?;
+                    w.write_all(b"\"; exon_id \"")?;
+                    w.write_all(id)?;
+                    write!(w, ":E{:03}\"; exonic_part_number \"{:03}\";", n + 1, n + 1)?;
+                    w.write_all(&attrs)?;
+                    w.write_all(b"\n")?;
+                }
+            }
+            bins += models[g].len();
+        }
+        w.flush()?;
+        eprintln!("Wrote {} exon bins of {} genes", bins, self.genes.len());
+        Ok(())
+    }
+
+    #[inline]
+    pub fn hit_name(&self, i: usize) -> Option<&Vec<u8>> {
+        self.genes.get_index(i)
+    }
+
+    /// The chromosome of each BAM contig. A contig that is not in the annotation is matched by
+    /// its UCSC or Ensembl alias unless `exact`, when no other contig has that name.
+    fn contig_map(&self, contigs: &[&[u8]], exact: bool) -> Vec<Option<usize>> {
+        let names: HashSet<&[u8]> = contigs.iter().copied().collect();
+        contigs.iter()
+            .map(|name| self.seq_names.get_index_of(*name).or_else(|| {
+                let alias = chr_alias(name).filter(|a| !exact && !names.contains(a.as_slice()))?;
+                self.seq_names.get_index_of(&alias)
+            }))
+            .collect()
+    }
+
+    /// Write the tab separated GC content column of a gene, NA without sequence
+    fn write_gc_content<W: Write>(&self, w: &mut W, gene: usize) -> Result<()> {
+        match self.gc_content.get(gene) {
+            Some(Some(gc)) => write!(w, "\t{:.4}", gc)?,
+            Some(None) => w.write_all(b"\tNA")?,
+            None => (),
+        }
+        Ok(())
+    }
+
+    /// Find a gene by its gene_id or gene_name. A gene_name shared by several genes finds the
+    /// first of them.
+    pub fn find_gene(&self, key: &[u8]) -> Option<usize> {
+        self.genes.get_index_of(key).or_else(|| {
+            let names = self.gene_names.get_or_init(|| {
+                let mut names = HashMap::new();
+                for (i, g) in self.gene_info.iter().enumerate() {
+                    if let Some(name) = g.name.as_ref() {
+                        names.entry(name.clone()).or_insert(i);
+                    }
+                }
+                names
+            });
+            names.get(key).copied()
+        })
+    }
+
+    /// Apply the configured rules to resolve a read that is ambiguous between genes
+    fn resolve_ambiguous(&self, r: &bam::Record, map: &NClist<Exon>, config: &Args) -> SegmentHit {
+        let mut candidates = candidate_genes(r, map, config, config.method == QuantMethod::Strict);
+        if config.prefer_containing {
+            let containing = candidate_genes(r, map, config, true);
+            if !containing.is_empty() {
+                candidates = containing;
+            }
+        }
+
+        match candidates.len() {
+            1 => SegmentHit::Hit(candidates[0]),
+            _ if config.prefer_shorter_gene => self.shortest_gene(&candidates),
+            _ => SegmentHit::Ambiguous,
+        }
+    }
+
+    /// Resolve an ambiguous read to the gene with the shortest genomic span, e.g. a snoRNA
+    /// within an intron of its host gene. Remains ambiguous when there is no unique shortest gene.
+    fn shortest_gene(&self, candidates: &[usize]) -> SegmentHit {
+        let len = |&id: &usize| (self.gene_info[id].span.end - self.gene_info[id].span.start).max(0);
+        match candidates.iter().min_by_key(|id| len(id)) {
+            Some(&id) if candidates.iter().filter(|c| len(c) == len(&id)).count() == 1 => SegmentHit::Hit(id),
+            Some(_) => SegmentHit::Ambiguous,
+            None => SegmentHit::Nohit,
+        }
+    }
+
+    /// Whether a read lies within the span of a gene on a matching strand
+    fn within_gene(&self, r: &bam::Record, chr: usize, config: &Args) -> bool {
+        let cigar = r.cigar();
+        let span = cigar.pos()..cigar.end_pos();
+        let forward = Strandness::fragment_forward(r);
+        self.spans[chr].overlaps(&span)
+            .any(|g| span.start >= g.range.start && span.end <= g.range.end && config.strandness.matches(forward, g.strand))
+    }
+
+    /// The '--sex-check' marker genes given by gene_id or gene_name. Genes that are not in the GTF
+    /// are reported and skipped.
+    fn find_markers(&self, genes: &[String]) -> Vec<usize> {
+        genes.iter().filter_map(|g| {
+            let idx = self.find_gene(g.as_bytes());
+            if idx.is_none() {
+                eprintln!("Sex check gene {} not found in GTF", g);
+            }
+            idx
+        }).collect()
+    }
+
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum SegmentHit {
+    Hit(usize),
+    Nohit,
+    /// No hit, but exons were overlapped on the opposite strand
+    WrongStrand,
+    Ambiguous,
+    /// Assigned, but too few high quality bases overlap the exons of the gene
+    LowQuality,
+    /// No exon overlapped, but within the span of a gene
+    Intronic,
+    /// Assigned, but with an intron longer than the introns of the gene
+    IntronMismatch,
+}
+
+impl SegmentHit {
+    fn gene(&self) -> Option<usize> {
+        match self {
+            SegmentHit::Hit(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    /// The summary category of an unassigned read
+    fn category(&self) -> Option<&'static str> {
+        match self {
+            SegmentHit::Hit(_) => None,
+            SegmentHit::Nohit => Some("nohit"),
+            SegmentHit::WrongStrand => Some("wrong_strand"),
+            SegmentHit::Ambiguous => Some("ambiguous"),
+            SegmentHit::LowQuality => Some("low_hq_overlap"),
+            SegmentHit::Intronic => Some("intronic"),
+            SegmentHit::IntronMismatch => Some("intron_mismatch"),
+        }
+    }
+}
+
+/// Why a read is not assigned, before its alignment is compared with the exons
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Rejection {
+    Unmapped,
+    Secondary,
+    Duplicate,
+    LowMapq,
+    MalformedCigar,
+    LongIntron,
+}
+
+impl Rejection {
+    /// The summary category of the rejected reads
+    fn category(self) -> &'static str {
+        match self {
+            Rejection::Unmapped => "unmapped",
+            Rejection::Secondary => "secondary_alignments",
+            Rejection::Duplicate => "marked_duplicated",
+            Rejection::LowMapq => "low_mapq",
+            Rejection::MalformedCigar => "malformed_cigar",
+            Rejection::LongIntron => "long_intron",
+        }
+    }
+}
+
+/// Apply the read filters, None when the read can be assigned. Mapped reads without a cigar get an
+/// ungapped alignment with '--cigar-fallback'.
+fn reject_read(record: &mut bam::Record, config: &Args) -> Option<Rejection> {
+    if record.is_unmapped() {
+        return Some(Rejection::Unmapped);
+    }
+    if record.is_secondary() || record.is_supplementary() {
+        return Some(Rejection::Secondary);
+    }
+    if !config.usedups && record.is_duplicate() {
+        return Some(Rejection::Duplicate);
+    }
+    if record.mapq() < config.mapq {
+        return Some(Rejection::LowMapq);
+    }
+
+    // a mapped read without a cigar has no aligned segments
+    if record.cigar_len() == 0 {
+        if config.cigar_fallback && record.seq_len() > 0 {
+            set_ungapped_cigar(record);
+        } else {
+            return Some(Rejection::MalformedCigar);
+        }
+    }
+
+    match config.max_intron_span {
+        Some(max) if record.cigar().iter().any(|c| matches!(*c, Cigar::RefSkip(n) if n > max)) => Some(Rejection::LongIntron),
+        _ => None,
+    }
+}
+
+/// Read and base counter. Explicitly 64 bit, counts of billion read datasets must not depend on
+/// the platform word size. The counters are added without overflow checks: a read count is at
+/// most the number of alignment records and a base count at most their aligned bases, also when
+/// shards are merged, and both stay far below 2^64 (1.8e19).
+pub type Count = u64;
+
+/// Version of the summary format, written as a comment on the first line. See the compatibility
+/// policy in the README before changing the output.
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// Exon boundary violations of reads rejected in strict mode, aggregated per gene.
+/// The 5' and 3' sides are relative to the strand of the exon.
+#[derive(Default, Clone, Debug, PartialEq)]
+struct BoundaryMismatch {
+    five_prime: Count,
+    five_prime_bases: Count,
+    three_prime: Count,
+    three_prime_bases: Count,
+}
+
+/// The counts of a shard added to the counts of another by `ReadMappings::merge`
+trait AddCounts {
+    fn add(&mut self, other: Self);
+}
+
+impl AddCounts for Count {
+    fn add(&mut self, other: Count) {
+        *self += other;
+    }
+}
+
+impl AddCounts for f64 {
+    fn add(&mut self, other: f64) {
+        *self += other;
+    }
+}
+
+impl AddCounts for [Count; 3] {
+    fn add(&mut self, other: [Count; 3]) {
+        self.iter_mut().zip(other).for_each(|(c, o)| *c += o);
+    }
+}
+
+impl AddCounts for BoundaryMismatch {
+    fn add(&mut self, other: BoundaryMismatch) {
+        self.five_prime += other.five_prime;
+        self.five_prime_bases += other.five_prime_bases;
+        self.three_prime += other.three_prime;
+        self.three_prime_bases += other.three_prime_bases;
+    }
+}
+
+impl<T: AddCounts> AddCounts for Vec<T> {
+    fn add(&mut self, other: Vec<T>) {
+        add_vec(self, other);
+    }
+}
+
+/// Add the values of `other` to `v` element by element. A report that is off, or counts that
+/// are allocated on the first read, are empty and take the values of `other`.
+fn add_vec<T: AddCounts>(v: &mut Vec<T>, other: Vec<T>) {
+    if v.is_empty() {
+        *v = other;
+    } else {
+        v.iter_mut().zip(other).for_each(|(v, o)| v.add(o));
+    }
+}
+
+#[derive(Default, Debug, PartialEq)]
+pub struct ReadMappings {
+    qc_failed: Count,
+    unmapped: Count,
+    secondary: Count,
+    duplicated: Count,
+    ambiguous: Count,
+    ambiguous_pair: Count,
+    notingtf: Count,
+    contaminant: Count,
+    wrong_strand: Count,
+    mapq: Count,
+    nohit: Count,
+    malformed_cigar: Count,
+    orphan: Count,
+    low_hq_overlap: Count,
+    intronic: Count,
+    long_intron: Count,
+    intron_mismatch: Count,
+    /// reads rejected by the `ReadFilter`s, None without filters
+    filtered: Option<Count>,
+    /// reads that pass the read filters of `reject_read`, and the reads passed to `on_assigned`
+    passed: Count,
+    assigned_reads: Count,
+    hit: Vec<Count>,
+    /// counts on the TSS windows, next to the gene body counts in `hit`
+    tss_hit: Vec<Count>,
+    /// assigned reads per gene with '--read-counts', a counted pair adds both mates
+    reads: Vec<Count>,
+    boundary: Vec<BoundaryMismatch>,
+    /// aligned bases of the counted reads in bins along the gene span, allocated on the first read
+    coverage: Vec<Vec<Count>>,
+    /// reads on the first, internal and last exons per gene
+    exon_usage: Vec<[Count; 3]>,
+    /// aligned bases of the counted reads on the exons of their gene
+    exonic_bases: Vec<Count>,
+    /// reads that would be assigned to the gene on the opposite strand
+    antisense: Vec<Count>,
+    /// the fractions of ambiguous reads distributed on the overlapping bases
+    weighted: Vec<f64>,
+    /// the variance of the distributed fractions, and the ambiguous reads with a share per gene
+    weighted_var: Vec<f64>,
+    weighted_reads: Vec<Count>,
+    /// the assigned reads by the G and C bases of their start, for '--gc-bias'
+    gc_observed: Vec<Count>,
+    /// reads per window along the collapsed gene model, allocated on the first read
+    windows: Vec<Vec<Count>>,
+    /// pairs whose mates are assigned to two different genes
+    bridging: HashMap<(usize, usize), Count>,
+    /// counted fragments per gene and stratum of each '--stratify' property
+    strata: HashMap<(usize, Vec<u8>), Count>,
+    /// names of the read pairs linking two distant genes
+    fusions: HashMap<(usize, usize), Vec<Vec<u8>>>,
+}
+
+impl ReadMappings {
+    pub fn new(n: usize) -> ReadMappings {
+        ReadMappings { hit: vec![0; n], ..Default::default() }
+    }
+
+    /// Create the mappings for n genes, with the per gene reports enabled in the configuration
+    pub fn with_config(n: usize, config: &Args) -> ReadMappings {
+        let mut counts = ReadMappings::new(n);
+        if config.method == QuantMethod::Strict && config.boundary_report.is_some() {
+            counts.boundary = vec![BoundaryMismatch::default(); n];
+        }
+        if config.coverage_profile.is_some() {
+            counts.coverage = vec![Vec::new(); n];
+        }
+        if config.dual_counts {
+            counts.tss_hit = vec![0; n];
+        }
+        if config.exon_usage.is_some() {
+            counts.exon_usage = vec![[0; 3]; n];
+        }
+        if config.exonic_bases.is_some() {
+            counts.exonic_bases = vec![0; n];
+        }
+        if config.strand_report.is_some() {
+            counts.antisense = vec![0; n];
+        }
+        if config.weight_by_overlap {
+            counts.weighted = vec![0.0; n];
+        }
+        if config.uncertainty_report.is_some() {
+            counts.weighted_var = vec![0.0; n];
+            counts.weighted_reads = vec![0; n];
+        }
+        if config.gene_windows.is_some() {
+            counts.windows = vec![Vec::new(); n];
+        }
+        counts
+    }
+
+    /// Add the counts of another set of mappings for the same GeneMap, e.g. a shard counted by
+    /// another worker. Merging shards gives the same result as counting all reads in one.
+    pub fn merge(&mut self, other: ReadMappings) {
+        // every field is named, a new field does not compile until it is merged
+        let ReadMappings {
+            qc_failed, unmapped, secondary, duplicated, ambiguous, ambiguous_pair, notingtf, contaminant,
+            wrong_strand, mapq, nohit, malformed_cigar, orphan, low_hq_overlap, intronic, long_intron,
+            intron_mismatch, filtered, passed, assigned_reads, hit, tss_hit, reads, boundary, coverage,
+            exon_usage, exonic_bases, antisense, weighted, weighted_var, weighted_reads, gc_observed,
+            windows, bridging, strata, fusions,
+        } = other;
+        self.qc_failed += qc_failed;
+        self.unmapped += unmapped;
+        self.secondary += secondary;
+        self.duplicated += duplicated;
+        self.ambiguous += ambiguous;
+        self.ambiguous_pair += ambiguous_pair;
+        self.notingtf += notingtf;
+        self.contaminant += contaminant;
+        self.wrong_strand += wrong_strand;
+        self.mapq += mapq;
+        self.nohit += nohit;
+        self.malformed_cigar += malformed_cigar;
+        self.orphan += orphan;
+        self.low_hq_overlap += low_hq_overlap;
+        self.intronic += intronic;
+        self.long_intron += long_intron;
+        self.intron_mismatch += intron_mismatch;
+        self.passed += passed;
+        self.assigned_reads += assigned_reads;
+        if let Some(o) = filtered {
+            *self.filtered.get_or_insert(0) += o;
+        }
+        add_vec(&mut self.hit, hit);
+        add_vec(&mut self.tss_hit, tss_hit);
+        add_vec(&mut self.reads, reads);
+        add_vec(&mut self.boundary, boundary);
+        add_vec(&mut self.coverage, coverage);
+        add_vec(&mut self.exon_usage, exon_usage);
+        add_vec(&mut self.exonic_bases, exonic_bases);
+        add_vec(&mut self.antisense, antisense);
+        add_vec(&mut self.weighted, weighted);
+        add_vec(&mut self.weighted_var, weighted_var);
+        add_vec(&mut self.weighted_reads, weighted_reads);
+        add_vec(&mut self.gc_observed, gc_observed);
+        add_vec(&mut self.windows, windows);
+        for (k, n) in bridging {
+            *self.bridging.entry(k).or_insert(0) += n;
+        }
+        for (k, n) in strata {
+            *self.strata.entry(k).or_insert(0) += n;
+        }
+        for (k, names) in fusions {
+            self.fusions.entry(k).or_default().extend(names);
+        }
+    }
+
+    fn count_hit(&mut self, h: SegmentHit) {
+        match h {
+            SegmentHit::Nohit => self.nohit += 1,
+            SegmentHit::WrongStrand => self.wrong_strand += 1,
+            SegmentHit::Ambiguous => self.ambiguous += 1,
+            SegmentHit::LowQuality => self.low_hq_overlap += 1,
+            SegmentHit::IntronMismatch => self.intron_mismatch += 1,
+            SegmentHit::Intronic => self.intronic += 1,
+            SegmentHit::Hit(id) => self.hit[id] += 1,
+        }
+    }
+
+    pub fn write<W: Write>(&self, o: W, genes: &GeneMap, config: &Args) -> Result<()> {
+        let sample = config.bam.first().map(|b| sample_name(b, config)).unwrap_or_default();
+        self.write_table(o, genes, config, &sample)
+    }
+
+    /// Write the counts of a `sample`, with the version comment, the header line and the GC
+    /// content column of '--gc-content'
+    fn write_table<W: Write>(&self, o: W, genes: &GeneMap, config: &Args, sample: &str) -> Result<()> {
+        let mut w = BufWriter::new(o);
+        if config.summary_style == SummaryStyle::Gensum {
+            writeln!(w, "#gensum_schema={}", SCHEMA_VERSION)?;
+            write_header(&mut w, &[sample], config)?;
+        }
+        let mut ibuf = itoa::Buffer::new();
+        for geneidx in 0..self.hit.len() {
+            w.write_all(genes.hit_name(geneidx).unwrap())?;
+            w.write_all(b"\t")?;
+            self.write_gene_columns(&mut w, geneidx, &mut ibuf)?;
+            genes.write_gc_content(&mut w, geneidx)?;
+            w.write_all(b"\n")?;
+        }
+        if config.summary_style == SummaryStyle::Featurecounts {
+            writeln!(w, "Status\t{}", sample)?;
+        }
+        let pad = summary_padding(config);
+        let spike_in_factor = spike_in_size_factors(&[self], genes, config)[0];
+        for (name, value) in self.summary(genes, config, spike_in_factor)? {
+            writeln!(w, "{}\t{}{}{}", name, value, pad.sample, pad.gc)?;
+        }
+        Ok(())
+    }
+
+    /// The reads on the genes of the '--spike-ins' prefixes, like `gene_count`
+    fn spike_in_count(&self, genes: &GeneMap, config: &Args) -> f64 {
+        (0..self.hit.len())
+            .filter(|&idx| {
+                let id = genes.hit_name(idx).unwrap();
+                config.spike_ins.iter().any(|p| id.starts_with(p.as_bytes()))
+            })
+            .fold(0.0, |sum, idx| sum + self.gene_count(idx))
+    }
+
+    /// The count of a gene, with the fractions of the ambiguous reads of '--weight-by-overlap'
+    fn gene_count(&self, geneidx: usize) -> f64 {
+        self.hit[geneidx] as f64 + self.weighted.get(geneidx).copied().unwrap_or(0.0)
+    }
+
+    /// The counts of all genes, like `gene_count`
+    fn assigned_count(&self) -> f64 {
+        self.hit.iter().sum::<Count>() as f64 + self.weighted.iter().sum::<f64>()
+    }
+
+    /// Format a sum of `gene_count`s like the gene column, with two decimals with
+    /// '--weight-by-overlap'
+    fn format_count(&self, count: f64) -> String {
+        match self.weighted.is_empty() {
+            true => (count as Count).to_string(),
+            false => format!("{:.2}", count),
+        }
+    }
+
+    /// Write the count columns of a gene: the count, and the tss and read counts when enabled
+    fn write_gene_columns<W: Write>(&self, w: &mut W, geneidx: usize, ibuf: &mut itoa::Buffer) -> Result<()> {
+        if let Some(&weighted) = self.weighted.get(geneidx) {
+            write!(w, "{:.2}", self.hit[geneidx] as f64 + weighted)?;
+        } else {
+            w.write_all(ibuf.format(self.hit[geneidx]).as_bytes())?;
+        }
+        if let Some(&tss) = self.tss_hit.get(geneidx) {
+            w.write_all(b"\t")?;
+            w.write_all(ibuf.format(tss).as_bytes())?;
+        }
+        if let Some(&reads) = self.reads.get(geneidx) {
+            w.write_all(b"\t")?;
+            w.write_all(ibuf.format(reads).as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// The summary rows below the gene counts, by name with the formatted value. The
+    /// '--spike-ins' size factor depends on the other samples and is computed by the caller.
+    fn summary(&self, genes: &GeneMap, config: &Args, spike_in_factor: Option<f64>) -> Result<Vec<(String, String)>> {
+        let mut rows = Vec::new();
+        // the categories of options are written as 0 when the option is off, so the lines of the
+        // summary do not depend on the options
+        let categories = [
+            ("qc_failed", self.qc_failed),
+            ("unmapped", self.unmapped),
+            ("low_mapq", self.mapq),
+            ("secondary_alignments", self.secondary),
+            ("marked_duplicated", self.duplicated),
+            ("ambiguous", self.ambiguous),
+            ("ambiguous_pair", self.ambiguous_pair),
+            ("chr_not_in_gtf", self.notingtf),
+            ("contaminant", self.contaminant),
+            ("wrong_strand", self.wrong_strand),
+            ("nohit", self.nohit),
+            ("malformed_cigar", self.malformed_cigar),
+            ("orphan", self.orphan),
+            ("low_hq_overlap", self.low_hq_overlap),
+            ("intronic", self.intronic),
+            ("long_intron", self.long_intron),
+            ("intron_mismatch", self.intron_mismatch),
+            ("filtered", self.filtered.unwrap_or(0)),
+        ];
+
+        if config.summary_style != SummaryStyle::Gensum {
+            // write all categories of the other tool in its order, some combine several of ours.
+            // The ambiguous reads distributed by '--weight-by-overlap' are assigned, each adds 1
+            // over its genes.
+            let assigned = self.assigned_count().round() as Count;
+            for &name in config.summary_style.categories() {
+                let count: Count = categories.iter()
+                    .filter(|c| config.summary_style.rename(c.0) == Some(name))
+                    .map(|c| c.1)
+                    .sum();
+                rows.push((name.to_string(), (if name == "Assigned" { assigned } else { count }).to_string()));
+            }
+            return Ok(rows);
+        }
+
+        for (name, count) in categories {
+            rows.push((name.to_string(), count.to_string()));
+        }
+
+        let mut assigned = vec![0.0; genes.annotations.len()];
+        for (idx, gene) in genes.gene_info.iter().enumerate() {
+            assigned[gene.annotation] += self.gene_count(idx);
+        }
+        for (name, count) in genes.annotations.iter().zip(assigned) {
+            rows.push((format!("assigned_{}", name), self.format_count(count)));
+        }
+
+        let total = self.assigned_count();
+        for (gene, idx) in &genes.qc_genes {
+            let cpm = if total > 0.0 { self.gene_count(*idx) * 1e6 / total } else { 0.0 };
+            rows.push((format!("qc_{}", gene), self.format_count(self.gene_count(*idx))));
+            rows.push((format!("qc_{}_cpm", gene), format!("{:.2}", cpm)));
+        }
+
+        if !config.spike_ins.is_empty() {
+            let spike_ins = self.spike_in_count(genes, config);
+            let fraction = if total > 0.0 { spike_ins / total } else { 0.0 };
+            rows.push(("spike_in_counts".to_string(), self.format_count(spike_ins)));
+            rows.push(("spike_in_fraction".to_string(), format!("{:.6}", fraction)));
+            rows.push(("spike_in_size_factor".to_string(), spike_in_factor.map_or("NA".to_string(), |f| format!("{:.6}", f))));
+        }
+
+        if !self.gc_observed.is_empty() {
+            rows.push(("gc_bias".to_string(), format!("{:.4}", self.gc_bias(genes))));
+        }
+        if config.sex_check {
+            let sum = |markers: &[usize]| markers.iter().fold(0.0, |sum, &idx| sum + self.gene_count(idx));
+            rows.push(("sex_check_female".to_string(), self.format_count(sum(&genes.female_genes))));
+            rows.push(("sex_check_male".to_string(), self.format_count(sum(&genes.male_genes))));
+        }
+
+        Ok(rows)
+    }
+
+    /// Assign a read on GTF chromosome `chr` to a gene, and apply the ambiguity resolution and
+    /// bookkeeping of the reports
+    fn map_read(&mut self, r: &bam::Record, chr: usize, genes: &GeneMap, config: &Args) -> SegmentHit {
+        let map = &genes.intervals[chr];
+        let cigar = r.cigar();
+        let annotated = genes.annotated(chr, cigar.pos()..cigar.end_pos());
+        let mut m = if annotated { map_segments(r, map, config) } else { SegmentHit::Nohit };
+        if m == SegmentHit::Ambiguous && (config.prefer_shorter_gene || config.prefer_containing) {
+            m = genes.resolve_ambiguous(r, map, config);
+        }
+        if annotated && !self.boundary.is_empty() && m == SegmentHit::Nohit {
+            self.count_boundary_mismatch(r, map, config);
+        }
+        if config.intronic && m == SegmentHit::Nohit && (!annotated || candidate_genes(r, map, config, false).is_empty())
+            && genes.within_gene(r, chr, config) {
+            return SegmentHit::Intronic;
+        }
+        match m {
+            SegmentHit::Hit(id) if !genes.max_introns.is_empty()
+                && longest_intron(r) > genes.max_introns[id] => SegmentHit::IntronMismatch,
+            SegmentHit::Hit(id) if config.min_hq_overlap > 0
+                && hq_overlap(r, id, map, config.min_baseq) < config.min_hq_overlap => SegmentHit::LowQuality,
+            m => m,
+        }
+    }
+
+    /// Assign and count a single read. Returns the gene the read was counted on.
+    fn count_read(&mut self, r: &bam::Record, chr: usize, genes: &GeneMap, config: &Args) -> Option<usize> {
+        let m = self.map_read(r, chr, genes, config);
+        self.count_assigned(r, &m, chr, genes, config);
+        if !self.antisense.is_empty() && m == SegmentHit::WrongStrand {
+            if let SegmentHit::Hit(id) = map_antisense(r, &genes.intervals[chr], config) {
+                self.antisense[id] += 1;
+            }
+        }
+        let gene = m.gene();
+        self.count_strata(r, None, gene, genes, config);
+        let cigar = r.cigar();
+        if !(m == SegmentHit::Ambiguous && self.count_weighted(aligned_segments(&cigar), Strandness::fragment_forward(r), &genes.intervals[chr], config)) {
+            self.count_hit(m);
+        }
+        if !self.tss_hit.is_empty() && genes.near_tss(chr, cigar.pos()..cigar.end_pos()) {
+            if let SegmentHit::Hit(id) = map_segments(r, &genes.tss[chr], config) {
+                self.tss_hit[id] += 1;
+            }
+        }
+        gene
+    }
+
+    /// Distribute an ambiguous read over the genes it overlaps, in proportion to the aligned bases
+    /// on the exons of each gene. Returns false when weighting is off or no bases overlap.
+    fn count_weighted<I>(&mut self, segments: I, forward: bool, map: &NClist<Exon>, config: &Args) -> bool
+        where I: Iterator<Item = Range<i64>>
+    {
+        if self.weighted.is_empty() {
+            return false;
+        }
+        let bases = overlap_bases(segments, forward, map, config);
+        let total: i64 = bases.iter().map(|b| b.1).sum();
+        if total == 0 {
+            return false;
+        }
+        for (id, n) in bases {
+            let p = n as f64 / total as f64;
+            self.weighted[id] += p;
+            // the read belongs to the gene or not, with probability p
+            if !self.weighted_var.is_empty() {
+                self.weighted_var[id] += p * (1.0 - p);
+                self.weighted_reads[id] += 1;
+            }
+        }
+        true
+    }
+
+    /// Bookkeeping of the per gene reports for a read that is counted
+    fn count_assigned(&mut self, r: &bam::Record, m: &SegmentHit, chr: usize, genes: &GeneMap, config: &Args) {
+        self.count_coverage(r, m, chr, genes, config);
+        if let (false, SegmentHit::Hit(id)) = (self.windows.is_empty(), m) {
+            self.count_windows(r, *id, genes, config);
+        }
+        if let (false, SegmentHit::Hit(id)) = (self.exonic_bases.is_empty(), m) {
+            let bases = overlap_bases(aligned_segments(&r.cigar()), Strandness::fragment_forward(r), &genes.intervals[chr], config);
+            if let Some(&(_, n)) = bases.iter().find(|b| b.0 == *id) {
+                self.exonic_bases[*id] += n as Count;
+            }
+        }
+        if let (false, SegmentHit::Hit(id)) = (self.exon_usage.is_empty(), m) {
+            let map = &genes.exon_positions[chr];
+            let mut class = 0;
+            for o in aligned_segments(&r.cigar()) {
+                class |= map.overlaps(&o).filter(|e| e.id as usize == *id).fold(0, |c, e| c | e.class);
+            }
+            for (i, bit) in [ExonPosition::FIRST, ExonPosition::INTERNAL, ExonPosition::LAST].iter().enumerate() {
+                if class & bit != 0 {
+                    self.exon_usage[*id][i] += 1;
+                }
+            }
+        }
+    }
+
+    /// Count a read once in every window of the collapsed model of its gene that it overlaps.
+    /// The windows are numbered from the 5' end of the gene.
+    fn count_windows(&mut self, r: &bam::Record, id: usize, genes: &GeneMap, config: &Args) {
+        let model = &genes.models[id];
+        let len: i64 = model.iter().map(|e| e.end - e.start).sum();
+        let size = i64::from(config.gene_window_size);
+        if len == 0 {
+            return;
+        }
+        let windows = &mut self.windows[id];
+        if windows.is_empty() {
+            windows.resize(((len + size - 1) / size) as usize, 0);
+        }
+
+        let reverse = genes.gene_info[id].strand == Strand::Reverse;
+        let mut hit: Vec<usize> = Vec::new();
+        for o in aligned_segments(&r.cigar()) {
+            let mut offset = 0;
+            for e in model {
+                let (start, end) = (o.start.max(e.start), o.end.min(e.end));
+                if start < end {
+                    // the first and last base in model coordinates, from the 5' end
+                    let (first, last) = (offset + start - e.start, offset + end - 1 - e.start);
+                    let (first, last) = if reverse { (len - 1 - last, len - 1 - first) } else { (first, last) };
+                    hit.extend((first / size) as usize..=(last / size) as usize);
+                }
+                offset += e.end - e.start;
+            }
+        }
+        hit.sort_unstable();
+        hit.dedup();
+        for w in hit {
+            windows[w] += 1;
+        }
+    }
+
+    /// Add the aligned bases of a counted read to the coverage bins of its gene
+    fn count_coverage(&mut self, r: &bam::Record, m: &SegmentHit, chr: usize, genes: &GeneMap, config: &Args) {
+        if let (false, SegmentHit::Hit(id)) = (self.coverage.is_empty(), m) {
+            // the span of a gene with exons on several chromosomes is on its first chromosome
+            if genes.gene_info[*id].chr != chr {
+                return;
+            }
+            let span = &genes.gene_info[*id].span;
+            let len = (span.end - span.start) as usize;
+            let n = config.coverage_bins as usize;
+            let bins = &mut self.coverage[*id];
+            if bins.is_empty() {
+                bins.resize(n, 0);
+            }
+            for o in aligned_segments(&r.cigar()) {
+                for p in o.start.max(span.start)..o.end.min(span.end) {
+                    bins[(p - span.start) as usize * n / len] += 1;
+                }
+            }
+        }
+    }
+
+    /// Apply the read filters and count the rejected reads, returns if the read can be assigned.
+    /// Mapped reads without a cigar get an ungapped alignment with '--cigar-fallback'.
+    fn filter_read(&mut self, record: &mut bam::Record, config: &Args) -> bool {
+        let rejection = reject_read(record, config);
+        if rejection != Some(Rejection::Unmapped) && record.is_quality_check_failed() {
+            self.qc_failed += 1;
+        }
+        let counter = match rejection {
+            None => {
+                self.passed += 1;
+                return true;
+            },
+            Some(Rejection::Unmapped) => &mut self.unmapped,
+            Some(Rejection::Secondary) => &mut self.secondary,
+            Some(Rejection::Duplicate) => &mut self.duplicated,
+            Some(Rejection::LowMapq) => &mut self.mapq,
+            Some(Rejection::MalformedCigar) => &mut self.malformed_cigar,
+            Some(Rejection::LongIntron) => &mut self.long_intron,
+        };
+        *counter += 1;
+        false
+    }
+
+    /// Count a read pair with both mates on the same chromosome. Only pairs where both mates are
+    /// assigned to the same gene are counted, unless the mates are merged into one fragment.
+    /// Returns the gene the pair was counted on.
+    fn count_pair(&mut self, r: &bam::Record, mate: &bam::Record, chr: usize, genes: &GeneMap, config: &Args) -> Option<usize> {
+        if !self.tss_hit.is_empty() {
+            self.count_tss_pair(r, mate, chr, genes, config);
+        }
+        let weigh = |s: &mut Self| {
+            let (cigar, mate_cigar) = (r.cigar(), mate.cigar());
+            let segments = aligned_segments(&cigar).chain(aligned_segments(&mate_cigar));
+            s.count_weighted(segments, Strandness::fragment_forward(r), &genes.intervals[chr], config)
+        };
+        if config.merge_mates {
+            let (cigar, mate_cigar) = (r.cigar(), mate.cigar());
+            let annotated = genes.annotated(chr, cigar.pos()..cigar.end_pos())
+                || genes.annotated(chr, mate_cigar.pos()..mate_cigar.end_pos());
+            let m = if annotated { map_fragment(r, mate, &genes.intervals[chr], config) } else { SegmentHit::Nohit };
+            self.count_assigned(r, &m, chr, genes, config);
+            self.count_assigned(mate, &m, chr, genes, config);
+            let gene = m.gene();
+            self.count_strata(r, Some(mate), gene, genes, config);
+            if !(m == SegmentHit::Ambiguous && weigh(self)) {
+                self.count_hit(m);
+            }
+            return gene;
+        }
+
+        let m1 = self.map_read(r, chr, genes, config);
+        let m2 = self.map_read(mate, chr, genes, config);
+        if m1 == SegmentHit::Ambiguous && m2 == SegmentHit::Ambiguous && weigh(self) {
+            None
+        } else if m1 == m2 {
+            self.count_assigned(r, &m1, chr, genes, config);
+            self.count_assigned(mate, &m2, chr, genes, config);
+            if !self.antisense.is_empty() && m1 == SegmentHit::WrongStrand {
+                let map = &genes.intervals[chr];
+                if let (SegmentHit::Hit(a), SegmentHit::Hit(b)) = (map_antisense(r, map, config), map_antisense(mate, map, config)) {
+                    if a == b {
+                        self.antisense[a] += 1;
+                    }
+                }
+            }
+            let gene = m1.gene();
+            self.count_strata(r, Some(mate), gene, genes, config);
+            self.count_hit(m1);
+            gene
+        } else {
+            if let (true, SegmentHit::Hit(a), SegmentHit::Hit(b)) = (config.readthrough_report.is_some(), &m1, &m2) {
+                *self.bridging.entry((*a.min(b), *a.max(b))).or_insert(0) += 1;
+            }
+            if config.fusion_report.is_some() {
+                self.count_fusion(r.qname(), &m1, &m2, genes, config);
+            }
+            self.ambiguous_pair += 1;
+            None
+        }
+    }
+
+    /// Count a read pair on the TSS windows, independent of its gene body assignment
+    fn count_tss_pair(&mut self, r: &bam::Record, mate: &bam::Record, chr: usize, genes: &GeneMap, config: &Args) {
+        let (cigar, mate_cigar) = (r.cigar(), mate.cigar());
+        if !genes.near_tss(chr, cigar.pos()..cigar.end_pos()) && !genes.near_tss(chr, mate_cigar.pos()..mate_cigar.end_pos()) {
+            return;
+        }
+        let map = &genes.tss[chr];
+        let m = if config.merge_mates {
+            map_fragment(r, mate, map, config)
+        } else {
+            match (map_segments(r, map, config), map_segments(mate, map, config)) {
+                (m1, m2) if m1 == m2 => m1,
+                _ => SegmentHit::Ambiguous,
+            }
+        };
+        if let SegmentHit::Hit(id) = m {
+            self.tss_hit[id] += 1;
+        }
+    }
+
+    /// Count a fragment assigned to a gene in its strata, with '--stratify'
+    fn count_strata(&mut self, r: &bam::Record, mate: Option<&bam::Record>, gene: Option<usize>, genes: &GeneMap, config: &Args) {
+        if let (false, Some(id)) = (config.stratify.is_empty(), gene) {
+            let key = config.stratify.iter().map(|s| s.index(r, mate, &genes.gene_info[id])).collect();
+            *self.strata.entry((id, key)).or_insert(0) += 1;
+        }
+    }
+
+    /// Count a paired read whose mate was not found when the whole file was read, as an orphan
+    /// or as a single read by the '--singleton-policy'. Returns the gene the read was counted on.
+    fn count_orphan(&mut self, r: &bam::Record, chr: usize, genes: &GeneMap, config: &Args) -> Option<usize> {
+        match config.singleton_policy {
+            SingletonPolicy::Skip => {
+                self.orphan += 1;
+                None
+            },
+            SingletonPolicy::Count => self.count_read(r, chr, genes, config),
+        }
+    }
+
+    /// Record a read pair linking two genes on different chromosomes or far apart on the same
+    /// chromosome
+    fn count_fusion(&mut self, qname: &[u8], m1: &SegmentHit, m2: &SegmentHit, genes: &GeneMap, config: &Args) {
+        if let (SegmentHit::Hit(a), SegmentHit::Hit(b)) = (m1, m2) {
+            let (ga, gb) = (&genes.gene_info[*a], &genes.gene_info[*b]);
+            let gap = ga.span.start.max(gb.span.start) - ga.span.end.min(gb.span.end);
+            if ga.chr != gb.chr || gap >= config.fusion_min_distance {
+                self.fusions.entry((*a.min(b), *a.max(b))).or_default().push(qname.to_owned());
+            }
+        }
+    }
+
+    /// Write the gene pairs linked by read pairs with a mate on each gene, ranked by the number of
+    /// supporting read pairs, as a list of fusion candidates
+    pub fn write_fusion_report<W: Write>(&self, o: W, genes: &GeneMap) -> Result<()> {
+        let mut pairs: Vec<_> = self.fusions.iter().collect();
+        pairs.sort_by_key(|&(&genes, names)| (std::cmp::Reverse(names.len()), genes));
+
+        let mut w = BufWriter::new(o);
+        writeln!(w, "gene_id_a\tgene_id_b\tsupporting_pairs\tread_names")?;
+        for (&(a, b), names) in pairs {
+            w.write_all(genes.hit_name(a).unwrap())?;
+            w.write_all(b"\t")?;
+            w.write_all(genes.hit_name(b).unwrap())?;
+            write!(w, "\t{}\t", names.len())?;
+            // sorted, the order in which pairs complete depends on spilling
+            let mut names: Vec<_> = names.iter().collect();
+            names.sort();
+            for (i, name) in names.iter().enumerate() {
+                if i > 0 {
+                    w.write_all(b",")?;
+                }
+                w.write_all(name)?;
+            }
+            w.write_all(b"\n")?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the assigned counts summed per gene source, e.g. to see the contribution of custom
+    /// additions to the annotation. Genes without a source are reported as 'unknown'.
+    pub fn write_source_counts<W: Write>(&self, o: W, genes: &GeneMap) -> Result<()> {
+        let mut w = BufWriter::new(o);
+        write_counts_by(&mut w, &[self], genes, |g| g.source.as_deref())
+    }
+
+    /// Write the assigned counts summed per group of a gene-to-group table, in the order of the
+    /// table. Genes that are not in the GTF are reported and skipped.
+    pub fn write_group_counts<W: Write>(&self, o: W, genes: &GeneMap, table: &[(String, String)]) -> Result<()> {
+        let mut groups: IndexMap<&str, (usize, f64)> = IndexMap::new();
+        for (gene, group) in table {
+            let entry = groups.entry(group.as_str()).or_insert((0, 0.0));
+            match genes.find_gene(gene.as_bytes()) {
+                Some(idx) => *entry = (entry.0 + 1, entry.1 + self.gene_count(idx)),
+                None => eprintln!("Gene {} of group {} not found in GTF", gene, group),
+            }
+        }
+
+        let mut w = BufWriter::new(o);
+        writeln!(w, "group\tgenes\tcount")?;
+        for (group, (n, count)) in groups {
+            writeln!(w, "{}\t{}\t{}", group, n, self.format_count(count))?;
+        }
+
+        Ok(())
+    }
+
+    /// Record the exon boundaries violated by a read that was rejected in strict mode
+    fn count_boundary_mismatch(&mut self, r: &bam::Record, map: &NClist<Exon>, config: &Args) {
+        let cigar = r.cigar();
+        let forward = Strandness::fragment_forward(r);
+        for o in aligned_segments(&cigar) {
+            // per gene keep the exon that is violated by the smallest number of bases
+            let mut closest: Vec<(&Exon, i64, i64)> = Vec::new();
+            for e in map.overlaps(&o).filter(|e| config.strandness.matches(forward, e.strand)) {
+                let left = (e.range.start - o.start).max(0);
+                let right = (o.end - e.range.end).max(0);
+                match closest.iter_mut().find(|(c, _, _)| c.id == e.id) {
+                    Some(c) if left + right < c.1 + c.2 => *c = (e, left, right),
+                    Some(_) => {},
+                    None => closest.push((e, left, right)),
+                }
+            }
+
+            for (e, left, right) in closest {
+                let (five, three) = if e.strand == Strand::Reverse { (right, left) } else { (left, right) };
+                let b = &mut self.boundary[e.gene()];
+                if five > 0 {
+                    b.five_prime += 1;
+                    b.five_prime_bases += five as Count;
+                }
+                if three > 0 {
+                    b.three_prime += 1;
+                    b.three_prime_bases += three as Count;
+                }
+            }
+        }
+    }
+
+    /// Write the pairs of adjacent genes on the same strand that are bridged by read pairs with
+    /// one mate assigned to each gene, a signal of readthrough transcription. Pairs are sorted by
+    /// the number of bridging fragments.
+    pub fn write_readthrough_report<W: Write>(&self, o: W, genes: &GeneMap) -> Result<()> {
+        // rank the genes by position per chromosome and strand to find neighbours
+        let mut order: Vec<usize> = (0..genes.gene_info.len()).collect();
+        order.sort_by_key(|&i| {
+            let g = &genes.gene_info[i];
+            (g.chr, g.strand as u8, g.span.start, g.span.end)
+        });
+        let mut rank = vec![0; order.len()];
+        for (r, &i) in order.iter().enumerate() {
+            rank[i] = r;
+        }
+
+        let mut pairs: Vec<_> = self.bridging.iter()
+            .filter(|(&(a, b), _)| {
+                let (ga, gb) = (&genes.gene_info[a], &genes.gene_info[b]);
+                ga.chr == gb.chr && ga.strand == gb.strand && rank[a].abs_diff(rank[b]) == 1
+            })
+            .collect();
+        pairs.sort_by_key(|&(&genes, &n)| (std::cmp::Reverse(n), genes));
+
+        let mut w = BufWriter::new(o);
+        writeln!(w, "gene_id_a\tgene_id_b\tbridging_pairs\tcount_a\tcount_b")?;
+        for (&(a, b), n) in pairs {
+            w.write_all(genes.hit_name(a).unwrap())?;
+            w.write_all(b"\t")?;
+            w.write_all(genes.hit_name(b).unwrap())?;
+            writeln!(w, "\t{}\t{}\t{}", n, self.hit[a], self.hit[b])?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the per gene exon boundary violations of reads rejected in strict mode. Genes
+    /// without violations are omitted.
+    pub fn write_boundary_report<W: Write>(&self, o: W, genes: &GeneMap) -> Result<()> {
+        let mut w = BufWriter::new(o);
+        writeln!(w, "gene_id\tfive_prime_reads\tfive_prime_bases\tthree_prime_reads\tthree_prime_bases")?;
+        for (geneidx, b) in self.boundary.iter().enumerate() {
+            if b.five_prime == 0 && b.three_prime == 0 {
+                continue;
+            }
+            w.write_all(genes.hit_name(geneidx).unwrap())?;
+            writeln!(w, "\t{}\t{}\t{}\t{}", b.five_prime, b.five_prime_bases, b.three_prime, b.three_prime_bases)?;
+        }
+
+        Ok(())
+    }
+
+    /// All alignment records, the first stage of the funnel
+    fn funnel_total(&self) -> Count {
+        self.unmapped + self.secondary + self.duplicated + self.mapq + self.malformed_cigar + self.long_intron + self.passed
+    }
+
+    /// The alignment records left after each read filter, in the order `reject_read` applies them,
+    /// and the assigned reads. Filters that are switched off are left out.
+    fn funnel(&self, config: &Args) -> Vec<(&'static str, Count)> {
+        let mut left = self.funnel_total();
+        let mut stages = vec![("total", left)];
+        let removed = [
+            ("mapped", self.unmapped, true),
+            ("primary", self.secondary, true),
+            ("not_duplicate", self.duplicated, !config.usedups),
+            ("mapq", self.mapq, true),
+            ("valid_cigar", self.malformed_cigar, true),
+            ("intron_span", self.long_intron, config.max_intron_span.is_some()),
+            ("read_filters", self.filtered.unwrap_or(0), self.filtered.is_some()),
+        ];
+        for (stage, n, applied) in removed {
+            if applied {
+                left = left.saturating_sub(n);
+                stages.push((stage, left));
+            }
+        }
+        stages.push(("assigned", self.assigned_reads));
+        stages
+    }
+
+    /// Write the reads left after each filter stage as a funnel, with the percentage of all
+    /// alignment records, as a tab separated table or as JSON. Both mates of a counted pair are
+    /// assigned reads.
+    pub fn write_funnel<W: Write>(&self, o: W, config: &Args, json: bool) -> Result<()> {
+        let stages = self.funnel(config);
+        let total = stages[0].1;
+        let percent = |n: Count| if total == 0 { 0.0 } else { 100.0 * n as f64 / total as f64 };
+        let mut w = BufWriter::new(o);
+        if json {
+            let rows: Vec<_> = stages.iter()
+                .map(|&(stage, n)| format!("    {{\"stage\": \"{}\", \"reads\": {}, \"percent\": {:.2}}}", stage, n, percent(n)))
+                .collect();
+            writeln!(w, "{{\n  \"stages\": [\n{}\n  ]\n}}", rows.join(",\n"))?;
+        } else {
+            writeln!(w, "stage\treads\tpercent")?;
+            for (stage, n) in stages {
+                writeln!(w, "{}\t{}\t{:.2}", stage, n, percent(n))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the uncertainty of the counts of the genes that got a share of ambiguous reads with
+    /// '--weight-by-overlap': the fraction of the count from ambiguous reads, the standard
+    /// deviation when every ambiguous read belongs to one of its genes with the probability of its
+    /// share, and a 95% interval within the unique count and the unique plus ambiguous reads.
+    pub fn write_uncertainty_report<W: Write>(&self, o: W, genes: &GeneMap) -> Result<()> {
+        let mut w = BufWriter::new(o);
+        writeln!(w, "gene_id\tcount\tunique\tambiguous_reads\tambiguous_fraction\tsd\tlow\thigh")?;
+        for (geneidx, &reads) in self.weighted_reads.iter().enumerate() {
+            if reads == 0 {
+                continue;
+            }
+            let unique = self.hit[geneidx] as f64;
+            let count = unique + self.weighted[geneidx];
+            let sd = self.weighted_var[geneidx].sqrt();
+            let low = (count - 1.96 * sd).max(unique);
+            let high = (count + 1.96 * sd).min(unique + reads as f64);
+            w.write_all(genes.hit_name(geneidx).unwrap())?;
+            writeln!(w, "\t{:.2}\t{}\t{}\t{:.3}\t{:.2}\t{:.2}\t{:.2}", count, self.hit[geneidx], reads,
+                self.weighted[geneidx] / count, sd, low, high)?;
+        }
+
+        Ok(())
+    }
+
+    /// The expected distribution of the GC bases of the read starts, when the reads of each gene
+    /// start uniformly along its counting regions, and the observed distribution
+    fn gc_distributions(&self, genes: &GeneMap) -> (Vec<f64>, Vec<f64>) {
+        let mut expected = vec![0.0; GC_WINDOW + 1];
+        for (&count, windows) in self.hit.iter().zip(&genes.gc_windows) {
+            let n: u32 = windows.iter().sum();
+            if count > 0 && n > 0 {
+                for (e, &w) in expected.iter_mut().zip(windows) {
+                    *e += count as f64 * w as f64 / n as f64;
+                }
+            }
+        }
+        let fractions = |v: Vec<f64>| {
+            let total: f64 = v.iter().sum();
+            v.into_iter().map(|x| if total > 0.0 { x / total } else { 0.0 }).collect::<Vec<_>>()
+        };
+        (fractions(expected), fractions(self.gc_observed.iter().map(|&n| n as f64).collect()))
+    }
+
+    /// The GC bias of the sample, the total variation distance between the observed and expected
+    /// GC distributions of the read starts: 0 without bias, 1 when they do not overlap
+    pub fn gc_bias(&self, genes: &GeneMap) -> f64 {
+        let (expected, observed) = self.gc_distributions(genes);
+        expected.iter().zip(&observed).map(|(e, o)| (e - o).abs()).sum::<f64>() / 2.0
+    }
+
+    /// Write the observed and expected fractions of the assigned reads by the GC percentage of
+    /// their first `GC_WINDOW` bases. The expected fractions take the gene counts of the sample
+    /// with reads starting uniformly along the counting regions of their gene. Amplification bias
+    /// shows as a ratio that rises or falls with the GC percentage.
+    pub fn write_gc_bias<W: Write>(&self, o: W, genes: &GeneMap) -> Result<()> {
+        let mut w = BufWriter::new(o);
+        let (expected, observed) = self.gc_distributions(genes);
+        writeln!(w, "gc\tobserved\texpected\tratio")?;
+        for (gc, (e, o)) in expected.iter().zip(&observed).enumerate() {
+            write!(w, "{}\t{:.4}\t{:.4}\t", gc * 100 / GC_WINDOW, o, e)?;
+            if *e > 0.0 {
+                writeln!(w, "{:.3}", o / e)?;
+            } else {
+                writeln!(w, "NA")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the genes whose fraction of antisense reads exceeds the median fraction of the sample
+    /// by more than `STRAND_REPORT_EXCESS`, a sign of a gene annotated on the wrong strand. Only
+    /// genes with at least `STRAND_REPORT_MIN_READS` sense and antisense reads are considered.
+    pub fn write_strand_report<W: Write>(&self, o: W, genes: &GeneMap) -> Result<()> {
+        let fractions: Vec<(usize, f64)> = self.antisense.iter().enumerate()
+            .map(|(i, &a)| (i, self.gene_count(i), a as f64))
+            .filter(|&(_, s, a)| s + a >= STRAND_REPORT_MIN_READS as f64)
+            .map(|(i, s, a)| (i, a / (s + a)))
+            .collect();
+
+        let mut sorted: Vec<f64> = fractions.iter().map(|f| f.1).collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = if sorted.is_empty() { 0.0 } else { sorted[sorted.len() / 2] };
+        if median > STRAND_REPORT_SWAPPED_MEDIAN {
+            eprintln!("Warning: median antisense fraction is {:.3}, check the --strandness of the library", median);
+        }
+
+        let mut w = BufWriter::new(o);
+        writeln!(w, "gene_id\tsense\tantisense\tantisense_fraction\tsample_median")?;
+        for (geneidx, fraction) in fractions.into_iter().filter(|f| f.1 - median > STRAND_REPORT_EXCESS) {
+            w.write_all(genes.hit_name(geneidx).unwrap())?;
+            writeln!(w, "\t{}\t{}\t{:.3}\t{:.3}", self.format_count(self.gene_count(geneidx)), self.antisense[geneidx], fraction, median)?;
+        }
+
+        Ok(())
+    }
+
+    /// Compare the gene counts with the expected counts of a truth table of `read_truth`, e.g. of
+    /// a simulation. The deviation of a gene is relative to its expected count, or 1 below that.
+    /// Fails when a deviation is above the tolerance, or a gene is not in the GTF.
+    pub fn compare_truth(&self, genes: &GeneMap, truth: &[(String, f64)], tolerance: f64) -> Result<()> {
+        let mut deviations = Vec::with_capacity(truth.len());
+        let mut missing = 0;
+        for (gene, expected) in truth {
+            match genes.find_gene(gene.as_bytes()) {
+                Some(idx) => {
+                    let observed = self.gene_count(idx);
+                    deviations.push((gene.as_str(), *expected, observed, (observed - expected).abs() / expected.max(1.0)));
+                },
+                None => {
+                    eprintln!("truth: gene {} not found in GTF", gene);
+                    missing += 1;
+                },
+            }
+        }
+
+        let n = deviations.len().max(1) as f64;
+        let mean = deviations.iter().map(|d| d.3).sum::<f64>() / n;
+        let max = deviations.iter().map(|d| d.3).fold(0.0, f64::max);
+        let failed: Vec<_> = deviations.iter().filter(|d| d.3 > tolerance).collect();
+        eprintln!("truth: {} genes compared, mean relative deviation {:.4}, max {:.4}, {} above tolerance {}",
+            deviations.len(), mean, max, failed.len(), tolerance);
+        for (gene, expected, observed, deviation) in failed.iter().take(20) {
+            eprintln!("truth: {}\texpected {}\tobserved {}\tdeviation {:.4}", gene, expected, observed, deviation);
+        }
+
+        if !failed.is_empty() || missing > 0 {
+            return Err(anyhow!("truth: {} genes above tolerance, {} genes not found", failed.len(), missing));
+        }
+        Ok(())
+    }
+
+    /// The value of a '--fail-if' metric
+    fn qc_metric(&self, metric: QcMetric, genes: &GeneMap) -> f64 {
+        let total = self.funnel_total();
+        let records = |n: Count| if total == 0 { 0.0 } else { n as f64 / total as f64 };
+        // assigned reads are primary alignments, both mates of a pair
+        let primary = total - self.secondary;
+        let assigned = self.assigned_count();
+        let fragments = |on: &dyn Fn(&Gene) -> bool| {
+            let n = genes.gene_info.iter().enumerate().filter(|(_, g)| on(g)).fold(0.0, |n, (idx, _)| n + self.gene_count(idx));
+            if assigned == 0.0 { 0.0 } else { n / assigned }
+        };
+        match metric {
+            QcMetric::Total => total as f64,
+            QcMetric::Assigned => self.assigned_reads as f64,
+            QcMetric::AssignedFrac => if primary == 0 { 0.0 } else { self.assigned_reads as f64 / primary as f64 },
+            QcMetric::UnmappedFrac => records(self.unmapped),
+            QcMetric::DupFrac => records(self.duplicated),
+            QcMetric::LowMapqFrac => records(self.mapq),
+            // rRNA, Mt_rRNA and rRNA_pseudogene
+            QcMetric::RrnaFrac => fragments(&|g| matches!(&g.biotype, Some(b) if b.windows(4).any(|w| w == b"rRNA"))),
+            QcMetric::MitoFrac => fragments(&|g| matches!(genes.seq_names[g.chr].as_slice(), b"MT" | b"chrM" | b"M" | b"chrMT")),
+        }
+    }
+
+    /// The conditions of the first group of the '--fail-if' gate that all hold, with the values of
+    /// the sample, or None when the sample passes
+    pub fn qc_failure(&self, gate: &QcGate, genes: &GeneMap) -> Option<String> {
+        gate.0.iter().find(|group| group.iter().all(|c| c.holds(self.qc_metric(c.metric, genes))))
+            .map(|group| {
+                let conditions: Vec<_> = group.iter()
+                    .map(|c| format!("{}={}{}{}", c.metric.name(), self.qc_metric(c.metric, genes), c.op.symbol(), c.value))
+                    .collect();
+                conditions.join(" && ")
+            })
+    }
+
+    /// Write the reads per window along the collapsed model of each gene with reads, from 5' to 3'.
+    /// The last window of a gene is shorter when the model length is not a multiple of the size.
+    pub fn write_gene_windows<W: Write>(&self, o: W, genes: &GeneMap) -> Result<()> {
+        let mut w = BufWriter::new(o);
+        writeln!(w, "gene_id\twindow\tcount")?;
+        for (geneidx, windows) in self.windows.iter().enumerate() {
+            for (i, count) in windows.iter().enumerate() {
+                w.write_all(genes.hit_name(geneidx).unwrap())?;
+                writeln!(w, "\t{}\t{}", i + 1, count)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the counts per gene and stratum of the '--stratify' properties as a long table, with
+    /// a line for each combination that has reads
+    pub fn write_stratified_counts<W: Write>(&self, o: W, genes: &GeneMap, strata: &[Stratum]) -> Result<()> {
+        let mut counts: Vec<_> = self.strata.iter().collect();
+        counts.sort_unstable();
+
+        let mut w = BufWriter::new(o);
+        write!(w, "gene_id")?;
+        for s in strata {
+            write!(w, "\t{}", s.name())?;
+        }
+        writeln!(w, "\tcount")?;
+        for ((geneidx, key), count) in counts {
+            w.write_all(genes.hit_name(*geneidx).unwrap())?;
+            for (s, &i) in strata.iter().zip(key) {
+                write!(w, "\t{}", s.label(i))?;
+            }
+            writeln!(w, "\t{}", count)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the reads and the aligned bases of the reads on the exons of each gene. Both mates of
+    /// a pair add their bases
+    pub fn write_exonic_bases<W: Write>(&self, o: W, genes: &GeneMap) -> Result<()> {
+        let mut w = BufWriter::new(o);
+        writeln!(w, "gene_id\treads\texonic_bases")?;
+        for (geneidx, (reads, bases)) in self.hit.iter().zip(&self.exonic_bases).enumerate() {
+            w.write_all(genes.hit_name(geneidx).unwrap())?;
+            writeln!(w, "\t{}\t{}", reads, bases)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the number of reads on the first, internal and last exons of the genes with reads.
+    /// A read on an exon that has several positions in the transcripts counts for each.
+    pub fn write_exon_usage<W: Write>(&self, o: W, genes: &GeneMap) -> Result<()> {
+        let mut w = BufWriter::new(o);
+        writeln!(w, "gene_id\tfirst_exon\tinternal_exon\tlast_exon")?;
+        for (geneidx, u) in self.exon_usage.iter().enumerate() {
+            if u.iter().all(|&c| c == 0) {
+                continue;
+            }
+            w.write_all(genes.hit_name(geneidx).unwrap())?;
+            writeln!(w, "\t{}\t{}\t{}", u[0], u[1], u[2])?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the binned coverage along each gene with counted reads, from 5' to 3'. The values are
+    /// the mean depth of the bin per million assigned reads, comparable between genes and samples.
+    pub fn write_coverage_profile<W: Write>(&self, o: W, genes: &GeneMap) -> Result<()> {
+        let mut w = BufWriter::new(o);
+        let total = self.assigned_count();
+        let scale = if total > 0.0 { 1e6 / total } else { 0.0 };
+        let Some(n) = self.coverage.iter().map(|c| c.len()).max() else {
+            return Ok(());
+        };
+
+        write!(w, "gene_id")?;
+        for i in 1..=n {
+            write!(w, "\tbin_{}", i)?;
+        }
+        writeln!(w)?;
+
+        for (geneidx, bins) in self.coverage.iter().enumerate().filter(|(_, c)| !c.is_empty()) {
+            let g = &genes.gene_info[geneidx];
+            let bin_len = (g.span.end - g.span.start) as f64 / bins.len() as f64;
+            w.write_all(genes.hit_name(geneidx).unwrap())?;
+            let values: Box<dyn Iterator<Item = &Count>> = if g.strand == Strand::Reverse {
+                Box::new(bins.iter().rev())
+            } else {
+                Box::new(bins.iter())
+            };
+            for &b in values {
+                write!(w, "\t{:.4}", b as f64 / bin_len * scale)?;
+            }
+            writeln!(w)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The sample name of a bam file in the header, '--sample-name' or its file name without the
+/// extension
+pub fn sample_name(bam_file: &Path, config: &Args) -> String {
+    if let Some(name) = config.sample_name.as_ref() {
+        return name.clone();
+    }
+    if bam_file == Path::new("-") {
+        return "stdin".to_string();
+    }
+    let name = bam_file.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    ["bam", "sam", "cram"].iter()
+        .find_map(|ext| name.strip_suffix(ext).and_then(|n| n.strip_suffix('.')))
+        .unwrap_or(&name)
+        .to_string()
+}
+
+/// The name of an annotation file in the summary, the file name without the compression and
+/// format extensions
+fn annotation_name(p: &Path) -> String {
+    if p == Path::new("-") {
+        return "stdin".to_string();
+    }
+    let name = p.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    name.rsplit_once('.').map_or(name, |(stem, _)| stem).to_string()
+}
+
+/// The read groups of the @RG header lines by their platform unit (PU), e.g. the flowcell and lane
+/// 'HGKVLDSXX.1'. Read groups without PU are a lane of their own, named by their ID.
+fn read_group_lanes(header: &bam::HeaderView) -> IndexMap<String, HashSet<Vec<u8>>> {
+    let mut lanes: IndexMap<String, HashSet<Vec<u8>>> = IndexMap::new();
+    for line in header.as_bytes().split(|&c| c == b'\n').filter(|l| l.starts_with(b"@RG\t")) {
+        let field = |tag: &[u8]| line.split(|&c| c == b'\t').find_map(|f| f.strip_prefix(tag));
+        if let Some(id) = field(b"ID:") {
+            let lane = String::from_utf8_lossy(field(b"PU:").unwrap_or(id)).into_owned();
+            lanes.entry(lane).or_default().insert(id.to_vec());
+        }
+    }
+    lanes
+}
+
+/// The sort order (SO) of the @HD header line, e.g. 'coordinate' or 'queryname'
+fn sort_order(header: &bam::HeaderView) -> Option<&[u8]> {
+    let line = header.as_bytes().split(|&c| c == b'\n').next().filter(|l| l.starts_with(b"@HD\t"))?;
+    line.split(|&c| c == b'\t').find_map(|f| f.strip_prefix(b"SO:"))
+}
+
+/// The lane of each read group for '--split-by-lane', see `read_group_lanes`. The reads without
+/// RG tag or with a read group that is not in the header are in an extra last lane.
+struct LaneIndex {
+    groups: HashMap<Vec<u8>, usize>,
+    lanes: usize,
+}
+
+impl LaneIndex {
+    fn new(lanes: &IndexMap<String, HashSet<Vec<u8>>>) -> LaneIndex {
+        let groups = lanes.values().enumerate()
+            .flat_map(|(i, groups)| groups.iter().map(move |g| (g.clone(), i)))
+            .collect();
+        LaneIndex { groups, lanes: lanes.len() }
+    }
+
+    /// The lane of a read, by its RG tag
+    fn lane(&self, record: &bam::Record) -> usize {
+        match record.aux(b"RG") {
+            Ok(bam::record::Aux::String(rg)) => self.groups.get(rg.as_bytes()).copied().unwrap_or(self.lanes),
+            _ => self.lanes,
+        }
+    }
+}
+
+/// The index in the counts of each lane of a read, 0 without lanes
+fn lane_of(lanes: Option<&LaneIndex>, record: &bam::Record) -> usize {
+    lanes.map_or(0, |l| l.lane(record))
+}
+
+/// The counts of each lane, or of all reads without lanes
+fn empty_lane_counts(lanes: Option<&LaneIndex>, genemap: &GeneMap, config: &Args, filters: &[&dyn ReadFilter]) -> Vec<ReadMappings> {
+    let n = lanes.map_or(1, |l| l.lanes + 1);
+    (0..n).map(|_| {
+        let mut counts = ReadMappings::with_config(genemap.genes.len(), config);
+        if !filters.is_empty() {
+            counts.filtered = Some(0);
+        }
+        counts
+    }).collect()
+}
+
+/// Quantify the reads of each lane of a bam file, see `read_group_lanes`, named by the sample and
+/// the lane, in one pass. The reads without a read group of the header are counted as the last
+/// lane, 'unassigned_lane', so the lanes add up to the sample.
+pub fn quantify_lanes(bam_file: &Path, config: &Args, genemap: &GeneMap) -> Result<Vec<(String, ReadMappings)>> {
+    let bam = open_bam(bam_file, config)?;
+    let lanes = read_group_lanes(bam.header());
+    if lanes.is_empty() {
+        return Err(anyhow!("--split-by-lane: no @RG header lines in {}", bam_file.display()));
+    }
+    let sample = sample_name(bam_file, config);
+    let counts = quantify_lane_reads(bam, config, genemap, &[], Some(&LaneIndex::new(&lanes)), |_, _| {})?;
+    let names = lanes.keys().map(String::as_str).chain(std::iter::once("unassigned_lane"));
+    Ok(names.zip(counts).map(|(lane, res)| (format!("{}_{}", sample, lane), res)).collect())
+}
+
+/// The empty fields that pad the summary rows of a table with a header to the width of the gene
+/// rows, after the value of each sample and at the end of the row
+struct SummaryPadding {
+    sample: &'static str,
+    gc: &'static str,
+}
+
+fn summary_padding(config: &Args) -> SummaryPadding {
+    if config.summary_style != SummaryStyle::Gensum {
+        return SummaryPadding { sample: "", gc: "" };
+    }
+    let sample = match (config.dual_counts, config.read_counts) {
+        (true, true) => "\t\t",
+        (false, false) => "",
+        _ => "\t",
+    };
+    SummaryPadding { sample, gc: if config.gc_content { "\t" } else { "" } }
+}
+
+/// The '--spike-ins' size factors of the samples, as the size factors of DESeq: the spike-in
+/// count of a sample divided by the geometric mean of the samples. A sample without spike-in
+/// reads has no factor and is left out of the mean, a single sample has a factor of 1.
+fn spike_in_size_factors(samples: &[&ReadMappings], genes: &GeneMap, config: &Args) -> Vec<Option<f64>> {
+    if config.spike_ins.is_empty() {
+        return vec![None; samples.len()];
+    }
+    let counts: Vec<f64> = samples.iter().map(|res| res.spike_in_count(genes, config)).collect();
+    let logs: Vec<f64> = counts.iter().filter(|&&c| c > 0.0).map(|c| c.ln()).collect();
+    let mean = (logs.iter().sum::<f64>() / logs.len() as f64).exp();
+    counts.into_iter().map(|c| (c > 0.0).then(|| c / mean)).collect()
+}
+
+/// Write the header line of the counts: gene_id and the count columns of each sample
+fn write_header<W: Write>(w: &mut W, samples: &[&str], config: &Args) -> Result<()> {
+    write!(w, "gene_id")?;
+    for name in samples {
+        write!(w, "\t{}", name)?;
+        if config.dual_counts {
+            write!(w, "\t{}_tss", name)?;
+        }
+        if config.read_counts {
+            write!(w, "\t{}_reads", name)?;
+        }
+    }
+    if config.gc_content {
+        write!(w, "\tgc")?;
+    }
+    writeln!(w)?;
+    Ok(())
+}
+
+/// Write the assigned counts summed per gene biotype, with a header line and a column per
+/// sample. Genes without a biotype are reported as 'unknown'.
+pub fn write_biotype_counts<W: Write>(o: W, samples: &[(String, ReadMappings)], genes: &GeneMap) -> Result<()> {
+    let mut w = BufWriter::new(o);
+    write!(w, "biotype")?;
+    for (name, _) in samples {
+        write!(w, "\t{}", name)?;
+    }
+    writeln!(w)?;
+    let samples: Vec<&ReadMappings> = samples.iter().map(|(_, res)| res).collect();
+    write_counts_by(&mut w, &samples, genes, |g| g.biotype.as_deref())
+}
+
+/// Write the assigned counts of each sample summed per gene property, sorted by the property
+fn write_counts_by<W, F>(w: &mut W, samples: &[&ReadMappings], genes: &GeneMap, key: F) -> Result<()>
+    where W: Write, F: Fn(&Gene) -> Option<&[u8]>
+{
+    let mut keys: IndexMap<&[u8], Vec<f64>> = IndexMap::new();
+    for (idx, gene) in genes.gene_info.iter().enumerate() {
+        let counts = keys.entry(key(gene).unwrap_or(b"unknown")).or_insert_with(|| vec![0.0; samples.len()]);
+        for (count, res) in counts.iter_mut().zip(samples) {
+            *count += res.gene_count(idx);
+        }
+    }
+    keys.sort_keys();
+
+    for (key, counts) in keys {
+        w.write_all(key)?;
+        for (count, res) in counts.into_iter().zip(samples) {
+            write!(w, "\t{}", res.format_count(count))?;
+        }
+        writeln!(w)?;
+    }
+    w.flush()?;
+
+    Ok(())
+}
+
+/// Write the counts of several samples as one table with a column per sample and a header line
+/// with the sample names. The gene rows are followed by the summary rows of the single sample
+/// output, joined by name, a row missing for a sample is 0.
+pub fn write_count_matrix<W: Write>(o: W, samples: &[(String, ReadMappings)], genes: &GeneMap, config: &Args) -> Result<()> {
+    let mut names: Vec<&str> = Vec::with_capacity(samples.len());
+    for (name, _) in samples {
+        if names.contains(&name.as_str()) {
+            return Err(anyhow!("Sample name {} occurs more than once, the columns of the count matrix must be unique", name));
+        }
+        names.push(name);
+    }
+
+    let mut w = BufWriter::new(o);
+    if config.summary_style == SummaryStyle::Gensum {
+        writeln!(w, "#gensum_schema={}", SCHEMA_VERSION)?;
+    }
+    write_header(&mut w, &names, config)?;
+
+    let mut ibuf = itoa::Buffer::new();
+    for geneidx in 0..genes.genes.len() {
+        w.write_all(genes.hit_name(geneidx).unwrap())?;
+        for (_, res) in samples {
+            w.write_all(b"\t")?;
+            res.write_gene_columns(&mut w, geneidx, &mut ibuf)?;
+        }
+        genes.write_gc_content(&mut w, geneidx)?;
+        w.write_all(b"\n")?;
+    }
+
+    let spike_in_factors = spike_in_size_factors(&samples.iter().map(|s| &s.1).collect::<Vec<_>>(), genes, config);
+    let mut summary: IndexMap<String, Vec<Option<String>>> = IndexMap::new();
+    for (i, (_, res)) in samples.iter().enumerate() {
+        for (name, value) in res.summary(genes, config, spike_in_factors[i])? {
+            summary.entry(name).or_insert_with(|| vec![None; samples.len()])[i] = Some(value);
+        }
+    }
+    if config.summary_style == SummaryStyle::Featurecounts {
+        writeln!(w, "Status\t{}", names.join("\t"))?;
+    }
+    let pad = summary_padding(config);
+    for (name, values) in summary {
+        w.write_all(name.as_bytes())?;
+        for value in values {
+            write!(w, "\t{}{}", value.as_deref().unwrap_or("0"), pad.sample)?;
+        }
+        writeln!(w, "{}", pad.gc)?;
+    }
+    Ok(())
+}
+
+pub fn quantify_bam<P: AsRef<Path>>(bam_file: P, config: &Args, genemap: &GeneMap) -> Result<ReadMappings> {
+    quantify_bam_with(bam_file, config, genemap, |_, _| {})
+}
+
+/// Quantify a bam file and call `on_assigned` for every read that is counted on a gene, with the
+/// index of the gene (see `GeneMap::hit_name`). Both mates of a counted pair are passed. The
+/// path '-' reads from stdin.
+pub fn quantify_bam_with<P, F>(bam_file: P, config: &Args, genemap: &GeneMap, on_assigned: F) -> Result<ReadMappings>
+    where P: AsRef<Path>, F: FnMut(&bam::Record, usize)
+{
+    quantify_reader_with(open_bam(bam_file.as_ref(), config)?, config, genemap, on_assigned)
+}
+
+/// Open a bam, sam or cram file, a url or stdin for '-'
+fn open_bam(bam_file: &Path, config: &Args) -> Result<bam::Reader> {
+    let mut bam = match bam_url(bam_file)? {
+        Some(url) => bam::Reader::from_url(&url)?,
+        None if bam_file == Path::new("-") => bam::Reader::from_stdin()?,
+        None => bam::Reader::from_path(bam_file)?,
+    };
+    // test from command line show improve until 4 cpu's
+    bam.set_threads(4)?;
+    if let Some(fasta) = config.reference.as_ref() {
+        bam.set_reference(fasta)?;
+    }
+    Ok(bam)
+}
+
+/// Write the alignments of a bam file with an XF tag, like htseq-count: the gene_id of an assigned
+/// read or the summary category of the other reads. The reads are not counted and kept in the
+/// order of the input, so the mates of a pair are assigned separately. Writes to stdout without
+/// an output file.
+pub fn annotate_bam(bam_file: &Path, config: &Args, genemap: &GeneMap, out: Option<&Path>) -> Result<()> {
+    let mut bam = open_bam(bam_file, config)?;
+    let header = bam::Header::from_template(bam.header());
+    let mut w = match out {
+        Some(p) => bam::Writer::from_path(p, &header, bam::Format::Bam)?,
+        None => bam::Writer::from_stdout(&header, bam::Format::Bam)?,
+    };
+    w.set_threads(4)?;
+
+    let target_names = bam.header().target_names();
+    let tid_map = genemap.contig_map(&target_names, config.exact_chr_names);
+    let contaminant_contigs: HashSet<&[u8]> = config.contaminant_contigs.iter().map(|c| c.as_bytes()).collect();
+    let contaminant: Vec<_> = target_names.iter().map(|name| contaminant_contigs.contains(name)).collect();
+    // the bookkeeping of the reports is not written
+    let mut scratch = ReadMappings::with_config(genemap.genes.len(), config);
+    let mut record = bam::Record::new();
+    let mut lookup = bam::Record::new();
+    while let Some(r) = bam.read(&mut record) {
+        r?;
+        // '--cigar-fallback' gives the read an ungapped cigar to find its gene, the alignment is
+        // written unchanged
+        let scan = if record.cigar_len() == 0 {
+            lookup.clone_from(&record);
+            &mut lookup
+        } else {
+            &mut record
+        };
+        let hit = match reject_read(scan, config) {
+            Some(rejection) => Err(rejection.category()),
+            None if contaminant[scan.tid() as usize] => Err("contaminant"),
+            None => match tid_map[scan.tid() as usize] {
+                Some(chr) => {
+                    let m = scratch.map_read(scan, chr, genemap, config);
+                    m.gene().ok_or_else(|| m.category().unwrap_or("nohit"))
+                },
+                None => Err("chr_not_in_gtf"),
+            },
+        };
+        let value = match hit {
+            Ok(gene) => String::from_utf8_lossy(&genemap.genes[gene]),
+            Err(category) => category.into(),
+        };
+        // replace the tag of an earlier run
+        let _ = record.remove_aux(b"XF");
+        record.push_aux(b"XF", bam::record::Aux::String(&value))?;
+        w.write(&record)?;
+    }
+    Ok(())
+}
+
+/// The url of a remote bam file, e.g. https:// or s3://. htslib also finds the index next to it.
+fn bam_url(p: &Path) -> Result<Option<url::Url>> {
+    match p.to_str() {
+        Some(s) if s.contains("://") => Ok(Some(url::Url::parse(s).map_err(|e| anyhow!("Invalid url {}: {}", s, e))?)),
+        _ => Ok(None),
+    }
+}
+
+/// log2 of the bin size of the bitmaps of annotated regions, small enough to leave most intergenic
+/// space unannotated and large enough to keep the bitmaps of a large genome below a megabyte
+const ANNOTATED_BIN_SHIFT: u32 = 10;
+
+/// The bins of 2^ANNOTATED_BIN_SHIFT bases per chromosome that overlap the regions of the
+/// interval lists, as bitmaps
+fn region_bins(maps: &[NClist<Exon>]) -> Vec<Vec<u64>> {
+    let all = i64::MIN..i64::MAX;
+    maps.iter().map(|map| {
+        let mut bins: Vec<u64> = Vec::new();
+        for e in map.overlaps(&all).filter(|e| e.range.end > e.range.start) {
+            let first = (e.range.start.max(0) >> ANNOTATED_BIN_SHIFT) as usize;
+            let last = ((e.range.end - 1).max(0) >> ANNOTATED_BIN_SHIFT) as usize;
+            if bins.len() <= last / 64 {
+                bins.resize(last / 64 + 1, 0);
+            }
+            for b in first..=last {
+                bins[b / 64] |= 1 << (b % 64);
+            }
+        }
+        bins
+    }).collect()
+}
+
+/// Whether a region overlaps a set bin of a bitmap of `region_bins`, always true without bitmap
+fn in_bins(bins: Option<&Vec<u64>>, region: Range<i64>) -> bool {
+    let bins = match bins {
+        Some(bins) if region.end > region.start => bins,
+        _ => return true,
+    };
+    let first = (region.start.max(0) >> ANNOTATED_BIN_SHIFT) as usize;
+    let last = ((region.end - 1).max(0) >> ANNOTATED_BIN_SHIFT) as usize;
+    (first..=last).any(|b| matches!(bins.get(b / 64), Some(w) if w & (1 << (b % 64)) != 0))
+}
+
+/// The length of the longest intron (N) in the cigar of a read, 0 for an unspliced read
+pub(crate) fn longest_intron(r: &bam::Record) -> i64 {
+    r.cigar().iter()
+        .filter_map(|c| match *c {
+            Cigar::RefSkip(n) => Some(i64::from(n)),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// The decision of a `ReadFilter`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FilterDecision {
+    Accept,
+    Reject,
+}
+
+/// A custom read filter, e.g. on tags or barcodes, applied to the reads that pass the filters of
+/// the configuration. Rejected reads are counted as 'filtered'. When one mate of a pair is
+/// rejected the other is counted as 'orphan', like a mate below the mapping quality. Closures
+/// taking a record are filters as well.
+pub trait ReadFilter {
+    fn accept(&self, record: &bam::Record) -> FilterDecision;
+}
+
+impl<F: Fn(&bam::Record) -> FilterDecision> ReadFilter for F {
+    fn accept(&self, record: &bam::Record) -> FilterDecision {
+        self(record)
+    }
+}
+
+/// Quantify the alignments of an opened reader, like `quantify_bam_with`. The caller configures
+/// the reader, e.g. its threads and the cram reference.
+pub fn quantify_reader_with<F>(bam: bam::Reader, config: &Args, genemap: &GeneMap, on_assigned: F) -> Result<ReadMappings>
+    where F: FnMut(&bam::Record, usize)
+{
+    quantify_reader_filtered(bam, config, genemap, &[], on_assigned)
+}
+
+/// Quantify the alignments of an opened reader like `quantify_reader_with`, and only count the
+/// reads accepted by all `filters`, in order. The summary gets a 'filtered' line when there are
+/// filters.
+pub fn quantify_reader_filtered<F>(bam: bam::Reader, config: &Args, genemap: &GeneMap, filters: &[&dyn ReadFilter], on_assigned: F) -> Result<ReadMappings>
+    where F: FnMut(&bam::Record, usize)
+{
+    Ok(quantify_lane_reads(bam, config, genemap, filters, None, on_assigned)?.remove(0))
+}
+
+/// Quantify the reads of each lane, see `LaneIndex`, like `quantify_reader_filtered`
+fn quantify_lane_reads<F>(bam: bam::Reader, config: &Args, genemap: &GeneMap, filters: &[&dyn ReadFilter], lanes: Option<&LaneIndex>, mut on_assigned: F) -> Result<Vec<ReadMappings>>
+    where F: FnMut(&bam::Record, usize)
+{
+    // every read passed to the callback is counted, both mates of a pair
+    let n = lanes.map_or(1, |l| l.lanes + 1);
+    let mut assigned_reads = vec![0; n];
+    let mut reads = vec![if config.read_counts { vec![0; genemap.genes.len()] } else { Vec::new() }; n];
+    let mut gc_observed = vec![if config.gc_bias.is_some() { vec![0; GC_WINDOW + 1] } else { Vec::new() }; n];
+    let mut counts = quantify_alignments(bam, config, genemap, filters, lanes, |r: &bam::Record, gene| {
+        let lane = lane_of(lanes, r);
+        assigned_reads[lane] += 1;
+        if let Some(n) = reads[lane].get_mut(gene) {
+            *n += 1;
+        }
+        if !gc_observed[lane].is_empty() {
+            if let Some(gc) = read_gc(r) {
+                gc_observed[lane][gc] += 1;
+            }
+        }
+        on_assigned(r, gene);
+    })?;
+    for (((counts, reads), gc_observed), assigned_reads) in counts.iter_mut().zip(reads).zip(gc_observed).zip(assigned_reads) {
+        counts.reads = reads;
+        counts.gc_observed = gc_observed;
+        counts.assigned_reads = assigned_reads;
+    }
+    Ok(counts)
+}
+
+fn quantify_alignments<F>(mut bam: bam::Reader, config: &Args, genemap: &GeneMap, filters: &[&dyn ReadFilter], lanes: Option<&LaneIndex>, mut on_assigned: F) -> Result<Vec<ReadMappings>>
+    where F: FnMut(&bam::Record, usize)
+{
+    if config.tx2gene.is_some() {
+        return quantify_transcriptome(bam, config, genemap, filters, lanes, on_assigned);
+    }
+
+    //intersect header chr list with rr
+    // one hash lookup per contig, draft assemblies may have hundreds of thousands of scaffolds
+    let header = bam.header().clone();
+    let target_names = header.target_names();
+    let tid_map = genemap.contig_map(&target_names, config.exact_chr_names);
+    let matched = tid_map.iter().filter(|t| t.is_some()).count();
+    eprintln!("{} of {} BAM contigs found in GTF", matched, tid_map.len());
+    let aliased: Vec<_> = target_names.iter().zip(&tid_map)
+        .filter_map(|(name, chr)| chr.map(|c| (name, &genemap.seq_names[c])).filter(|(name, chr)| **name != chr.as_slice()))
+        .collect();
+    if !aliased.is_empty() {
+        let examples: Vec<_> = aliased.iter().take(3)
+            .map(|(name, chr)| format!("{} as {}", String::from_utf8_lossy(name), String::from_utf8_lossy(chr)))
+            .collect();
+        eprintln!("{} BAM contigs matched to the GTF by their UCSC/Ensembl name, e.g. {}", aliased.len(), examples.join(", "));
+    }
+    if matched == 0 {
+        eprintln!("Warning: none of the BAM contigs are in the GTF, check the chromosome naming");
+    }
+    let contaminant_contigs: HashSet<&[u8]> = config.contaminant_contigs.iter().map(|c| c.as_bytes()).collect();
+    let contaminant: Vec<_> = target_names.iter()
+        .map(|name| contaminant_contigs.contains(name))
+        .collect();
+
+    //quantify
+    let mut notingtf = vec![0; tid_map.len()];
+    let mut mates = MateBuffer::default();
+    let mut spill = match config.max_memory {
+        Some(mb) => Some(MateSpill::new(&header, mb * 1024 * 1024)?),
+        None => None,
+    };
+    let mut lane_counts = empty_lane_counts(lanes, genemap, config, filters);
+    let fusion_report = config.fusion_report.is_some();
+    let mut fusion_mates = FusionMates { coordinate_sorted: sort_order(&header) == Some(b"coordinate"), ..Default::default() };
+
+    // reuse the record buffer, a new one is only allocated when a read is kept for pairing
+    let mut record = bam::Record::new();
+    while let Some(r) = bam.read(&mut record) {
+        r?;
+            let counts = &mut lane_counts[lane_of(lanes, &record)];
+            if !counts.filter_read(&mut record, config) {
+                continue;
+            }
+            if filters.iter().any(|f| f.accept(&record) == FilterDecision::Reject) {
+                *counts.filtered.get_or_insert(0) += 1;
+                continue;
+            }
+
+            if contaminant[record.tid() as usize] {
+                counts.contaminant += 1;
+            } else if let Some(ref_chr_id) = tid_map[record.tid() as usize] {
+                if record.is_paired() {
+                    if record.is_mate_unmapped() {
+                        // singletons are not buffered, their mate never arrives
+                        if !config.nosingletons {
+                            if let Some(gene) = counts.count_read(&record, ref_chr_id, genemap, config) {
+                                on_assigned(&record, gene);
+                            }
+                        }
+                    } else {
+                        //is the mate on the same chromosome? if not than this read pair is ambiguous
+                        if record.tid() != record.mtid() {
+                            counts.ambiguous_pair += 1;
+                            // a mate on a contaminant contig or one not in the GTF never completes the pair
+                            let mtid = record.mtid() as usize;
+                            if fusion_report && !contaminant[mtid] && tid_map[mtid].is_some() {
+                                let m = map_segments(&record, &genemap.intervals[ref_chr_id], config);
+                                if let Some(mate) = fusion_mates.pair(&record, m.gene()) {
+                                    counts.count_fusion(record.qname(), &m, &SegmentHit::Hit(mate), genemap, config);
+                                }
+                            }
+                        } else if let Some(mate) = mates.take_mate(&record) {
+                            if let Some(gene) = counts.count_pair(&record, &mate, ref_chr_id, genemap, config) {
+                                on_assigned(&record, gene);
+                                on_assigned(&mate, gene);
+                            }
+                        } else {
+                            mates.push(std::mem::replace(&mut record, bam::Record::new()));
+                            if let Some(spill) = spill.as_mut() {
+                                spill.check(&mut mates, fusion_mates.bytes)?;
+                            }
+                        }
+                    }
+                } else {
+                    //Single-end read
+                    if let Some(gene) = counts.count_read(&record, ref_chr_id, genemap, config) {
+                        on_assigned(&record, gene);
+                    }
+                }
+            } else {
+                // this chr was not in the gtf
+                counts.notingtf += 1;
+                notingtf[record.tid() as usize] += 1;
+            }
+    }
+
+    // pair the reads that were spilled to disk, one bucket at a time, each counted as a shard.
+    // Without an earlier spill the remaining reads stay in memory, the spill files copy the BAM
+    // header which is costly to write and parse for assemblies with many contigs.
+    if let Some(mut spill) = spill.filter(|s| !s.writers.is_empty()) {
+        spill.spill(&mut mates)?;
+        for mut bucket in spill.finish()? {
+            let mut shards = empty_lane_counts(lanes, genemap, config, &[]);
+            let mut mates = MateBuffer::default();
+            for record in bucket.records() {
+                let record = record?;
+                if let Some(mate) = mates.take_mate(&record) {
+                    if let Some(gene) = shards[lane_of(lanes, &record)].count_pair(&record, &mate, tid_map[record.tid() as usize].unwrap(), genemap, config) {
+                        on_assigned(&record, gene);
+                        on_assigned(&mate, gene);
+                    }
+                } else {
+                    mates.push(record);
+                }
+            }
+            for record in mates.drain() {
+                if let Some(gene) = shards[lane_of(lanes, &record)].count_orphan(&record, tid_map[record.tid() as usize].unwrap(), genemap, config) {
+                    on_assigned(&record, gene);
+                }
+            }
+            for (counts, shard) in lane_counts.iter_mut().zip(shards) {
+                counts.merge(shard);
+            }
+        }
+    }
+
+    // reads still waiting for their mate, the mate was filtered or never a primary alignment
+    for record in mates.drain() {
+        if let Some(gene) = lane_counts[lane_of(lanes, &record)].count_orphan(&record, tid_map[record.tid() as usize].unwrap(), genemap, config) {
+            on_assigned(&record, gene);
+        }
+    }
+
+    let total_notingtf: Count = lane_counts.iter().map(|c| c.notingtf).sum();
+    if total_notingtf > 0 && config.notingtf_top > 0 {
+        let mut contigs: Vec<_> = target_names.into_iter().zip(notingtf).filter(|c| c.1 > 0).collect();
+        contigs.sort_by_key(|c| std::cmp::Reverse(c.1));
+        eprintln!("{} reads on {} contigs not in GTF, top {}:", total_notingtf, contigs.len(), config.notingtf_top);
+        for (name, n) in contigs.iter().take(config.notingtf_top) {
+            eprintln!("  {}\t{}", String::from_utf8_lossy(name), n);
+        }
+    }
+
+    Ok(lane_counts)
+}
+
+/// Quantify alignments to the transcriptome, every bam target is a transcript of the '--tx2gene'
+/// table. The exon intervals are not searched, the alignments of a read are collected until the
+/// next read name and the read is counted once, also when paired. The alignments of a read must
+/// be adjacent, as written by the aligner or sorted by name, coordinate sorted input is rejected.
+fn quantify_transcriptome<F>(mut bam: bam::Reader, config: &Args, genemap: &GeneMap, filters: &[&dyn ReadFilter], lanes: Option<&LaneIndex>, mut on_assigned: F) -> Result<Vec<ReadMappings>>
+    where F: FnMut(&bam::Record, usize)
+{
+    match sort_order(bam.header()) {
+        Some(b"coordinate") => return Err(anyhow!("--tx2gene requires the alignments of a read to be adjacent, the bam file is coordinate sorted, sort it by name")),
+        Some(b"unknown") | None => eprintln!("Warning: the sort order of the bam file is unknown, --tx2gene expects the alignments of a read to be adjacent"),
+        _ => {},
+    }
+    let tid_gene: Vec<_> = bam.header().target_names().iter()
+        .map(|name| genemap.transcript_genes.get(*name).copied()).collect();
+    let matched = tid_gene.iter().filter(|t| t.is_some()).count();
+    eprintln!("{} of {} BAM transcripts found in --tx2gene", matched, tid_gene.len());
+    if matched == 0 {
+        eprintln!("Warning: none of the BAM contigs are transcripts of --tx2gene, was the BAM aligned to the transcriptome?");
+    }
+
+    let mut lane_counts = empty_lane_counts(lanes, genemap, config, filters);
+    let mut read = TranscriptAlignments::default();
+    let mut record = bam::Record::new();
+    while let Some(r) = bam.read(&mut record) {
+        r?;
+        if record.qname() != read.qname.as_slice() {
+            read.count(&mut lane_counts[read.lane], genemap, config, &mut on_assigned);
+            read.qname.clear();
+            read.qname.extend_from_slice(record.qname());
+            read.lane = lane_of(lanes, &record);
+        }
+        // secondary alignments are not counted, but their transcripts must be of the same gene
+        read.add(&record, &tid_gene, config);
+        let counts = &mut lane_counts[lane_of(lanes, &record)];
+        if !counts.filter_read(&mut record, config) {
+            continue;
+        }
+        if filters.iter().any(|f| f.accept(&record) == FilterDecision::Reject) {
+            *counts.filtered.get_or_insert(0) += 1;
+            continue;
+        }
+        read.primary.push(std::mem::replace(&mut record, bam::Record::new()));
+    }
+    read.count(&mut lane_counts[read.lane], genemap, config, &mut on_assigned);
+
+    Ok(lane_counts)
+}
+
+/// The alignments of one read to the transcriptome
+#[derive(Default)]
+struct TranscriptAlignments {
+    qname: Vec<u8>,
+    /// the lane of the read, see `LaneIndex`
+    lane: usize,
+    /// the primary alignments that passed the filters, of one or both mates
+    primary: Vec<bam::Record>,
+    /// the genes of the alignments on the expected strand
+    genes: Vec<usize>,
+    /// an alignment is on a transcript that is not in the tx2gene table
+    unknown: bool,
+    /// an alignment is on a transcript in the table, but on the wrong strand
+    wrong_strand: bool,
+}
+
+impl TranscriptAlignments {
+    fn add(&mut self, r: &bam::Record, tid_gene: &[Option<usize>], config: &Args) {
+        if r.is_unmapped() || r.is_supplementary() {
+            return;
+        }
+        match tid_gene[r.tid() as usize] {
+            None => self.unknown = true,
+            Some(gene) if config.strandness.matches(Strandness::fragment_forward(r), Strand::Forward) => {
+                if !self.genes.contains(&gene) {
+                    self.genes.push(gene);
+                }
+            },
+            Some(_) => self.wrong_strand = true,
+        }
+    }
+
+    /// Count the read when a primary alignment passed the filters, and clear the alignments.
+    /// Alignments on transcripts of several genes, or on unknown transcripts, make it ambiguous.
+    fn count<F>(&mut self, counts: &mut ReadMappings, genemap: &GeneMap, config: &Args, on_assigned: &mut F)
+        where F: FnMut(&bam::Record, usize)
+    {
+        if !self.primary.is_empty() {
+            if self.genes.is_empty() && self.unknown && !self.wrong_strand {
+                counts.notingtf += 1;
+            } else {
+                let hit = match (self.genes.as_slice(), self.unknown) {
+                    (&[gene], false) => SegmentHit::Hit(gene),
+                    ([], _) => SegmentHit::WrongStrand,
+                    _ => SegmentHit::Ambiguous,
+                };
+                let gene = hit.gene();
+                counts.count_strata(&self.primary[0], self.primary.get(1), gene, genemap, config);
+                counts.count_hit(hit);
+                if let Some(gene) = gene {
+                    for r in &self.primary {
+                        on_assigned(r, gene);
+                    }
+                }
+            }
+        }
+        self.primary.clear();
+        self.genes.clear();
+        self.unknown = false;
+        self.wrong_strand = false;
+    }
+}
+
+/// The read assigned to a gene, by chromosome, aligned span, read name and gene
+type Assignment = (usize, Range<i64>, Vec<u8>, usize);
+
+/// The reads counted on each gene, kept until they are written sorted by position. With
+/// '--max-memory' the reads are written to sorted temporary files when they exceed the limit, and
+/// the files are merged when the reads are written.
+#[derive(Default)]
+pub struct ReadAssignments {
+    reads: Vec<Assignment>,
+    /// estimated memory use of the reads
+    bytes: usize,
+    max_bytes: Option<usize>,
+    runs: Vec<PathBuf>,
+}
+
+impl ReadAssignments {
+    /// approximate memory used by a read next to its name
+    const READ_OVERHEAD: usize = 64;
+
+    /// Reads in memory are limited by '--max-memory'
+    pub fn with_config(config: &Args) -> ReadAssignments {
+        ReadAssignments { max_bytes: config.max_memory.map(|mb| mb * 1024 * 1024), ..Default::default() }
+    }
+
+    /// Add a read counted on a gene, e.g. from the callback of `quantify_bam_with`
+    pub fn push(&mut self, r: &bam::Record, gene: usize, genes: &GeneMap) -> Result<()> {
+        let cigar = r.cigar();
+        self.bytes += r.qname().len() + Self::READ_OVERHEAD;
+        self.reads.push((genes.gene_info[gene].chr, cigar.pos()..cigar.end_pos(), r.qname().to_vec(), gene));
+        if self.max_bytes.is_some_and(|max| self.bytes > max) {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn sort(&mut self) {
+        self.reads.sort_unstable_by(|a, b| (a.0, a.1.start, a.1.end, &a.2, a.3).cmp(&(b.0, b.1.start, b.1.end, &b.2, b.3)));
+    }
+
+    /// Write the reads in memory sorted to a temporary file
+    fn spill(&mut self) -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("gensum-{}-reads", std::process::id()));
+        if self.runs.is_empty() {
+            std::fs::create_dir_all(&dir)?;
+            eprintln!("Read assignments exceed memory limit, spilling sorted reads to {}", dir.display());
+        }
+        self.sort();
+        let p = dir.join(format!("{}.txt", self.runs.len()));
+        let mut w = BufWriter::new(File::create(&p)?);
+        for (chr, range, name, gene) in self.reads.drain(..) {
+            write!(w, "{}\t{}\t{}\t{}\t", chr, range.start, range.end, gene)?;
+            w.write_all(&name)?;
+            w.write_all(b"\n")?;
+        }
+        w.flush()?;
+        self.runs.push(p);
+        self.bytes = 0;
+        Ok(())
+    }
+
+    /// The reads of the temporary files and in memory, each sorted, merged by position
+    fn merged(mut self) -> Result<impl Iterator<Item = Result<Assignment>>> {
+        self.sort();
+        let mut sources: Vec<Box<dyn Iterator<Item = Result<Assignment>>>> = Vec::new();
+        for p in &self.runs {
+            let lines = BufReader::new(File::open(p)?).split(b'\n');
+            // the file is removed once opened, the reads stay readable until the reader is closed
+            std::fs::remove_file(p)?;
+            sources.push(Box::new(lines.map(|l| {
+                let l = l?;
+                let mut f = l.splitn(5, |&c| c == b'\t');
+                let mut num = || f.next().and_then(atoi::atoi::<i64>).ok_or_else(|| anyhow!("Corrupt read assignment spill file"));
+                let (chr, start, end, gene) = (num()? as usize, num()?, num()?, num()? as usize);
+                let name = f.next().ok_or_else(|| anyhow!("Corrupt read assignment spill file"))?.to_vec();
+                Ok((chr, start..end, name, gene))
+            })));
+        }
+        if let Some(dir) = self.runs.first().and_then(|p| p.parent()) {
+            std::fs::remove_dir(dir)?;
+        }
+        sources.push(Box::new(self.reads.into_iter().map(Ok)));
+
+        let mut heads = sources.iter_mut().map(|s| s.next().transpose()).collect::<Result<Vec<_>>>()?;
+        Ok(std::iter::from_fn(move || {
+            let i = heads.iter().enumerate()
+                .filter_map(|(i, h)| h.as_ref().map(|a| (i, a)))
+                .min_by(|(_, a), (_, b)| (a.0, a.1.start, a.1.end, &a.2, a.3).cmp(&(b.0, b.1.start, b.1.end, &b.2, b.3)))?
+                .0;
+            match sources[i].next().transpose() {
+                Ok(next) => std::mem::replace(&mut heads[i], next).map(Ok),
+                Err(e) => Some(Err(e)),
+            }
+        }))
+    }
+
+    /// Write the reads as a table with the chromosome, 0-based start and end, read name and
+    /// gene_id, sorted by position. A file name ending in .gz is bgzip compressed and indexed
+    /// with tabix, so the reads on a locus can be queried.
+    pub fn write<P: AsRef<Path>>(self, p: P, genes: &GeneMap) -> Result<()> {
+        let p = p.as_ref();
+        let compress = p.extension() == Some("gz".as_ref());
+        let o: Box<dyn Write> = if compress {
+            Box::new(rust_htslib::bgzf::Writer::from_path(p)?)
+        } else {
+            Box::new(File::create(p)?)
+        };
+
+        let mut w = BufWriter::new(o);
+        writeln!(w, "#chrom\tstart\tend\tread\tgene_id")?;
+        for read in self.merged()? {
+            let (chr, range, name, gene) = read?;
+            w.write_all(&genes.seq_names[chr])?;
+            write!(w, "\t{}\t{}\t", range.start, range.end)?;
+            w.write_all(&name)?;
+            w.write_all(b"\t")?;
+            w.write_all(&genes.genes[gene])?;
+            w.write_all(b"\n")?;
+        }
+        // the bgzf writer adds the end of file block when it is dropped
+        drop(w);
+
+        if compress {
+            let c_path = std::ffi::CString::new(p.to_string_lossy().as_bytes())?;
+            // SAFETY: the path is a valid C string and the BED configuration is a static of htslib
+            let res = unsafe { rust_htslib::htslib::tbx_index_build(c_path.as_ptr(), 0, &rust_htslib::htslib::tbx_conf_bed) };
+            if res < 0 {
+                return Err(anyhow!("Cannot create the tabix index of {}", p.display()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The 64 bit FNV-1a hash of `bytes`. Unlike the `DefaultHasher` of the standard library, the
+/// hash is fixed, so the '--verify-sample' genes are the same with every Rust release.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Count a pseudo-random sample of `n` genes again from the indexed bam file, fetching only the
+/// reads on each gene, and compare with the counts of the streaming pass. The sample is the same
+/// for every run. Returns an error listing the genes with different counts.
+pub fn verify_sample<P: AsRef<Path>>(bam_file: P, config: &Args, genemap: &GeneMap, counts: &ReadMappings, n: usize) -> Result<()> {
+    let bam = match bam_url(bam_file.as_ref())? {
+        Some(url) => bam::IndexedReader::from_url(&url),
+        None => bam::IndexedReader::from_path(&bam_file),
+    };
+    let mut bam = bam
+        .map_err(|e| anyhow!("--verify-sample requires an indexed bam file: {}", e))?;
+    if let Some(fasta) = config.reference.as_ref() {
+        bam.set_reference(fasta)?;
+    }
+
+    // order the genes by a hash of their id, the sample does not depend on the GTF order
+    let mut sample: Vec<(u64, usize)> = genemap.gene_info.iter().enumerate()
+        .filter(|(_, g)| g.span.start < g.span.end)
+        .map(|(i, _)| (fnv1a(&genemap.genes[i]), i))
+        .collect();
+    sample.sort_unstable();
+    sample.truncate(n);
+
+    let header = bam.header().clone();
+    let target_names = header.target_names();
+    let tid_map = genemap.contig_map(&target_names, config.exact_chr_names);
+    let mut mismatches = Vec::new();
+    for (_, geneidx) in sample {
+        let gene = &genemap.gene_info[geneidx];
+        let recount = match tid_map.iter().position(|&c| c == Some(gene.chr)) {
+            Some(tid) if !config.contaminant_contigs.iter().any(|c| c.as_bytes() == target_names[tid]) => {
+                let mut region = gene.span.clone();
+                if let Some(w) = config.tss_window {
+                    let tss = gene.tss_window(w);
+                    region = region.start.min(tss.start)..region.end.max(tss.end);
+                }
+                recount_region(&mut bam, tid as u32, region, gene.chr, config, genemap)?.hit[geneidx]
+            },
+            _ => 0,
+        };
+        if recount != counts.hit[geneidx] {
+            let name = String::from_utf8_lossy(&genemap.genes[geneidx]).into_owned();
+            eprintln!("verify: gene {} counted {} streaming, {} indexed", name, counts.hit[geneidx], recount);
+            mismatches.push(name);
+        }
+    }
 
-        //Create the NClists
-        let mut numexons = 0;
-        let mut numexonsdd = 0;
-        let intervals = exons.into_iter()
-            .map(|mut v| {
-                numexons += v.len();
-                v.sort();
-                v.dedup(); //deduplication saves around 50% because of comparable isoforms (and havanna entries)
-            numexonsdd += v.len();
-            NClist::from_vec(v) })
-            .collect::<Result<_, _>>()
-            .map_err(|_| anyhow!("Cannot create interval search list, all ranges must be > 1"))?;
+    if !mismatches.is_empty() {
+        return Err(anyhow!("verify: {} of {} sampled genes have different counts: {}", mismatches.len(), n.min(genemap.genes.len()), mismatches.join(",")));
+    }
+    eprintln!("verify: counts of {} sampled genes are identical", n.min(genemap.genes.len()));
+    Ok(())
+}
 
-        eprintln!("{} lines in GTF, parsed {} exons, {} unique geneid-exon ranges ({:?})", n, numexons, numexonsdd, gtftime);
+/// Count the reads overlapping a region of the indexed bam file, like `quantify_bam_with`. The
+/// mates outside the region are fetched by their position.
+fn recount_region(bam: &mut bam::IndexedReader, tid: u32, region: Range<i64>, chr: usize, config: &Args, genemap: &GeneMap) -> Result<ReadMappings> {
+    let mut counts = ReadMappings::with_config(genemap.genes.len(), config);
+    let mut mates = MateBuffer::default();
 
-        Ok(GeneMap { genes, seq_names, intervals })
+    bam.fetch((tid, region.start, region.end))?;
+    let mut record = bam::Record::new();
+    while let Some(r) = bam.read(&mut record) {
+        r?;
+        if !counts.filter_read(&mut record, config) {
+            continue;
+        }
+        if !record.is_paired() {
+            counts.count_read(&record, chr, genemap, config);
+        } else if record.is_mate_unmapped() {
+            if !config.nosingletons {
+                counts.count_read(&record, chr, genemap, config);
+            }
+        } else if record.tid() != record.mtid() {
+            counts.ambiguous_pair += 1;
+        } else if let Some(mate) = mates.take_mate(&record) {
+            counts.count_pair(&record, &mate, chr, genemap, config);
+        } else {
+            mates.push(std::mem::replace(&mut record, bam::Record::new()));
+        }
     }
 
-    #[inline]
-    pub fn hit_name(&self, i: usize) -> Option<&Vec<u8>> {
-        self.genes.get_index(i)
+    let outside: Vec<_> = mates.drain().collect();
+    for r in outside {
+        match fetch_mate(bam, &r, config)? {
+            Some(mate) => counts.count_pair(&r, &mate, chr, genemap, config),
+            None => counts.count_orphan(&r, chr, genemap, config),
+        };
     }
-
+    Ok(counts)
 }
 
-#[derive(Eq, PartialEq)]
-enum SegmentHit {
-    Hit(usize),
-    Nohit,
-    Ambiguous
+/// Find the primary alignment of the mate of a read that passes the read filters
+fn fetch_mate(bam: &mut bam::IndexedReader, r: &bam::Record, config: &Args) -> Result<Option<bam::Record>> {
+    bam.fetch((r.mtid() as u32, r.mpos(), r.mpos() + 1))?;
+    let mut filtered = ReadMappings::new(0);
+    let mut mate = bam::Record::new();
+    while let Some(res) = bam.read(&mut mate) {
+        res?;
+        if mate.qname() == r.qname() && mate.pos() == r.mpos() && mate.is_first_in_template() != r.is_first_in_template()
+            && !mate.is_secondary() && !mate.is_supplementary() {
+            return Ok(if filtered.filter_read(&mut mate, config) { Some(mate) } else { None });
+        }
+    }
+    Ok(None)
 }
 
+/// Paired reads waiting for their mate
 #[derive(Default)]
-pub struct ReadMappings {
-    qc_failed: usize,
-    unmapped: usize,
-    secondary: usize,
-    duplicated: usize,
-    ambiguous: usize,
-    ambiguous_pair: usize,
-    notingtf: usize,
-    mapq: usize,
-    nohit: usize,
-    hit: Vec<usize>
+struct MateBuffer {
+    /// the last unpaired read, in name sorted or collated files this is the mate of the next read
+    pending: Option<bam::Record>,
+    delayed: HashMap<Vec<u8>, bam::Record>,
+    /// estimated memory use of the delayed reads
+    bytes: usize,
 }
 
-impl ReadMappings {
-    pub fn new(n: usize) -> ReadMappings {
-        ReadMappings { hit: vec![0; n], ..Default::default() }
+impl MateBuffer {
+    /// approximate memory used by a buffered record next to its data
+    const RECORD_OVERHEAD: usize = 128;
+
+    /// Find the mate of a paired read. The pending read is checked first, so mates that are
+    /// adjacent in the file never need to be stored in the delayed map.
+    fn take_mate(&mut self, r: &bam::Record) -> Option<bam::Record> {
+        match self.pending.take() {
+            Some(p) if p.qname() == r.qname() => Some(p),
+            p => {
+                if let Some(p) = p {
+                    self.delay(p);
+                }
+                let mate = self.delayed.remove(r.qname());
+                if let Some(m) = mate.as_ref() {
+                    self.bytes -= Self::record_size(m);
+                }
+                mate
+            }
+        }
     }
 
-    fn count_hit(&mut self, h: SegmentHit) {
-        match h {
-            SegmentHit::Nohit => self.nohit += 1,
-            SegmentHit::Ambiguous => self.ambiguous += 1,
-            SegmentHit::Hit(id) => self.hit[id] += 1,
+    fn push(&mut self, r: bam::Record) {
+        if let Some(p) = self.pending.replace(r) {
+            self.delay(p);
         }
     }
 
-    pub fn write<W: Write>(&self, o: W, genes: &GeneMap) -> Result<()> {
+    fn delay(&mut self, r: bam::Record) {
+        self.bytes += Self::record_size(&r);
+        self.delayed.insert(r.qname().to_vec(), r);
+    }
 
-        let mut w = BufWriter::new(o);
-        let mut ibuf = itoa::Buffer::new();
-        for (geneidx, &count) in self.hit.iter().enumerate() {
-            w.write_all(genes.hit_name(geneidx).unwrap())?;
-            w.write_all(&[b'\t'])?;
-            w.write_all(ibuf.format(count).as_bytes())?;
-            w.write_all(&[b'\n'])?;
-        }
+    fn record_size(r: &bam::Record) -> usize {
+        r.inner().l_data as usize + r.qname().len() + Self::RECORD_OVERHEAD
+    }
+
+    /// Remove all buffered reads
+    fn drain(&mut self) -> impl Iterator<Item = bam::Record> + '_ {
+        self.bytes = 0;
+        self.pending.take().into_iter().chain(self.delayed.drain().map(|(_, r)| r))
+    }
+}
 
-        writeln!(w, "qc_failed\t{}", self.qc_failed)?;
-        writeln!(w, "unmapped\t{}", self.unmapped)?;
-        writeln!(w, "low_mapq\t{}", self.mapq)?;
-        writeln!(w, "secondary_alignments\t{}", self.secondary)?;
-        writeln!(w, "marked_duplicated\t{}", self.duplicated)?;
-        writeln!(w, "ambiguous\t{}", self.ambiguous)?;
-        writeln!(w, "ambiguous_pair\t{}", self.ambiguous_pair)?;
-        writeln!(w, "chr_not_in_gtf\t{}", self.notingtf)?;
-        writeln!(w, "nohit\t{}", self.nohit)?;
+/// Mates on another chromosome waiting for their mate for the fusion report. Only mates on a gene
+/// are kept, by their read name, and a pair is removed once the second mate is read whether or not
+/// it is on a gene. Of coordinate sorted input the mate read first is known, so a second mate is
+/// never kept after a first mate without gene. A mate that is rejected by the read filters leaves
+/// its pair, the memory is included in '--max-memory'.
+#[derive(Default)]
+struct FusionMates {
+    delayed: HashMap<Vec<u8>, usize>,
+    /// estimated memory use of the delayed mates
+    bytes: usize,
+    coordinate_sorted: bool,
+}
 
-        Ok(())
+impl FusionMates {
+    /// approximate memory used by an entry next to the read name
+    const ENTRY_OVERHEAD: usize = 48;
+
+    /// The gene of the mate read earlier, when this read completes a pair. The read is kept when
+    /// it is on a gene and its mate is still to come.
+    fn pair(&mut self, r: &bam::Record, gene: Option<usize>) -> Option<usize> {
+        if let Some(mate) = self.delayed.remove(r.qname()) {
+            self.bytes -= r.qname().len() + Self::ENTRY_OVERHEAD;
+            return gene.map(|_| mate);
+        }
+        let first = (r.tid(), r.pos()) < (r.mtid(), r.mpos());
+        if let Some(gene) = gene.filter(|_| first || !self.coordinate_sorted) {
+            self.bytes += r.qname().len() + Self::ENTRY_OVERHEAD;
+            self.delayed.insert(r.qname().to_vec(), gene);
+        }
+        None
     }
 }
 
-pub fn quantify_bam<P: AsRef<Path>>(bam_file: P, config: &Args, genemap: &GeneMap) -> Result<ReadMappings> {
-    //open bam
-    let mut bam = bam::Reader::from_path(bam_file)?;
-    // test from command line show improve until 4 cpu's
-    bam.set_threads(4)?;
+/// Spills unpaired reads to temporary bam files when the mate buffer exceeds the memory limit.
+/// Reads are distributed over the files by a hash of their name, so both mates always end up in
+/// the same file and each file can be paired separately.
+struct MateSpill {
+    dir: PathBuf,
+    header: bam::Header,
+    writers: Vec<bam::Writer>,
+    max_bytes: usize,
+}
 
-    //intersect header chr list with rr
-    let header = bam.header();
-    let tid_map: Vec<_> = header.target_names().iter()
-        .map(|name| genemap.seq_names.iter().position(|n| name == &n.as_slice())).collect();
+impl MateSpill {
+    const BUCKETS: usize = 16;
 
-    //quantify
-    let mut delayed = HashMap::new();
-    let mut counts = ReadMappings::new(genemap.genes.len());
+    fn new(header: &bam::HeaderView, max_bytes: usize) -> Result<MateSpill> {
+        let dir = std::env::temp_dir().join(format!("gensum-{}", std::process::id()));
+        Ok(MateSpill { dir, header: bam::Header::from_template(header), writers: Vec::new(), max_bytes })
+    }
 
-    for record in bam.records() {
-        let record = record?;
-            if record.is_unmapped() {
-                counts.unmapped += 1;
-                continue;
-            }
+    /// Spill the buffered reads if they use more than the allowed memory, next to the memory
+    /// `reserved` by the other buffers
+    fn check(&mut self, mates: &mut MateBuffer, reserved: usize) -> Result<()> {
+        if mates.bytes + reserved > self.max_bytes {
+            self.spill(mates)?;
+        }
+        Ok(())
+    }
 
-            if record.is_quality_check_failed() {
-                counts.qc_failed += 1;
-            }
-            if record.is_secondary() || record.is_supplementary() {
-                counts.secondary += 1;
-                continue;
+    fn spill(&mut self, mates: &mut MateBuffer) -> Result<()> {
+        if self.writers.is_empty() {
+            std::fs::create_dir_all(&self.dir)?;
+            eprintln!("Mate buffer exceeds memory limit, spilling unpaired reads to {}", self.dir.display());
+            for i in 0..Self::BUCKETS {
+                let p = self.dir.join(format!("{}.bam", i));
+                let mut w = bam::Writer::from_path(p, &self.header, bam::Format::Bam)?;
+                w.set_compression_level(bam::CompressionLevel::Fastest)?;
+                self.writers.push(w);
             }
+        }
 
-            if !config.usedups && record.is_duplicate() {
-                counts.duplicated += 1;
-                continue;
-            }
+        for r in mates.drain() {
+            self.writers[fnv1a(r.qname()) as usize % Self::BUCKETS].write(&r)?;
+        }
+        Ok(())
+    }
 
-            if record.mapq() < config.mapq {
-                counts.mapq += 1;
-                continue;
-            }
+    /// Close the spill files and return readers for the buckets. The files are removed once
+    /// the readers are opened.
+    fn finish(self) -> Result<Vec<bam::Reader>> {
+        if self.writers.is_empty() {
+            return Ok(Vec::new());
+        }
+        drop(self.writers);
 
-            if let Some(ref_chr_id) = tid_map[record.tid() as usize] {
-                let ref_chr_map = &genemap.intervals[ref_chr_id];
-                if record.is_paired() {
-                    if record.is_mate_unmapped() && !config.nosingletons {
-                        counts.count_hit(map_segments(&record, ref_chr_map, config));
-                    } else {
-                        //is the mate on the same chromosome? if not than this read pair is ambiguous
-                        if record.tid() != record.mtid() {
-                            counts.ambiguous_pair += 1;
-                        } else if let Some(mate) = delayed.remove(record.qname()) {
-                            let m1 = map_segments(&record, ref_chr_map, config);
-                            let m2 = map_segments(&mate, ref_chr_map, config);
-                            if m1 == m2 {
-                                counts.count_hit(m1);
-                            } else {
-                                counts.ambiguous_pair += 1;
-                            }
-                        } else {
-                            delayed.insert(record.qname().to_vec(), record);
-                        }
-                    }
-                } else {
-                    //Single-end read
-                    counts.count_hit(map_segments(&record, ref_chr_map, config));
-                }
-            } else {
-                // this chr was not in the gtf
-                counts.notingtf += 1;
-            }
+        let mut buckets = Vec::new();
+        for i in 0..Self::BUCKETS {
+            let p = self.dir.join(format!("{}.bam", i));
+            buckets.push(bam::Reader::from_path(&p)?);
+            std::fs::remove_file(p)?;
+        }
+        std::fs::remove_dir(&self.dir)?;
+        Ok(buckets)
     }
-    Ok(counts)
 }
 
-fn map_segments(r: &bam::Record, map: &NClist<Exon>, config: &Args) -> SegmentHit {
-    //Store the first gene hit id
-    let mut  target_id = None;
-    let cigar = r.cigar();
-
-    let strict = config.method == QuantMethod::Strict;
-    let strandness = config.strandness;
+/// Give a read without a cigar an ungapped alignment of its full length, starting at its position
+fn set_ungapped_cigar(r: &mut bam::Record) {
+    let (qname, seq, qual) = (r.qname().to_vec(), r.seq().as_bytes(), r.qual().to_vec());
+    let cigar = CigarString(vec![Cigar::Match(seq.len() as u32)]);
+    r.set(&qname, Some(&cigar), &seq, &qual);
+}
 
-    // use cigar line to filter the cigar to ranges (o) that lie on the genome
-    for o in cigar.into_iter().scan(r.pos(), |pos, c| {
+/// Use the cigar line to filter the alignment to the ranges that lie on the genome
+pub(crate) fn aligned_segments(cigar: &CigarStringView) -> impl Iterator<Item = Range<i64>> + '_ {
+    cigar.iter().scan(cigar.pos(), |pos, c| {
         match c {
             Cigar::Del(n) | Cigar::RefSkip(n) => {
                 *pos += *n as i64;
@@ -324,6 +4274,8 @@ fn map_segments(r: &bam::Record, map: &NClist<Exon>, config: &Args) -> SegmentHi
             Cigar::Ins(_) | Cigar::SoftClip(_) | Cigar::HardClip(_) | Cigar::Pad(_) => {
                 Some(None)
             },
+            // zero length operations are valid in the BAM encoding, but do not cover any base
+            Cigar::Match(0) | Cigar::Equal(0) | Cigar::Diff(0) => Some(None),
             Cigar::Match(n) | Cigar::Equal(n) | Cigar::Diff(n) => {
                 let r = *pos..*pos + *n as i64;
                 *pos += *n as i64;
@@ -331,11 +4283,191 @@ fn map_segments(r: &bam::Record, map: &NClist<Exon>, config: &Args) -> SegmentHi
             }
         }
     }).flatten()
-    {
-        //match this segment's genomic region to exons and filter based on program configuration
-        let exons =  map.overlaps(&o)
+}
+
+/// The number of aligned bases with a base quality of at least `min_baseq` that lie within the
+/// exons of a gene. Reads without base qualities count all aligned bases.
+fn hq_overlap(r: &bam::Record, gene: usize, map: &NClist<Exon>, min_baseq: u8) -> u32 {
+    let qual = r.qual();
+    let (mut pos, mut qpos) = (r.pos(), 0);
+    let mut n = 0;
+    for c in r.cigar().iter() {
+        match *c {
+            Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) => {
+                let segment = pos..pos + l as i64;
+                let exons: Vec<_> = map.overlaps(&segment).filter(|e| e.gene() == gene).collect();
+                for i in 0..l as usize {
+                    let p = pos + i as i64;
+                    if !matches!(qual.get(qpos + i), Some(&q) if q < min_baseq)
+                        && exons.iter().any(|e| e.range.contains(&p)) {
+                        n += 1;
+                    }
+                }
+                pos += l as i64;
+                qpos += l as usize;
+            },
+            Cigar::Ins(l) | Cigar::SoftClip(l) => qpos += l as usize,
+            Cigar::Del(l) | Cigar::RefSkip(l) => pos += l as i64,
+            Cigar::HardClip(_) | Cigar::Pad(_) => {},
+        }
+    }
+    n
+}
+
+/// All genes a read could be assigned to. With `strict` a gene needs to contain every aligned
+/// segment of the read within its exons, otherwise any overlap suffices.
+fn candidate_genes(r: &bam::Record, map: &NClist<Exon>, config: &Args, strict: bool) -> Vec<usize> {
+    let cigar = r.cigar();
+    let forward = Strandness::fragment_forward(r);
+    let mut candidates: Option<Vec<usize>> = None;
+
+    for o in aligned_segments(&cigar) {
+        let mut ids: Vec<usize> = map.overlaps(&o)
+            .filter(|e| !strict || (o.start >= *e.start() && o.end <= *e.end()))
+            .filter(|e| config.strandness.matches(forward, e.strand))
+            .map(|e| e.gene())
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        candidates = Some(match candidates {
+            None => ids,
+            Some(mut c) if strict => { c.retain(|id| ids.contains(id)); c },
+            Some(mut c) => { c.extend(ids); c.sort_unstable(); c.dedup(); c },
+        });
+    }
+
+    candidates.unwrap_or_default()
+}
+
+/// The aligned bases on the exons of each gene, exons of a gene that overlap each other are
+/// counted once. Strict mode only counts segments within an exon.
+fn overlap_bases<I>(segments: I, forward: bool, map: &NClist<Exon>, config: &Args) -> Vec<(usize, i64)>
+    where I: Iterator<Item = Range<i64>>
+{
+    let strict = config.method == QuantMethod::Strict;
+    let mut bases: Vec<(usize, i64)> = Vec::new();
+    for o in segments {
+        let mut exons: Vec<(usize, Range<i64>)> = map.overlaps(&o)
+            .filter(|e| !strict || (o.start >= *e.start() && o.end <= *e.end()))
+            .filter(|e| config.strandness.matches(forward, e.strand))
+            .map(|e| (e.gene(), e.range.start.max(o.start)..e.range.end.min(o.end)))
+            .collect();
+        exons.sort_by_key(|e| (e.0, e.1.start));
+
+        let mut covered: Option<(usize, Range<i64>)> = None;
+        for (id, range) in exons {
+            match covered.as_mut() {
+                Some((c, r)) if *c == id && range.start <= r.end => r.end = r.end.max(range.end),
+                _ => {
+                    if let Some((c, r)) = covered.replace((id, range)) {
+                        add_bases(&mut bases, c, r.end - r.start);
+                    }
+                },
+            }
+        }
+        if let Some((c, r)) = covered {
+            add_bases(&mut bases, c, r.end - r.start);
+        }
+    }
+    bases
+}
+
+fn add_bases(bases: &mut Vec<(usize, i64)>, id: usize, n: i64) {
+    match bases.iter_mut().find(|b| b.0 == id) {
+        Some(b) => b.1 += n,
+        None => bases.push((id, n)),
+    }
+}
+
+pub(crate) fn map_segments(r: &bam::Record, map: &NClist<Exon>, config: &Args) -> SegmentHit {
+    let cigar = r.cigar();
+    let span = cigar.pos()..cigar.end_pos();
+    assign_segments(aligned_segments(&cigar), span, Strandness::fragment_forward(r), map, config)
+}
+
+/// Assign a read as if its fragment was on the opposite strand
+fn map_antisense(r: &bam::Record, map: &NClist<Exon>, config: &Args) -> SegmentHit {
+    let cigar = r.cigar();
+    let span = cigar.pos()..cigar.end_pos();
+    assign_segments(aligned_segments(&cigar), span, !Strandness::fragment_forward(r), map, config)
+}
+
+/// Assign a read pair as a single fragment, using the aligned segments of both mates
+fn map_fragment(r: &bam::Record, mate: &bam::Record, map: &NClist<Exon>, config: &Args) -> SegmentHit {
+    let (cigar, mate_cigar) = (r.cigar(), mate.cigar());
+    let segments = merge_segments(aligned_segments(&cigar), aligned_segments(&mate_cigar));
+    let span = cigar.pos().min(mate_cigar.pos())..cigar.end_pos().max(mate_cigar.end_pos());
+    assign_segments(segments, span, Strandness::fragment_forward(r), map, config)
+}
+
+/// Merge the aligned segments of two mates, each sorted by position, into one sorted sequence
+fn merge_segments<A, B>(a: A, b: B) -> impl Iterator<Item = Range<i64>>
+    where A: Iterator<Item = Range<i64>>, B: Iterator<Item = Range<i64>>
+{
+    let (mut a, mut b) = (a.peekable(), b.peekable());
+    std::iter::from_fn(move || match (a.peek(), b.peek()) {
+        (Some(x), Some(y)) if y.start < x.start => b.next(),
+        (Some(_), _) => a.next(),
+        (None, _) => b.next(),
+    })
+}
+
+thread_local! {
+    /// the exons overlapping the span of a read, the buffer is reused for every read
+    static CANDIDATES: RefCell<Vec<Exon>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Assign the aligned segments of a read or fragment, sorted by position. The interval list is
+/// queried once for the whole span of the alignment, and the exons found are sorted and merged
+/// with the segments in a single sweep.
+pub(crate) fn assign_segments<I>(segments: I, span: Range<i64>, forward: bool, map: &NClist<Exon>, config: &Args) -> SegmentHit
+    where I: Iterator<Item = Range<i64>>
+{
+    CANDIDATES.with(|candidates| {
+        let mut candidates = candidates.borrow_mut();
+        candidates.clear();
+        candidates.extend(map.overlaps(&span).cloned());
+        candidates.sort_unstable_by_key(|e| e.range.start);
+        sweep_segments(segments, forward, &candidates, config)
+    })
+}
+
+/// Match the sorted segments to the exons sorted by start. The exons before `lo` end before the
+/// current segment, the exons from `hi` start after it.
+fn sweep_segments<I>(segments: I, forward: bool, overlaps: &[Exon], config: &Args) -> SegmentHit
+    where I: Iterator<Item = Range<i64>>
+{
+    if overlaps.is_empty() {
+        return SegmentHit::Nohit;
+    }
+    let (mut lo, mut hi) = (0, 0);
+
+    //Store the first gene hit id
+    let mut  target_id = None;
+    // exons were skipped only because of their strand
+    let mut wrong_strand = false;
+
+    let strict = config.method == QuantMethod::Strict;
+    let strandness = config.strandness;
+
+    for o in segments {
+        while hi < overlaps.len() && overlaps[hi].range.start < o.end {
+            hi += 1;
+        }
+        while lo < hi && overlaps[lo].range.end <= o.start {
+            lo += 1;
+        }
+        //match this segment's genomic region to exons and filter based on program configuration,
+        // the segments of two mates overlap so an earlier segment can end after this one
+        let exons = overlaps[lo..hi].iter()
+            .filter(|e| e.range.start < o.end && e.range.end > o.start)
             .filter(|e| !strict || (o.start >= *e.start() && o.end <= *e.end()))
-            .filter(|e| strandness.matches_bam_record(r, e.strand));
+            .filter(|e| {
+                let matches = strandness.matches(forward, e.strand);
+                wrong_strand |= !matches;
+                matches
+            });
 
         // check that all overlapping exons map to the same gene
         let mut segment_ambiguous = false;
@@ -343,23 +4475,23 @@ fn map_segments(r: &bam::Record, map: &NClist<Exon>, config: &Args) -> SegmentHi
 
         for exon in exons {
             if let Some(id) = segment_id {
-                if !strict && (id != exon.id) {
+                if !strict && (id != exon.gene()) {
                     // in  union mode any part linking to a different gene makes it ambiguous
                     return SegmentHit::Ambiguous;
-                } else if strict && id != exon.id {
+                } else if strict && id != exon.gene() {
                     // in strict mode ambigous segments can be recued if a unique mapping is 
                     // available from other segments
                     segment_ambiguous = true;
                     break;
                 } 
             } else {
-                segment_id = Some(exon.id)
+                segment_id = Some(exon.gene())
             }
         }
 
         //strict requires al segments overlap the same gene
         if strict && segment_id.is_none() {
-            return SegmentHit::Nohit;
+            return if wrong_strand { SegmentHit::WrongStrand } else { SegmentHit::Nohit };
         }
 
         if !segment_ambiguous {
@@ -374,8 +4506,536 @@ fn map_segments(r: &bam::Record, map: &NClist<Exon>, config: &Args) -> SegmentHi
 
     if let Some(id) = target_id {
         SegmentHit::Hit(id)
+    } else if wrong_strand {
+        SegmentHit::WrongStrand
     } else {
         SegmentHit::Nohit
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hits() -> Vec<SegmentHit> {
+        (0..100).map(|i| match i % 7 {
+            0 => SegmentHit::Nohit,
+            1 => SegmentHit::Ambiguous,
+            2 => SegmentHit::WrongStrand,
+            n => SegmentHit::Hit(n + i % 3),
+        }).collect()
+    }
+
+    #[test]
+    fn zero_length_cigar_ops() {
+        let cigar = CigarString(vec![Cigar::SoftClip(0), Cigar::Match(0), Cigar::Match(10), Cigar::Del(0),
+            Cigar::Equal(0), Cigar::RefSkip(0), Cigar::Match(5), Cigar::Ins(0), Cigar::Diff(0)]).into_view(100);
+        assert_eq!(aligned_segments(&cigar).collect::<Vec<_>>(), vec![100..110, 110..115]);
+
+        let cigar = CigarString(vec![Cigar::SoftClip(10), Cigar::Match(0)]).into_view(100);
+        assert_eq!(aligned_segments(&cigar).count(), 0);
+    }
+
+    #[test]
+    fn missing_cigar_fallback() {
+        // htslib marks cigar-less records unmapped when reading SAM, they only occur in BAM files
+        let mut r = bam::Record::new();
+        r.set(b"r1", None, b"ACGTACGTAC", &[30; 10]);
+        r.set_pos(100);
+        assert_eq!(r.cigar_len(), 0);
+
+        set_ungapped_cigar(&mut r);
+        assert_eq!(r.qname(), b"r1");
+        assert_eq!(r.seq().as_bytes(), b"ACGTACGTAC");
+        assert_eq!(aligned_segments(&r.cigar()).collect::<Vec<_>>(), vec![100..110]);
+    }
+
+    #[test]
+    fn long_cigar_roundtrip() {
+        // more than 65535 operations are stored in a CG:B tag, which htslib expands when reading
+        let ops: Vec<Cigar> = (0..25_000).flat_map(|_| vec![Cigar::Equal(1), Cigar::Diff(1), Cigar::RefSkip(1)]).collect();
+        let mut r = bam::Record::new();
+        r.set(b"long", Some(&CigarString(ops)), &[b'A'; 50_000], &[30; 50_000]);
+        r.set_tid(0);
+        r.set_pos(100);
+
+        let p = std::env::temp_dir().join(format!("gensum-test-{}-long.bam", std::process::id()));
+        let mut header = bam::Header::new();
+        header.push_record(bam::header::HeaderRecord::new(b"SQ").push_tag(b"SN", "1").push_tag(b"LN", 1_000_000));
+        bam::Writer::from_path(&p, &header, bam::Format::Bam).unwrap().write(&r).unwrap();
+
+        let mut reader = bam::Reader::from_path(&p).unwrap();
+        let mut read = bam::Record::new();
+        reader.read(&mut read).unwrap().unwrap();
+        std::fs::remove_file(&p).unwrap();
+
+        assert_eq!(read.cigar_len(), 75_000);
+        let segments: Vec<_> = aligned_segments(&read.cigar()).collect();
+        assert_eq!(segments.len(), 50_000);
+        assert_eq!(segments.first(), Some(&(100..101)));
+        assert_eq!(segments.last(), Some(&(100 + 75_000 - 2..100 + 75_000 - 1)));
+    }
+
+    /// The arguments of the main command with the options, the files are not read
+    fn test_args(opts: &[&str]) -> Args {
+        use clap::Parser;
+        Args::try_parse_from([&["gensum", "-b", "x", "-g", "x"], opts].concat()).unwrap()
+    }
+
+    /// Load the annotation of a GTF text as the main command does with the arguments
+    fn load_test_gtf(name: &str, gtf: &str, args: &Args) -> Result<GeneMap> {
+        let p = std::env::temp_dir().join(format!("gensum-test-{}-{}.gtf", std::process::id(), name));
+        std::fs::write(&p, gtf).unwrap();
+        let gm = GeneMap::with_config(&p, args);
+        std::fs::remove_file(&p).unwrap();
+        gm
+    }
+
+    /// An alignment record at a position, without sequence
+    fn record(pos: i64, cigar: Vec<Cigar>) -> bam::Record {
+        let mut r = bam::Record::new();
+        r.set(b"r", Some(&CigarString(cigar)), b"", b"");
+        r.set_pos(pos);
+        r
+    }
+
+    #[test]
+    fn interleaved_chromosomes() {
+        // the first exon of chromosome 2 is skipped, and chromosome 1 continues after 3
+        let gtf = "1\tt\texon\t101\t200\t.\t+\t.\tgene_id \"GA\";\n\
+            2\tt\texon\t500\t400\t.\t+\t.\tgene_id \"GB\";\n\
+            3\tt\texon\t101\t200\t.\t-\t.\tgene_id \"GC\";\n\
+            2\tt\texon\t101\t200\t.\t+\t.\tgene_id \"GB\";\n\
+            1\tt\texon\t301\t400\t.\t+\t.\tgene_id \"GD\";\n";
+        let gm = load_test_gtf("interleaved", gtf, &test_args(&[])).unwrap();
+
+        assert_eq!(gm.intervals.len(), gm.seq_names.len());
+        let genes_on = |chr: &[u8]| -> Vec<usize> {
+            let map = &gm.intervals[gm.seq_names.get_index_of(chr).unwrap()];
+            map.overlaps(&(0..1000)).map(|e| e.gene()).collect()
+        };
+        assert_eq!(genes_on(b"1"), vec![0, 3]);
+        assert_eq!(genes_on(b"2"), vec![1]);
+        assert_eq!(genes_on(b"3"), vec![2]);
+    }
+
+    #[test]
+    fn export_model() {
+        let gtf = "2\tt\texon\t301\t400\t.\t-\t.\tgene_id \"GA\";\n\
+            1\tt\texon\t101\t200\t.\t+\t.\tgene_id \"GB\";\n\
+            2\tt\texon\t101\t200\t.\t-\t.\tgene_id \"GA\";\n\
+            2\tt\texon\t101\t200\t.\t-\t.\tgene_id \"GA\";\n";
+        let gm = load_test_gtf("model", gtf, &test_args(&[])).unwrap();
+        let mut out = Vec::new();
+        gm.write_model(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+            "2\t100\t200\tGA\t0\t-\n2\t300\t400\tGA\t0\t-\n1\t100\t200\tGB\t0\t+\n");
+    }
+
+    #[test]
+    fn gene_windows() {
+        // a reverse strand gene with two overlapping exons and one separate exon, 150 model bases
+        let gtf = "1\tt\texon\t101\t150\t.\t-\t.\tgene_id \"GA\";\n\
+            1\tt\texon\t131\t200\t.\t-\t.\tgene_id \"GA\";\n\
+            1\tt\texon\t301\t350\t.\t-\t.\tgene_id \"GA\";\n";
+        let args = test_args(&["--gene-windows", "x", "--gene-window-size", "40"]);
+        let gm = load_test_gtf("windows", gtf, &args).unwrap();
+        assert_eq!(gm.models[0], vec![100..200, 300..350]);
+
+        let mut counts = ReadMappings::with_config(1, &args);
+        // the last bases of the gene are its 5' end, a spliced read is counted once per window
+        counts.count_windows(&record(340, vec![Cigar::Match(10)]), 0, &gm, &args);
+        counts.count_windows(&record(190, vec![Cigar::Match(10), Cigar::RefSkip(100), Cigar::Match(5)]), 0, &gm, &args);
+        counts.count_windows(&record(160, vec![Cigar::Match(20)]), 0, &gm, &args);
+        counts.count_windows(&record(100, vec![Cigar::Match(5)]), 0, &gm, &args);
+        assert_eq!(counts.windows[0], vec![1, 2, 1, 1]);
+    }
+
+    #[test]
+    fn exonic_bases() {
+        let gtf = "1\tt\texon\t101\t200\t.\t+\t.\tgene_id \"GA\";\n\
+            1\tt\texon\t151\t250\t.\t+\t.\tgene_id \"GA\";\n\
+            1\tt\texon\t301\t400\t.\t+\t.\tgene_id \"GA\";\n";
+        let args = test_args(&["--exonic-bases", "x"]);
+        let gm = load_test_gtf("bases", gtf, &args).unwrap();
+        let mut counts = ReadMappings::with_config(1, &args);
+        // overlapping exons count once, the intron and the bases after the last exon not at all
+        counts.count_assigned(&record(190, vec![Cigar::Match(20), Cigar::RefSkip(100), Cigar::Match(5)]), &SegmentHit::Hit(0), 0, &gm, &args);
+        counts.count_assigned(&record(390, vec![Cigar::Match(20)]), &SegmentHit::Hit(0), 0, &gm, &args);
+        counts.count_assigned(&record(390, vec![Cigar::Match(20)]), &SegmentHit::Nohit, 0, &gm, &args);
+        assert_eq!(counts.exonic_bases, vec![35]);
+    }
+
+    #[test]
+    fn split_gene_coverage() {
+        let gtf = "1\tt\texon\t101\t200\t.\t+\t.\tgene_id \"GA\";\n\
+            2\tt\texon\t101\t200\t.\t+\t.\tgene_id \"GA\";\n";
+        let args = test_args(&["--coverage-profile", "x", "--coverage-bins", "4"]);
+        let gm = load_test_gtf("split", gtf, &args).unwrap();
+        let mut counts = ReadMappings::with_config(1, &args);
+        // the span of GA is on chromosome 1, the same positions on chromosome 2 add no coverage
+        for chr in 0..2 {
+            counts.count_assigned(&record(100, vec![Cigar::Match(30)]), &SegmentHit::Hit(0), chr, &gm, &args);
+        }
+        assert_eq!(counts.coverage[0], vec![25, 5, 0, 0]);
+    }
+
+    #[test]
+    fn source_counts() {
+        let gtf = "1\tensembl\texon\t101\t200\t.\t+\t.\tgene_id \"GA\";\n\
+            1\thavana\texon\t1001\t1100\t.\t+\t.\tgene_id \"GB\"; gene_source \"ensembl\";\n\
+            1\tcustom\texon\t2001\t2100\t.\t+\t.\tgene_id \"GC\";\n";
+        let gm = load_test_gtf("sources", gtf, &test_args(&[])).unwrap();
+        let mut counts = ReadMappings::new(3);
+        counts.hit = vec![1, 2, 4];
+        let mut out = Vec::new();
+        counts.write_source_counts(&mut out, &gm).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "custom\t4\nensembl\t3\n");
+    }
+
+    #[test]
+    fn qc_genes_read_once() {
+        let list = std::env::temp_dir().join(format!("gensum-test-{}-qc.txt", std::process::id()));
+        std::fs::write(&list, "# housekeeping\nA\nGZ\n").unwrap();
+        let args = test_args(&["--qc-genes", &list.to_string_lossy()]);
+        let gm = load_test_gtf("qc", "1\tt\texon\t101\t200\t.\t+\t.\tgene_id \"GA\"; gene_name \"A\";\n", &args).unwrap();
+        // the list is resolved when the annotation is loaded, not when each summary is written
+        std::fs::remove_file(&list).unwrap();
+        let mut counts = ReadMappings::new(1);
+        counts.hit = vec![4];
+        let rows = counts.summary(&gm, &args, None).unwrap();
+        let qc: Vec<_> = rows.iter().filter(|r| r.0.starts_with("qc_A")).collect();
+        assert_eq!(qc, [&("qc_A".to_string(), "4".to_string()), &("qc_A_cpm".to_string(), "1000000.00".to_string())]);
+    }
+
+    #[test]
+    fn sex_check_markers() {
+        let gtf = "X\tt\texon\t101\t200\t.\t+\t.\tgene_id \"GX\"; gene_name \"XIST\";\n\
+            Y\tt\texon\t101\t200\t.\t+\t.\tgene_id \"GY\"; gene_name \"UTY\";\n";
+        let args = test_args(&["--sex-check", "--male-genes", "GZ,UTY"]);
+        let gm = load_test_gtf("sex", gtf, &args).unwrap();
+        // the markers are found by gene_id or gene_name when the annotation is loaded
+        assert_eq!((gm.female_genes.as_slice(), gm.male_genes.as_slice()), (&[0][..], &[1][..]));
+        let mut counts = ReadMappings::new(2);
+        counts.hit = vec![3, 4];
+        let rows = counts.summary(&gm, &args, None).unwrap();
+        let sex: Vec<_> = rows.iter().filter(|r| r.0.starts_with("sex_check")).map(|r| r.1.as_str()).collect();
+        assert_eq!(sex, ["3", "4"]);
+    }
+
+    #[test]
+    fn spike_in_factors() {
+        let gtf = "1\tt\texon\t101\t200\t.\t+\t.\tgene_id \"ERCC-1\";\n\
+            1\tt\texon\t301\t400\t.\t+\t.\tgene_id \"GA\";\n";
+        let args = test_args(&["--spike-ins", "ERCC-"]);
+        let gm = load_test_gtf("spike-ins", gtf, &args).unwrap();
+        let samples: Vec<ReadMappings> = [[2, 5], [8, 1], [0, 7]].iter().map(|hit| {
+            let mut counts = ReadMappings::new(2);
+            counts.hit = hit.to_vec();
+            counts
+        }).collect();
+        // relative to the geometric mean 4 of the samples with spike-ins
+        let factors = spike_in_size_factors(&samples.iter().collect::<Vec<_>>(), &gm, &args);
+        let factors: Vec<_> = factors.iter().map(|f| f.map(|f| format!("{:.6}", f))).collect();
+        assert_eq!(factors, [Some("0.500000".to_string()), Some("2.000000".to_string()), None]);
+        assert_eq!(spike_in_size_factors(&samples[..1].iter().collect::<Vec<_>>(), &gm, &args), [Some(1.0)]);
+    }
+
+    #[test]
+    fn fixed_hash() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn strand_report() {
+        let gtf = "1\tt\texon\t101\t200\t.\t+\t.\tgene_id \"GA\";\n\
+            1\tt\texon\t1001\t1100\t.\t+\t.\tgene_id \"GB\";\n\
+            1\tt\texon\t2001\t2100\t.\t+\t.\tgene_id \"GC\";\n\
+            1\tt\texon\t3001\t3100\t.\t+\t.\tgene_id \"GD\";\n\
+            1\tt\texon\t4001\t4100\t.\t+\t.\tgene_id \"GE\";\n";
+        let gm = load_test_gtf("strand", gtf, &test_args(&[])).unwrap();
+        let mut counts = ReadMappings::new(5);
+        counts.hit = vec![90, 95, 90, 5, 1];
+        counts.antisense = vec![10, 5, 10, 95, 9];
+        let mut out = Vec::new();
+        counts.write_strand_report(&mut out, &gm).unwrap();
+        // GE has too few reads, the median of the others is 0.1
+        assert_eq!(String::from_utf8(out).unwrap(),
+            "gene_id\tsense\tantisense\tantisense_fraction\tsample_median\nGD\t5\t95\t0.950\t0.100\n");
+    }
+
+    #[test]
+    fn gc_window_counts() {
+        let mut seq = vec![b'a'; 200];
+        seq[0..10].fill(b'c');
+        seq[150] = b'N';
+        // 60 bases, the first 10 are C
+        let windows = gc_windows(&seq, &[0..40, 100..120]);
+        assert_eq!(windows.iter().sum::<u32>(), 11);
+        assert_eq!(&windows[..11], &[1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1]);
+        // the windows with the N are left out
+        assert_eq!(gc_windows(&seq, &[100..150, 150..200]), [vec![1], vec![0; 50]].concat());
+    }
+
+    #[test]
+    fn gene_versions() {
+        assert_eq!(strip_gene_version(b"ENSG00000141510.17").as_ref(), b"ENSG00000141510");
+        assert_eq!(strip_gene_version(b"ENSG00000182378.14_PAR_Y").as_ref(), b"ENSG00000182378_PAR_Y");
+        assert_eq!(strip_gene_version(b"ENSG00000141510").as_ref(), b"ENSG00000141510");
+        assert_eq!(strip_gene_version(b"RP11-34P13.x").as_ref(), b"RP11-34P13.x");
+        assert_eq!(strip_gene_version(b"RP11-34P13.5").as_ref(), b"RP11-34P13.5");
+        assert_eq!(strip_gene_version(b"AC000061.1").as_ref(), b"AC000061");
+        assert_eq!(strip_gene_version(b"Gm12345.2").as_ref(), b"Gm12345");
+        assert_eq!(strip_gene_version(b"HLA-A.1").as_ref(), b"HLA-A.1");
+        assert_eq!(strip_gene_version(b"GA.1").as_ref(), b"GA.1");
+
+        let gtf = "1\tt\texon\t101\t200\t.\t+\t.\tgene_id \"ENSG01.1\";\n\
+            1\tt\texon\t301\t400\t.\t+\t.\tgene_id \"ENSG01.2\";\n\
+            1\tt\texon\t1001\t1100\t.\t+\t.\tgene_id \"ENSG02.1\";\n";
+        let gm = load_test_gtf("versions", gtf, &test_args(&["--strip-gene-version"])).unwrap();
+        // the versions of ENSG01 are merged into one gene
+        assert_eq!(gm.genes.iter().collect::<Vec<_>>(), [b"ENSG01", b"ENSG02"]);
+        assert_eq!(gm.gene_info[0].span, 100..400);
+    }
+
+    #[test]
+    fn validate_introns() {
+        use clap::Parser;
+        let gtf = "1\tt\texon\t101\t200\t.\t+\t.\tgene_id \"GA\"; transcript_id \"T1\";\n\
+            1\tt\texon\t301\t400\t.\t+\t.\tgene_id \"GA\"; transcript_id \"T1\";\n\
+            1\tt\texon\t501\t600\t.\t+\t.\tgene_id \"GA\"; transcript_id \"T2\";\n\
+            1\tt\texon\t101\t200\t.\t+\t.\tgene_id \"GA\"; transcript_id \"T2\";\n\
+            1\tt\texon\t1001\t1100\t.\t+\t.\tgene_id \"GB\"; transcript_id \"T3\";\n\
+            1\tt\texon\t2001\t2100\t.\t+\t.\tgene_id \"GC\";\n";
+        for tolerance in ["-0.1", "NaN", "inf", "11"] {
+            assert!(Args::try_parse_from(["gensum", "-b", "x", "-g", "x", "--validate-introns", "--intron-tolerance", tolerance]).is_err());
+        }
+        let args = test_args(&["--validate-introns", "--intron-tolerance", "0.1"]);
+        let gm = load_test_gtf("introns", gtf, &args).unwrap();
+        // the longest intron of GA is in T2, GB has a single exon and GC no transcript
+        assert_eq!(gm.max_introns, vec![330, i64::MAX, i64::MAX]);
+
+        let mut counts = ReadMappings::with_config(3, &args);
+        let spliced = |n| record(180, vec![Cigar::Match(20), Cigar::RefSkip(n), Cigar::Match(20)]);
+        assert_eq!(counts.map_read(&spliced(320), 0, &gm, &args), SegmentHit::Hit(0));
+        assert_eq!(counts.map_read(&spliced(340), 0, &gm, &args), SegmentHit::IntronMismatch);
+        let within = record(1010, vec![Cigar::Match(20), Cigar::RefSkip(10), Cigar::Match(20)]);
+        assert_eq!(counts.map_read(&within, 0, &gm, &args), SegmentHit::Hit(1));
+        let within = record(2010, vec![Cigar::Match(20), Cigar::RefSkip(10), Cigar::Match(20)]);
+        assert_eq!(counts.map_read(&within, 0, &gm, &args), SegmentHit::Hit(2));
+    }
+
+    #[test]
+    fn boundary_mismatch() {
+        let gtf = "1\tt\texon\t101\t200\t.\t+\t.\tgene_id \"GA\";\n\
+            1\tt\texon\t151\t180\t.\t+\t.\tgene_id \"GA\";\n\
+            1\tt\texon\t1001\t1100\t.\t-\t.\tgene_id \"GB\";\n";
+        let args = test_args(&["--method", "strict", "--boundary-report", "x"]);
+        let gm = load_test_gtf("boundary", gtf, &args).unwrap();
+        let mut counts = ReadMappings::with_config(2, &args);
+        let read = |pos, len| record(pos, vec![Cigar::Match(len)]);
+        // the closest exon of GA is the longest one, the reverse GB has its 5' end on the right
+        assert_eq!(counts.map_read(&read(190, 20), 0, &gm, &args), SegmentHit::Nohit);
+        assert_eq!(counts.map_read(&read(90, 20), 0, &gm, &args), SegmentHit::Nohit);
+        assert_eq!(counts.map_read(&read(1090, 15), 0, &gm, &args), SegmentHit::Nohit);
+        assert_eq!(counts.map_read(&read(120, 20), 0, &gm, &args), SegmentHit::Hit(0));
+
+        let mut out = Vec::new();
+        counts.write_boundary_report(&mut out, &gm).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "gene_id\tfive_prime_reads\tfive_prime_bases\tthree_prime_reads\tthree_prime_bases\n\
+            GA\t1\t10\t1\t10\nGB\t1\t5\t0\t0\n");
+    }
+
+    #[test]
+    fn bad_exon_policy() {
+        let gtf = "1\tt\texon\t101\t200\t.\t+\t.\tgene_id \"GA\";\n\
+            1\tt\texon\t500\t400\t.\t+\t.\tgene_id \"GB\";\n\
+            1\tt\texon\t701\t700\t.\t+\t.\tgene_id \"GC\";\n";
+        let genes = |gm: &GeneMap| -> Vec<(usize, Range<i64>)> {
+            gm.intervals[0].overlaps(&(0..1000)).map(|e| (e.gene(), e.range.clone())).collect()
+        };
+
+        let gm = load_test_gtf("skip", gtf, &test_args(&[])).unwrap();
+        assert_eq!(genes(&gm), vec![(0, 100..200)]);
+        assert_eq!(gm.genes.len(), 3);
+        // the zero length exon is not swapped into an exon of 2 bases
+        let gm = load_test_gtf("swap", gtf, &test_args(&["--bad-exon", "swap"])).unwrap();
+        assert_eq!(genes(&gm), vec![(0, 100..200), (1, 399..500)]);
+        let err = format!("{:#}", load_test_gtf("fail", gtf, &test_args(&["--bad-exon", "fail"])).err().unwrap());
+        assert!(err.starts_with("Line 2 of ") && err.contains("gene GB"), "{}", err);
+    }
+
+    #[test]
+    fn verify_indexed_recount() {
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+        let mut sam = bam::Reader::from_path(data.join("mini.sam")).unwrap();
+        let mut records: Vec<bam::Record> = sam.records().map(|r| r.unwrap()).collect();
+        // unplaced reads (tid -1) go last
+        records.sort_by_key(|r| (r.tid() as u32, r.pos()));
+
+        let p = std::env::temp_dir().join(format!("gensum-test-{}-verify.bam", std::process::id()));
+        {
+            let header = bam::Header::from_template(sam.header());
+            let mut w = bam::Writer::from_path(&p, &header, bam::Format::Bam).unwrap();
+            for r in &records {
+                w.write(r).unwrap();
+            }
+        }
+        bam::index::build(&p, None, bam::index::Type::Bai, 1).unwrap();
+
+        for opts in [&[][..], &["--nosingle"], &["--merge-mates"], &["--tss-window", "50"], &["--singleton-policy", "count"]] {
+            let args = test_args(opts);
+            let gm = GeneMap::with_config(data.join("mini.gtf"), &args).unwrap();
+            let res = quantify_bam(&p, &args, &gm).unwrap();
+            verify_sample(&p, &args, &gm, &res, 100).unwrap();
+        }
+        std::fs::remove_file(&p).unwrap();
+        std::fs::remove_file(p.with_extension("bam.bai")).unwrap();
+    }
+
+    #[test]
+    fn custom_filter() {
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+        let args = test_args(&[]);
+        let gm = GeneMap::with_config(data.join("mini.gtf"), &args).unwrap();
+        let sam = || bam::Reader::from_path(data.join("mini.sam")).unwrap();
+
+        let all = quantify_reader_with(sam(), &args, &gm, |_, _| {}).unwrap();
+        let accept_all = |_: &bam::Record| FilterDecision::Accept;
+        let unfiltered = quantify_reader_filtered(sam(), &args, &gm, &[&accept_all], |_, _| {}).unwrap();
+        assert_eq!((unfiltered.hit, unfiltered.filtered), (all.hit.clone(), Some(0)));
+
+        // reject the reverse strand reads, only one filter needs to reject
+        let reverse = |r: &bam::Record| if r.is_reverse() { FilterDecision::Reject } else { FilterDecision::Accept };
+        let mut assigned = 0;
+        let res = quantify_reader_filtered(sam(), &args, &gm, &[&accept_all, &reverse], |r, _| {
+            assert!(!r.is_reverse());
+            assigned += 1;
+        }).unwrap();
+        assert!(res.filtered.unwrap() > 0);
+        assert!(res.hit.iter().sum::<Count>() < all.hit.iter().sum::<Count>());
+        assert!(assigned > 0);
+
+        let mut out = Vec::new();
+        res.write(&mut out, &gm, &args).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains(&format!("\nfiltered\t{}\n", res.filtered.unwrap())));
+    }
+
+    #[test]
+    fn annotated_bins() {
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+        let args = test_args(&["--intronic"]);
+        let mut gm = GeneMap::with_config(data.join("mini.gtf"), &args).unwrap();
+        // the exons of chromosome 1 are in the bins of the first 4 kb
+        assert_eq!(gm.annotated_bins[0], vec![0b1111]);
+        assert!(gm.annotated(0, 1023..1025));
+        assert!(!gm.annotated(0, 5000..5100));
+        assert!(gm.annotated(0, 3500..10_000_000));
+
+        let res = quantify_bam(data.join("mini.sam"), &args, &gm).unwrap();
+        gm.annotated_bins.clear();
+        assert_eq!(quantify_bam(data.join("mini.sam"), &args, &gm).unwrap(), res);
+
+        // the fragments of merged mates and the TSS windows
+        let args = test_args(&["--merge-mates", "--tss-window", "50", "--dual-counts"]);
+        let mut gm = GeneMap::with_config(data.join("mini.gtf"), &args).unwrap();
+        assert!(!gm.tss_bins[0].is_empty());
+        let res = quantify_bam(data.join("mini.sam"), &args, &gm).unwrap();
+        gm.annotated_bins.clear();
+        gm.tss_bins.clear();
+        assert_eq!(quantify_bam(data.join("mini.sam"), &args, &gm).unwrap(), res);
+    }
+
+    #[test]
+    fn sorted_segment_sweep() {
+        let a = vec![100..150, 300..350];
+        let b = vec![120..170, 200..210];
+        let merged: Vec<_> = merge_segments(a.into_iter(), b.into_iter()).collect();
+        assert_eq!(merged, vec![100..150, 120..170, 200..210, 300..350]);
+
+        let args = test_args(&[]);
+        let exon = |id, range| Exon { id, strand: Strand::Forward, range };
+        let exons = vec![exon(0, 90..160), exon(1, 140..145), exon(0, 290..400)];
+        assert_eq!(sweep_segments(vec![100..110, 300..350].into_iter(), true, &exons, &args), SegmentHit::Hit(0));
+        // the short exon of another gene is found after a longer exon that starts before it
+        assert_eq!(sweep_segments(vec![100..110, 141..143].into_iter(), true, &exons, &args), SegmentHit::Ambiguous);
+        assert_eq!(sweep_segments(std::iter::once(200..250), true, &exons, &args), SegmentHit::Nohit);
+    }
+
+    #[test]
+    fn fusion_mates() {
+        let read = |name: &[u8], tid, mtid| {
+            let mut r = bam::Record::new();
+            r.set(name, None, b"A", &[30]);
+            r.set_tid(tid);
+            r.set_mtid(mtid);
+            r
+        };
+        let mut mates = FusionMates { coordinate_sorted: true, ..Default::default() };
+        assert_eq!(mates.pair(&read(b"a", 0, 1), Some(3)), None);
+        assert_eq!(mates.pair(&read(b"a", 1, 0), Some(5)), Some(3));
+        // the first mate without gene is not kept, nor is the second mate after it
+        assert_eq!(mates.pair(&read(b"b", 0, 1), None), None);
+        assert_eq!(mates.pair(&read(b"b", 1, 0), Some(5)), None);
+        // the pair is removed when the second mate is not on a gene
+        assert_eq!(mates.pair(&read(b"c", 0, 1), Some(3)), None);
+        assert_eq!(mates.pair(&read(b"c", 1, 0), None), None);
+        assert!(mates.delayed.is_empty());
+        assert_eq!(mates.bytes, 0);
+    }
+
+    #[test]
+    fn merge_shards() {
+        let mut single = ReadMappings::new(9);
+        for h in hits() {
+            single.count_hit(h);
+        }
+        single.bridging.insert((1, 2), 3);
+        single.fusions.insert((0, 4), vec![b"r1".to_vec(), b"r2".to_vec()]);
+
+        let mut shards = vec![ReadMappings::new(9), ReadMappings::new(9), ReadMappings::new(9)];
+        for (i, h) in hits().into_iter().enumerate() {
+            shards[i % 3].count_hit(h);
+        }
+        shards[0].bridging.insert((1, 2), 1);
+        shards[2].bridging.insert((1, 2), 2);
+        shards[1].fusions.insert((0, 4), vec![b"r1".to_vec()]);
+        shards[2].fusions.insert((0, 4), vec![b"r2".to_vec()]);
+
+        // the per gene reports, the windows and coverage of a gene are allocated on its first read
+        single.windows = vec![Vec::new(); 9];
+        single.windows[2] = vec![3, 1];
+        single.coverage = vec![Vec::new(); 9];
+        single.coverage[4] = vec![2, 0, 5];
+        single.boundary = vec![BoundaryMismatch::default(); 9];
+        single.boundary[1] = BoundaryMismatch { five_prime: 2, five_prime_bases: 7, three_prime: 1, three_prime_bases: 3 };
+        single.weighted = vec![0.0; 9];
+        single.weighted[0] = 1.5;
+        single.strata.insert((3, b"a".to_vec()), 4);
+        for shard in &mut shards {
+            shard.windows = vec![Vec::new(); 9];
+            shard.coverage = vec![Vec::new(); 9];
+            shard.boundary = vec![BoundaryMismatch::default(); 9];
+            shard.weighted = vec![0.0; 9];
+        }
+        shards[0].windows[2] = vec![1, 1];
+        shards[1].windows[2] = vec![2, 0];
+        shards[1].coverage[4] = vec![2, 0, 1];
+        shards[2].coverage[4] = vec![0, 0, 4];
+        shards[0].boundary[1] = BoundaryMismatch { five_prime: 1, five_prime_bases: 4, three_prime: 0, three_prime_bases: 0 };
+        shards[2].boundary[1] = BoundaryMismatch { five_prime: 1, five_prime_bases: 3, three_prime: 1, three_prime_bases: 3 };
+        shards[0].weighted[0] = 0.5;
+        shards[1].weighted[0] = 1.0;
+        shards[0].strata.insert((3, b"a".to_vec()), 1);
+        shards[2].strata.insert((3, b"a".to_vec()), 3);
+
+        let mut merged = ReadMappings::new(9);
+        for s in shards {
+            merged.merge(s);
+        }
+        assert_eq!(merged, single);
+    }
+}
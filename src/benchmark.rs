@@ -0,0 +1,84 @@
+//! Entry points of the benchmarks in benches/. They build a dense synthetic annotation and reads
+//! in memory, and time the assignment of the reads to the exons. Not part of the API.
+use std::ops::Range;
+
+use clap::Parser;
+use nclist::NClist;
+use rust_htslib::bam::{self, record::{Cigar, CigarString}};
+
+use crate::Args;
+use crate::app::{aligned_segments, assign_segments, map_segments, Exon, SegmentHit};
+use crate::gtf::Strand;
+
+/// The exons of overlapping genes on one chromosome, and reads on them
+pub struct DenseAnnotation {
+    exons: NClist<Exon>,
+    reads: Vec<bam::Record>,
+    /// the aligned segments and span of each read
+    segments: Vec<(Vec<Range<i64>>, Range<i64>)>,
+    config: Args,
+}
+
+impl DenseAnnotation {
+    /// `genes` genes of `exons` exons of 100 bases with introns of 200 bases, a gene starting
+    /// every 100 bases on alternating strands, so about 3 * `exons` genes overlap each base.
+    /// Half of the `reads` are spliced over an intron.
+    pub fn new(genes: usize, exons: usize, reads: usize) -> DenseAnnotation {
+        let mut annotation = Vec::with_capacity(genes * exons);
+        for gene in 0..genes {
+            let strand = if gene % 2 == 0 { Strand::Forward } else { Strand::Reverse };
+            for exon in 0..exons {
+                let start = (gene * 100 + exon * 300) as i64;
+                annotation.push(Exon::new(gene, strand, start..start + 100));
+            }
+        }
+        let end = (genes * 100 + exons * 300) as i64;
+
+        // a fixed sequence of positions, the runs are comparable
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let reads: Vec<bam::Record> = (0..reads).map(|i| {
+            state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            let cigar = match i % 2 {
+                0 => vec![Cigar::Match(50)],
+                _ => vec![Cigar::Match(25), Cigar::RefSkip(200), Cigar::Match(25)],
+            };
+            let mut r = bam::Record::new();
+            r.set(b"r", Some(&CigarString(cigar)), &[b'A'; 50], &[30; 50]);
+            r.set_tid(0);
+            r.set_pos((state >> 33) as i64 % end);
+            if i % 3 == 0 {
+                r.set_reverse();
+            }
+            r
+        }).collect();
+        let segments = reads.iter().map(|r| {
+            let cigar = r.cigar();
+            (aligned_segments(&cigar).collect(), cigar.pos()..cigar.end_pos())
+        }).collect();
+
+        DenseAnnotation {
+            exons: NClist::from_vec(annotation).expect("valid exons"),
+            reads,
+            segments,
+            config: Args::try_parse_from(["gensum", "-b", "x", "-g", "x"]).unwrap(),
+        }
+    }
+
+    /// Assign every read from its cigar, like the counting. Returns the number of assigned reads.
+    pub fn map_segments(&self) -> usize {
+        self.reads.iter()
+            .filter(|r| matches!(map_segments(r, &self.exons, &self.config), SegmentHit::Hit(_)))
+            .count()
+    }
+
+    /// Assign the aligned segments of every read, without reading the cigar. Returns the number
+    /// of assigned reads.
+    pub fn assign_segments(&self) -> usize {
+        self.segments.iter().enumerate()
+            .filter(|(i, (segments, span))| {
+                let hit = assign_segments(segments.iter().cloned(), span.clone(), i % 3 != 0, &self.exons, &self.config);
+                matches!(hit, SegmentHit::Hit(_))
+            })
+            .count()
+    }
+}
@@ -0,0 +1,42 @@
+//! Entry points of the fuzz targets in fuzz/. They run the parsers on arbitrary input and ignore
+//! the errors, a panic is a bug. Not part of the API.
+use rust_htslib::bam::{self, HeaderView};
+use rust_htslib::bam::header::{Header, HeaderRecord};
+
+use crate::app::{aligned_segments, longest_intron};
+use crate::gtf::{Gff3Index, GtfReader, GtfRecord};
+
+/// Parse every line of `data` as an exon of each annotation format
+pub fn annotation(data: &[u8]) {
+    let index = Gff3Index::read(data).ok();
+    let mut reader = GtfReader::new(data);
+    let mut record = GtfRecord::new();
+    while let Ok(n) = reader.read_record(&mut record) {
+        if n == 0 {
+            break;
+        }
+        let _ = record.parse_exon(&["gene_id"]).map(|e| e.map(|e| e.attribute_problems()));
+        if let Some(index) = index.as_ref() {
+            let _ = record.parse_gff3_exon(index);
+        }
+        let _ = record.parse_bed_exon();
+        let _ = record.parse_saf_exon();
+        let _ = record.parse_interval_list_exon();
+        let _ = record.parse_gene_pred_exons(false);
+        let _ = record.parse_gene_pred_exons(true);
+        let _ = record.column_problems();
+    }
+}
+
+/// Parse `data` as a SAM line on contig 1 or 2 and scan its cigar like the counting
+pub fn sam_record(data: &[u8]) {
+    let mut header = Header::new();
+    for contig in ["1", "2"] {
+        header.push_record(HeaderRecord::new(b"SQ").push_tag(b"SN", contig).push_tag(b"LN", 10000));
+    }
+    let header = HeaderView::from_header(&header);
+    if let Ok(r) = bam::Record::from_sam(&header, data) {
+        aligned_segments(&r.cigar()).for_each(drop);
+        longest_intron(&r);
+    }
+}
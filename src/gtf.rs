@@ -1,23 +1,36 @@
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::io::{self, Read, BufRead, BufReader};
 
 use anyhow::{Result, Context};
 use atoi::atoi;
+use memchr::memchr;
 
 pub struct GtfReader<R> {
     reader: BufReader<R>,
+    line: usize,
 }
 
 impl<R: Read> GtfReader<R> {
     pub fn new(r: R) -> GtfReader<R> {
         let reader = BufReader::new(r);
-        GtfReader { reader }
+        GtfReader { reader, line: 0 }
+    }
+
+    /// The line number of the last record, counting from 1
+    pub fn line(&self) -> usize {
+        self.line
     }
 
     pub fn read_record(&mut self, record: &mut GtfRecord) -> io::Result<usize> {
         loop {
             let n = self.reader.read_until(b'\n', record.clear_buf_mut())?;
-            if !record.is_comment() {
+            self.line += 1;
+            // sequences may follow the features in a GFF3 file
+            if record.0.starts_with(b"##FASTA") {
+                break Ok(0);
+            }
+            if n == 0 || !(record.is_comment() || record.is_blank()) {
                 break Ok(n);
             }
         }
@@ -31,56 +44,367 @@ impl GtfRecord {
         GtfRecord(Vec::new())
     }
 
-    fn clear_buf_mut(&mut self) -> &mut Vec<u8> {
+    pub fn clear_buf_mut(&mut self) -> &mut Vec<u8> {
         self.0.clear();
         &mut self.0
     }
 
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The gene id of a GTF record of any feature type, the first of the `id_attrs` attributes
+    /// the record has, like `parse_exon`
+    pub fn gene_key<A: AsRef<[u8]>>(&self, id_attrs: &[A]) -> Option<&[u8]> {
+        let attrs = Fields::new(&self.0, b'\t').nth(8)?;
+        let (mut id, mut id_rank) = (None, id_attrs.len());
+        for (key, value) in attributes(attrs) {
+            if let Some(rank) = id_attrs[..id_rank].iter().position(|a| a.as_ref() == key) {
+                id = Some(value);
+                id_rank = rank;
+            }
+        }
+        id
+    }
+
     pub fn is_comment(&self) -> bool {
         self.0.first() == Some(&b'#')
     }
 
+    pub fn is_blank(&self) -> bool {
+        self.0.iter().all(u8::is_ascii_whitespace)
+    }
+
     /// attempt to parse the current GTF record as an exon
     /// Returns None for any other type
     /// Fails when unable to parse or required attributes (gene_id)
-    /// are not present
-    pub fn parse_exon(&self) -> Result<Option<GtfExon>> {
-        let mut s = self.0.split(|&b| b == b'\t');
+    /// are not present. The gene id is the first of the `id_attrs`
+    /// attributes the exon has, e.g. gene_id, gene or locus_tag
+    pub fn parse_exon<A: AsRef<[u8]>>(&self, id_attrs: &[A]) -> Result<Option<GtfExon<'_>>> {
+        let (seq_name, source, start, end, strand, attrs) = match self.exon_columns()? {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        // scan the attributes once for all keys of interest
+        let (mut id, mut name, mut biotype) = (None, None, None);
+        let (mut transcript_id, mut exon_number, mut gene_source) = (None, None, None);
+        let mut id_rank = id_attrs.len();
+        for (key, value) in attributes(attrs) {
+            if let Some(rank) = id_attrs[..id_rank].iter().position(|a| a.as_ref() == key) {
+                id = Some(value);
+                id_rank = rank;
+            }
+            match key {
+                b"gene_name" if name.is_none() => name = Some(value),
+                b"transcript_id" if transcript_id.is_none() => transcript_id = Some(value),
+                b"exon_number" if exon_number.is_none() => exon_number = atoi(value),
+                // ensembl uses gene_biotype, gencode gene_type
+                b"gene_biotype" | b"gene_type" if biotype.is_none() => biotype = Some(value),
+                b"gene_source" if gene_source.is_none() => gene_source = Some(value),
+                _ => {},
+            }
+        }
+        let id = id.ok_or_else(|| data_error(&self.0)).with_context(|| {
+            let names: Vec<_> = id_attrs.iter().map(|a| String::from_utf8_lossy(a.as_ref())).collect();
+            format!("No {} in attributes", names.join(" or "))
+        })?;
+
+        let source = Some(gene_source.unwrap_or(source));
+        Ok(Some(GtfExon { seq_name, start, end, strand, id, name, biotype, source, transcript_id, exon_number, attrs }))
+    }
+
+    /// Parse the current GFF3 record as an exon. The gene is found by following the Parent
+    /// attributes in the index, its gene_id attribute or otherwise its ID is the gene id.
+    /// Fails when the exon has no Parent, or the Parent is not in the file.
+    pub fn parse_gff3_exon<'a>(&'a self, index: &'a Gff3Index) -> Result<Option<GtfExon<'a>>> {
+        let (seq_name, source, start, end, strand, attrs) = match self.exon_columns()? {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        let (mut parent, mut exon_number) = (None, None);
+        for (key, value) in gff3_attributes(attrs) {
+            match key {
+                // an exon shared by transcripts may list all of them
+                b"Parent" if parent.is_none() => parent = Fields::new(value, b',').next(),
+                // ensembl uses rank
+                b"rank" | b"exon_number" if exon_number.is_none() => exon_number = atoi(value),
+                _ => {},
+            }
+        }
+        let parent = parent.ok_or_else(|| data_error(&self.0)).context("No Parent in attributes")?;
+        let (gene_key, gene) = index.gene(parent)
+            .ok_or_else(|| data_error(&self.0))
+            .context("Parent of exon not found in GFF3")?;
+        let transcript_id = match index.features.get_key_value(parent) {
+            Some((k, t)) if k.as_slice() != gene_key => Some(t.stable_id.as_deref().unwrap_or(k.as_slice())),
+            _ => None,
+        };
+
+        Ok(Some(GtfExon {
+            seq_name, start, end, strand,
+            id: gene.stable_id.as_deref().unwrap_or(gene_key),
+            name: gene.name.as_deref(),
+            biotype: gene.biotype.as_deref(),
+            source: Some(source),
+            transcript_id,
+            exon_number,
+            attrs: b"",
+        }))
+    }
+
+    /// Parse the current record as a line of a 6 column BED file, the name column is the gene id.
+    /// The coordinates are converted to 1 based. Returns None for track and browser lines.
+    pub fn parse_bed_exon(&self) -> Result<Option<GtfExon<'_>>> {
+        let line = self.0.trim_ascii_end();
+        if line.starts_with(b"track") || line.starts_with(b"browser") {
+            return Ok(None);
+        }
+        let mut s = Fields::new(line, b'\t');
+        let seq_name = s.next().ok_or_else(|| data_error(&self.0)).context("No chrom in BED line")?;
+        let start = s.next().and_then(atoi).filter(|v: &i64| (0..i64::MAX - 1).contains(v))
+            .ok_or_else(|| data_error(&self.0))
+            .context("Invalid start")?;
+        let end = s.next().and_then(atoi).filter(|v: &i64| (0..i64::MAX).contains(v))
+            .ok_or_else(|| data_error(&self.0))
+            .context("Invalid end")?;
+        let id = s.next().ok_or_else(|| data_error(&self.0)).context("No name in BED line")?;
+        let strand = s.nth(1)
+            .ok_or("No strand")
+            .and_then(Strand::try_from)
+            .map_err(|_| data_error(&self.0))
+            .context("Invalid strand")?;
+        Ok(Some(GtfExon::interval(seq_name, start + 1, end, strand, id)))
+    }
+
+    /// Parse the current record as a line of a featureCounts SAF file: GeneID, Chr, Start, End
+    /// and Strand, with 1 based coordinates. Returns None for the header line.
+    pub fn parse_saf_exon(&self) -> Result<Option<GtfExon<'_>>> {
+        let line = self.0.trim_ascii_end();
+        let mut s = Fields::new(line, b'\t');
+        let id = s.next().ok_or_else(|| data_error(&self.0)).context("No GeneID in SAF line")?;
+        if id == b"GeneID" {
+            return Ok(None);
+        }
+        let seq_name = s.next().ok_or_else(|| data_error(&self.0)).context("No Chr in SAF line")?;
+        let start = s.next().and_then(atoi).filter(|v: &i64| (1..i64::MAX).contains(v))
+            .ok_or_else(|| data_error(&self.0))
+            .context("Invalid start")?;
+        let end = s.next().and_then(atoi).filter(|v: &i64| (0..i64::MAX).contains(v))
+            .ok_or_else(|| data_error(&self.0))
+            .context("Invalid end")?;
+        let strand = s.next()
+            .ok_or("No strand")
+            .and_then(Strand::try_from)
+            .map_err(|_| data_error(&self.0))
+            .context("Invalid strand")?;
+        Ok(Some(GtfExon::interval(seq_name, start, end, strand, id)))
+    }
+
+    /// Parse the current record as a line of a Picard interval_list: contig, start, end, strand and
+    /// name, with 1 based coordinates. The name is the gene id. Returns None for the SAM header.
+    pub fn parse_interval_list_exon(&self) -> Result<Option<GtfExon<'_>>> {
+        if self.0.first() == Some(&b'@') {
+            return Ok(None);
+        }
+        let mut s = Fields::new(self.0.trim_ascii_end(), b'\t');
+        let seq_name = s.next().ok_or_else(|| data_error(&self.0)).context("No contig in interval_list line")?;
+        let start = s.next().and_then(atoi).filter(|v: &i64| (1..i64::MAX).contains(v))
+            .ok_or_else(|| data_error(&self.0))
+            .context("Invalid start")?;
+        let end = s.next().and_then(atoi).filter(|v: &i64| (0..i64::MAX).contains(v))
+            .ok_or_else(|| data_error(&self.0))
+            .context("Invalid end")?;
+        let strand = s.next()
+            .ok_or("No strand")
+            .and_then(Strand::try_from)
+            .map_err(|_| data_error(&self.0))
+            .context("Invalid strand")?;
+        let id = s.next().ok_or_else(|| data_error(&self.0)).context("No name in interval_list line")?;
+        Ok(Some(GtfExon::interval(seq_name, start, end, strand, id)))
+    }
+
+    /// Parse the current record as a UCSC refFlat or genePred line, with an exon for every block
+    /// of exonStarts and exonEnds. The gene id is the gene name of refFlat, or the name2 column of
+    /// an extended genePred file and its transcript name otherwise. The leading bin column of
+    /// UCSC table dumps is skipped.
+    pub fn parse_gene_pred_exons(&self, refflat: bool) -> Result<Vec<GtfExon<'_>>> {
+        let mut cols: Vec<&[u8]> = Fields::new(self.0.trim_ascii_end(), b'\t').collect();
+        if !refflat && cols.len() > 3 && Strand::try_from(cols[3]).is_ok() {
+            cols.remove(0);
+        }
+        let (id, transcript, cols) = match (refflat, cols.len()) {
+            (true, n) if n >= 11 => (cols[0], cols[1], &cols[2..]),
+            (false, n) if n >= 10 => (cols.get(11).copied().unwrap_or(cols[0]), cols[0], &cols[1..]),
+            _ => return Err(data_error(&self.0)).context("Too few columns for refFlat or genePred"),
+        };
+        let seq_name = cols[0];
+        let strand = Strand::try_from(cols[1]).map_err(|_| data_error(&self.0)).context("Invalid strand")?;
+
+        let positions = |col: &'_ [u8]| -> Result<Vec<i64>> {
+            Fields::new(col, b',')
+                .filter(|v| !v.is_empty())
+                .map(|v| atoi(v).filter(|v: &i64| (0..i64::MAX).contains(v)))
+                .collect::<Option<_>>()
+                .ok_or_else(|| data_error(&self.0))
+                .context("Invalid exon position")
+        };
+        let (starts, ends) = (positions(cols[7])?, positions(cols[8])?);
+        if starts.len() != ends.len() {
+            return Err(data_error(&self.0)).context("Different number of exon starts and ends");
+        }
+
+        let n = starts.len();
+        Ok(starts.into_iter().zip(ends).enumerate().map(|(i, (start, end))| {
+            let exon_number = if strand == Strand::Reverse { n - i } else { i + 1 };
+            GtfExon {
+                transcript_id: Some(transcript),
+                exon_number: u32::try_from(exon_number).ok(),
+                ..GtfExon::interval(seq_name, start + 1, end, strand, id)
+            }
+        }).collect())
+    }
+
+    /// The sequence name, source, coordinates, strand and attribute column of an exon record, the
+    /// columns are the same in GTF and GFF3. Returns None for any other type.
+    #[allow(clippy::type_complexity)]
+    fn exon_columns(&self) -> Result<Option<(&[u8], &[u8], i64, i64, Strand, &[u8])>> {
+        let mut s = Fields::new(&self.0, b'\t');
         let seq_name = s.next()
             .ok_or_else(|| data_error(&self.0))
             .context("No seqname in gtf line")?;
-        //skip source
-        let seq_type = s.nth(1)
+        let source = s.next()
+            .ok_or_else(|| data_error(&self.0))
+            .context("No source in gtf line")?;
+        let seq_type = s.next()
             .ok_or_else(|| data_error(&self.0))
             .context("No seqtype in gtf line")?;
         //eprintln!("type {}", seq_type);
-        if seq_type == b"exon" {
-            let start = s.next().and_then(atoi)
-                .ok_or_else(|| data_error(&self.0))
-                .context("Invalid start")?;
-            let end = s.next().and_then(atoi)
-                .ok_or_else(|| data_error(&self.0))
-                .context("Invalid end")?;
-            let strand = s.nth(1)
-                .ok_or("No strand")
-                .and_then(Strand::try_from)
-                .map_err(|_| data_error(&self.0))
-                .context("Invalid strand")?;
+        if seq_type != b"exon" {
+            return Ok(None);
+        }
+        // coordinates are 1 based, reject values that would under/overflow when converted
+        let start = s.next().and_then(atoi).filter(|v: &i64| (1..i64::MAX).contains(v))
+            .ok_or_else(|| data_error(&self.0))
+            .context("Invalid start")?;
+        let end = s.next().and_then(atoi).filter(|v: &i64| (0..i64::MAX).contains(v))
+            .ok_or_else(|| data_error(&self.0))
+            .context("Invalid end")?;
+        let strand = s.nth(1)
+            .ok_or("No strand")
+            .and_then(Strand::try_from)
+            .map_err(|_| data_error(&self.0))
+            .context("Invalid strand")?;
+
+        // the rest of the line, messy files may have tabs within the attributes
+        let attrs = s.next().and(s.rest).ok_or_else(|| data_error(&self.0)).context("No attributes")?;
+        Ok(Some((seq_name, source, start, end, strand, attrs)))
+    }
+
+    /// The problems of the columns of a GTF or GFF3 line of any feature type: missing columns,
+    /// invalid coordinates, score, strand or frame. The end before the start of an exon is left to
+    /// the exon parser.
+    pub fn column_problems(&self) -> Vec<String> {
+        let columns: Vec<&[u8]> = Fields::new(self.0.trim_ascii_end(), b'\t').collect();
+        if columns.len() < 9 {
+            return vec![format!("{} columns instead of 9", columns.len())];
+        }
+        let mut problems = Vec::new();
+        let text = |c: &[u8]| String::from_utf8_lossy(c).into_owned();
+        for (i, name) in [(0, "seqname"), (2, "feature type")] {
+            if columns[i].is_empty() {
+                problems.push(format!("empty {}", name));
+            }
+        }
+        let start = atoi::<i64>(columns[3]).filter(|v| (1..i64::MAX).contains(v));
+        let end = atoi::<i64>(columns[4]).filter(|v| (0..i64::MAX).contains(v));
+        match (start, end) {
+            (None, _) => problems.push(format!("invalid start '{}'", text(columns[3]))),
+            (_, None) => problems.push(format!("invalid end '{}'", text(columns[4]))),
+            (Some(start), Some(end)) if end < start && columns[2] != b"exon" => {
+                problems.push(format!("{} ends at {} before its start {}", text(columns[2]), end, start));
+            },
+            _ => (),
+        }
+        if columns[5] != b"." && std::str::from_utf8(columns[5]).ok().and_then(|v| v.parse::<f64>().ok()).is_none() {
+            problems.push(format!("invalid score '{}'", text(columns[5])));
+        }
+        // GFF3 allows ? for a strand that is relevant but unknown
+        if Strand::try_from(columns[6]).is_err() && columns[6] != b"?" {
+            problems.push(format!("invalid strand '{}'", text(columns[6])));
+        }
+        if !matches!(columns[7], b"." | b"0" | b"1" | b"2") {
+            problems.push(format!("invalid frame '{}'", text(columns[7])));
+        }
+        problems
+    }
+}
+
+/// The genes and transcripts of a GFF3 file by their ID, read in a first pass over the file.
+/// Exons refer to their transcript with the Parent attribute, which refers to the gene.
+#[derive(Default)]
+pub struct Gff3Index {
+    features: HashMap<Vec<u8>, Gff3Feature>,
+}
+
+struct Gff3Feature {
+    parent: Option<Vec<u8>>,
+    /// the gene_id or transcript_id attribute, ensembl prefixes the ID with the feature type
+    stable_id: Option<Vec<u8>>,
+    name: Option<Vec<u8>>,
+    biotype: Option<Vec<u8>>,
+}
+
+impl Gff3Index {
+    /// limit on the depth of the feature hierarchy, guards against Parent cycles
+    const MAX_DEPTH: usize = 16;
 
-            let attrs = s.nth(1).ok_or_else(|| data_error(&self.0)).context("No attributes")?;
+    pub fn read<R: Read>(r: R) -> Result<Gff3Index> {
+        let mut reader = GtfReader::new(r);
+        let mut record = GtfRecord::new();
+        let mut index = Gff3Index::default();
+        while reader.read_record(&mut record)? > 0 {
+            let mut s = Fields::new(&record.0, b'\t');
+            // exons and the features within them are never a parent
+            let attrs = match s.nth(2) {
+                Some(b"exon") | Some(b"CDS") | Some(b"five_prime_UTR") | Some(b"three_prime_UTR") | None => continue,
+                Some(_) => s.nth(5),
+            };
 
-            // split attrs on ';'
-            // in the ensembl gtf the gene_id is the first entry so this is not
-            // really necessary.
-            let mut attr = attrs.split(|&b| b == b';');
-            let id = attr.find(|s| s.starts_with(b"gene_id "))
-                .map(|s| &s[9..s.len()-1])
-                .ok_or_else(|| data_error(&self.0)).context("No gene_id in attributes")?;
+            let (mut id, mut feature) = (None, Gff3Feature { parent: None, stable_id: None, name: None, biotype: None });
+            for (key, value) in attrs.into_iter().flat_map(gff3_attributes) {
+                match key {
+                    b"ID" => id = Some(value),
+                    b"Parent" => feature.parent = Fields::new(value, b',').next().map(|p| p.to_vec()),
+                    b"gene_id" | b"transcript_id" => feature.stable_id = Some(value.to_vec()),
+                    b"Name" => feature.name = Some(value.to_vec()),
+                    b"gene_name" if feature.name.is_none() => feature.name = Some(value.to_vec()),
+                    // ensembl uses biotype, ncbi gene_biotype and gencode gene_type
+                    b"biotype" | b"gene_biotype" | b"gene_type" => feature.biotype = Some(value.to_vec()),
+                    _ => {},
+                }
+            }
+            if let Some(id) = id {
+                index.features.insert(id.to_vec(), feature);
+            }
+        }
+        Ok(index)
+    }
 
-            Ok(Some(GtfExon { seq_name, start, end, strand, id}))
-        } else {
-            Ok(None)
+    /// The top level feature of a feature and its ID, following the Parent attributes
+    fn gene<'a>(&'a self, mut id: &'a [u8]) -> Option<(&'a [u8], &'a Gff3Feature)> {
+        let mut feature = self.features.get(id)?;
+        for _ in 0..Self::MAX_DEPTH {
+            match feature.parent.as_deref().and_then(|p| self.features.get_key_value(p)) {
+                Some((k, p)) => {
+                    id = k.as_slice();
+                    feature = p;
+                },
+                None => return Some((id, feature)),
+            }
         }
+        None
     }
 }
 
@@ -90,6 +414,78 @@ impl std::fmt::Display for GtfRecord {
     }
 }
 
+/// Splits a line into fields on a separator, using memchr to find the separators
+struct Fields<'a> {
+    rest: Option<&'a [u8]>,
+    sep: u8,
+}
+
+impl<'a> Fields<'a> {
+    fn new(line: &'a [u8], sep: u8) -> Fields<'a> {
+        Fields { rest: Some(line), sep }
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = &'a [u8];
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let rest = self.rest?;
+        match memchr(self.sep, rest) {
+            Some(i) => {
+                self.rest = Some(&rest[i + 1..]);
+                Some(&rest[..i])
+            },
+            None => {
+                self.rest = None;
+                Some(rest)
+            }
+        }
+    }
+}
+
+/// Iterate over the key-value pairs of the ';' separated GTF attribute column, in any order and
+/// with or without a final ';'. The key and value are separated by spaces or tabs, quotes around
+/// the values are removed and a ';' within quotes is part of the value.
+fn attributes(attrs: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    attribute_fields(attrs).filter_map(|a| {
+        let a = a.trim_ascii();
+        let i = a.iter().position(u8::is_ascii_whitespace)?;
+        let v = a[i + 1..].trim_ascii();
+        Some((&a[..i], v.strip_prefix(b"\"").and_then(|v| v.strip_suffix(b"\"")).unwrap_or(v)))
+    })
+}
+
+/// The ';' separated fields of the GTF attribute column, a ';' within quotes is part of a field
+fn attribute_fields(attrs: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = Some(attrs);
+    std::iter::from_fn(move || {
+        let s = rest?;
+        let mut end = 0;
+        while let Some(i) = memchr(b';', &s[end..]) {
+            end += i;
+            // an odd number of quotes before the ';', it is within a value
+            if s[..end].iter().filter(|&&c| c == b'"').count() % 2 == 0 {
+                rest = Some(&s[end + 1..]);
+                return Some(&s[..end]);
+            }
+            end += 1;
+        }
+        rest = None;
+        Some(s)
+    })
+}
+
+/// Iterate over the key=value pairs of the ';' separated GFF3 attribute column
+fn gff3_attributes(attrs: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    Fields::new(attrs, b';').filter_map(|a| {
+        let a = a.trim_ascii();
+        let i = memchr(b'=', a)?;
+        Some((&a[..i], &a[i + 1..]))
+    })
+}
+
 fn data_error(s: &[u8]) -> io::Error {
     io::Error::new(io::ErrorKind::InvalidData, String::from_utf8_lossy(s))
 }
@@ -100,7 +496,60 @@ pub struct GtfExon<'a> {
     pub start: i64,
     pub end: i64,
     pub strand: Strand,
-    pub id: &'a [u8]
+    pub id: &'a [u8],
+    pub name: Option<&'a [u8]>,
+    pub biotype: Option<&'a [u8]>,
+    /// the gene_source attribute, or the source column of the exon
+    pub source: Option<&'a [u8]>,
+    pub transcript_id: Option<&'a [u8]>,
+    /// position of the exon in the transcript, from the 5' end
+    pub exon_number: Option<u32>,
+    /// the attribute column of a GTF exon, empty for the other formats
+    pub attrs: &'a [u8],
+}
+
+impl<'a> GtfExon<'a> {
+    /// An exon of an interval file, without gene name, biotype, source and transcript
+    fn interval(seq_name: &'a [u8], start: i64, end: i64, strand: Strand, id: &'a [u8]) -> GtfExon<'a> {
+        GtfExon { seq_name, start, end, strand, id, name: None, biotype: None, source: None, transcript_id: None, exon_number: None, attrs: b"" }
+    }
+
+    /// Whether the exon has a tag attribute with this value, GENCODE lists a tag attribute per tag
+    pub fn has_tag(&self, tag: &[u8]) -> bool {
+        attributes(self.attrs).any(|(key, value)| key == b"tag" && value == tag)
+    }
+
+    /// The problems of the attributes of a GTF exon that the parser works around: unbalanced
+    /// quotes, attributes without a value, empty values and a missing transcript_id
+    pub fn attribute_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.attrs.is_empty() {
+            return problems;
+        }
+        if self.attrs.iter().filter(|&&c| c == b'"').count() % 2 == 1 {
+            problems.push("unbalanced quotes".to_string());
+        }
+        for field in attribute_fields(self.attrs).map(<[u8]>::trim_ascii).filter(|f| !f.is_empty()) {
+            if !field.iter().any(u8::is_ascii_whitespace) {
+                problems.push(format!("attribute {} without a value", String::from_utf8_lossy(field)));
+            }
+        }
+        for (key, value) in attributes(self.attrs) {
+            if value.is_empty() {
+                problems.push(format!("empty {}", String::from_utf8_lossy(key)));
+            }
+        }
+        if self.transcript_id.is_none() {
+            problems.push("no transcript_id".to_string());
+        }
+        problems
+    }
+
+    /// The transcript_support_level of the transcript, 1 is the best supported. None for NA
+    pub fn support_level(&self) -> Option<u8> {
+        // e.g. "1 (assigned to previous version 5)"
+        attributes(self.attrs).find(|(key, _)| *key == b"transcript_support_level").and_then(|(_, value)| atoi(value))
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -144,25 +593,199 @@ mod test {
 
         //gene entry
         assert!(matches!(reader.read_record(&mut record), Ok(n) if n > 0));
-        assert!(matches!(record.parse_exon(), Ok(None)));
+        assert!(matches!(record.parse_exon(&[b"gene_id"]), Ok(None)));
 
         //transcript entry
         assert!(matches!(reader.read_record(&mut record), Ok(n) if n > 0));
-        assert!(matches!(record.parse_exon(), Ok(None)));
+        assert!(matches!(record.parse_exon(&[b"gene_id"]), Ok(None)));
 
         // two exons
         assert!(matches!(reader.read_record(&mut record), Ok(n) if n > 0));
-        assert!(matches!(record.parse_exon(), Ok(Some(r)) if r.id == b"ENSG00000112592"));
+        assert!(matches!(record.parse_exon(&[b"gene_id"]), Ok(Some(r)) if r.id == b"ENSG00000112592"));
 
         assert!(matches!(reader.read_record(&mut record), Ok(n) if n > 0));
-        assert!(matches!(record.parse_exon(), Ok(Some(r)) if r.id == b"ENSG00000112592"));
+        assert!(matches!(record.parse_exon(&[b"gene_id"]), Ok(Some(r)) if r.id == b"ENSG00000112592" && r.name == Some(b"TBP") && r.biotype == Some(b"protein_coding")));
+        assert!(matches!(record.parse_exon(&[b"gene_id"]), Ok(Some(r)) if r.transcript_id == Some(b"ENST00000421512") && r.exon_number == Some(2)));
+        // the gene_source attribute, not the source column of the exon
+        assert!(matches!(record.parse_exon(&[b"gene_id"]), Ok(Some(r)) if r.source == Some(b"ensembl_havana")));
+        assert_eq!(record.gene_key(&[b"gene_id"]), Some(&b"ENSG00000112592"[..]));
+        assert_eq!(record.gene_key(&[&b"gene"[..], b"gene_name"]), Some(&b"TBP"[..]));
+        assert!(matches!(record.parse_exon(&[b"gene_id"]), Ok(Some(r)) if r.has_tag(b"mRNA_end_NF") && !r.has_tag(b"basic") && r.support_level() == Some(1)));
 
         // and a CDS
         assert!(matches!(reader.read_record(&mut record), Ok(n) if n > 0));
-        assert!(matches!(record.parse_exon(), Ok(None)));
+        assert!(matches!(record.parse_exon(&[b"gene_id"]), Ok(None)));
 
         //EOF
         assert!(matches!(reader.read_record(&mut record), Ok(0)));
     }
+
+    const GFF3: &str = "##gff-version 3
+6\tensembl_havana\tgene\t170554302\t170572870\t.\t+\t.\tID=gene:ENSG00000112592;Name=TBP;biotype=protein_coding;gene_id=ENSG00000112592;version=13
+6\thavana\tmRNA\t170554302\t170566957\t.\t+\t.\tID=transcript:ENST00000421512;Parent=gene:ENSG00000112592;Name=TBP-203;biotype=protein_coding;transcript_id=ENST00000421512
+6\thavana\texon\t170556882\t170557083\t.\t+\t.\tParent=transcript:ENST00000421512;Name=ENSE00001510679;exon_id=ENSE00001510679;rank=2
+6\thavana\texon\t170557090\t170557100\t.\t+\t.\tParent=transcript:ENST00000000000;rank=3
+##FASTA
+>6
+ACGT
+";
+
+    #[test]
+    fn read_gff3() {
+        let index = Gff3Index::read(Cursor::new(GFF3)).unwrap();
+        let mut reader = GtfReader::new(Cursor::new(GFF3));
+        let mut record = GtfRecord::new();
+
+        // gene and mRNA
+        for _ in 0..2 {
+            assert!(matches!(reader.read_record(&mut record), Ok(n) if n > 0));
+            assert!(matches!(record.parse_gff3_exon(&index), Ok(None)));
+        }
+
+        assert!(matches!(reader.read_record(&mut record), Ok(n) if n > 0));
+        let exon = record.parse_gff3_exon(&index).unwrap().unwrap();
+        assert_eq!(exon.id, b"ENSG00000112592");
+        assert_eq!((exon.name, exon.biotype), (Some(&b"TBP"[..]), Some(&b"protein_coding"[..])));
+        assert_eq!((exon.transcript_id, exon.exon_number), (Some(&b"ENST00000421512"[..]), Some(2)));
+
+        // the parent transcript is missing
+        assert!(matches!(reader.read_record(&mut record), Ok(n) if n > 0));
+        assert!(record.parse_gff3_exon(&index).is_err());
+
+        // the sequences are not read
+        assert!(matches!(reader.read_record(&mut record), Ok(0)));
+    }
+
+    #[test]
+    fn read_intervals() {
+        let mut record = GtfRecord::new();
+        let mut reader = GtfReader::new(Cursor::new("track name=genes\n6\t100\t200\tTBP\t0\t-\n"));
+        reader.read_record(&mut record).unwrap();
+        assert!(matches!(record.parse_bed_exon(), Ok(None)));
+        reader.read_record(&mut record).unwrap();
+        let exon = record.parse_bed_exon().unwrap().unwrap();
+        assert_eq!((exon.seq_name, exon.start, exon.end, exon.strand, exon.id), (&b"6"[..], 101, 200, Strand::Reverse, &b"TBP"[..]));
+
+        let mut reader = GtfReader::new(Cursor::new("GeneID\tChr\tStart\tEnd\tStrand\nTBP\t6\t101\t200\t.\nTBP\t6\tx\t200\t+\n"));
+        reader.read_record(&mut record).unwrap();
+        assert!(matches!(record.parse_saf_exon(), Ok(None)));
+        reader.read_record(&mut record).unwrap();
+        let exon = record.parse_saf_exon().unwrap().unwrap();
+        assert_eq!((exon.seq_name, exon.start, exon.end, exon.strand, exon.id), (&b"6"[..], 101, 200, Strand::Unknown, &b"TBP"[..]));
+        reader.read_record(&mut record).unwrap();
+        assert!(record.parse_saf_exon().is_err());
+
+        let mut reader = GtfReader::new(Cursor::new("@HD\tVN:1.6\n@SQ\tSN:6\tLN:1000\n6\t101\t200\t-\trRNA\n"));
+        for _ in 0..2 {
+            reader.read_record(&mut record).unwrap();
+            assert!(matches!(record.parse_interval_list_exon(), Ok(None)));
+        }
+        reader.read_record(&mut record).unwrap();
+        let exon = record.parse_interval_list_exon().unwrap().unwrap();
+        assert_eq!((exon.seq_name, exon.start, exon.end, exon.strand, exon.id), (&b"6"[..], 101, 200, Strand::Reverse, &b"rRNA"[..]));
+    }
+
+    #[test]
+    fn read_gene_pred() {
+        let mut record = GtfRecord::new();
+        let mut reader = GtfReader::new(Cursor::new("TBP\tNM_003194\tchr6\t-\t100\t400\t150\t350\t2\t100,300,\t200,400,\n"));
+        reader.read_record(&mut record).unwrap();
+        let exons = record.parse_gene_pred_exons(true).unwrap();
+        let blocks: Vec<_> = exons.iter().map(|e| (e.id, e.transcript_id, e.start, e.end, e.exon_number)).collect();
+        let (id, t) = (&b"TBP"[..], Some(&b"NM_003194"[..]));
+        assert_eq!(blocks, vec![(id, t, 101, 200, Some(2)), (id, t, 301, 400, Some(1))]);
+        assert_eq!((exons[0].seq_name, exons[0].strand), (&b"chr6"[..], Strand::Reverse));
+
+        // genePred with a bin column and the gene name in name2
+        let mut reader = GtfReader::new(Cursor::new("585\tNM_003194\tchr6\t+\t100\t400\t150\t350\t2\t100,300,\t200,400,\t0\tTBP\n"));
+        reader.read_record(&mut record).unwrap();
+        let exons = record.parse_gene_pred_exons(false).unwrap();
+        assert_eq!(exons.iter().map(|e| (e.id, e.start, e.exon_number)).collect::<Vec<_>>(), vec![(id, 101, Some(1)), (id, 301, Some(2))]);
+
+        let mut reader = GtfReader::new(Cursor::new("NM_003194\tchr6\t+\t100\t400\t150\t350\t2\t100,300,\t200,\n"));
+        reader.read_record(&mut record).unwrap();
+        assert!(record.parse_gene_pred_exons(false).is_err());
+    }
+
+    #[test]
+    fn attribute_pairs() {
+        let attrs = b"gene_id \"G1\"; gene_name \"A\";  level 2;\n";
+        let pairs: Vec<_> = attributes(attrs).collect();
+        assert_eq!(pairs, vec![(&b"gene_id"[..], &b"G1"[..]), (b"gene_name", b"A"), (b"level", b"2")]);
+        // tabs and extra whitespace, a quoted ';', no final ';'
+        let attrs = b" gene_name\tA ;note \"a; b\";  gene_id   G1 \r\n";
+        let pairs: Vec<_> = attributes(attrs).collect();
+        assert_eq!(pairs, vec![(&b"gene_name"[..], &b"A"[..]), (b"note", b"a; b"), (b"gene_id", b"G1")]);
+
+        let fields: Vec<_> = Fields::new(b"a\t\tb", b'\t').collect();
+        assert_eq!(fields, vec![&b"a"[..], b"", b"b"]);
+    }
+
+    #[test]
+    fn attribute_problems() {
+        let mut record = GtfRecord::new();
+        record.clear_buf_mut().extend_from_slice(b"1\tsrc\texon\t10\t20\t.\t+\t.\tgene_id \"G\"; transcript_id \"T\";\n");
+        assert!(record.parse_exon(&[b"gene_id"]).unwrap().unwrap().attribute_problems().is_empty());
+        record.clear_buf_mut().extend_from_slice(b"1\tsrc\texon\t10\t20\t.\t+\t.\tgene_id \"G\"; basic; gene_name \"\"; note \"a;\n");
+        let exon = record.parse_exon(&[b"gene_id"]).unwrap().unwrap();
+        assert_eq!(exon.attribute_problems(), vec!["unbalanced quotes", "attribute basic without a value", "empty gene_name", "no transcript_id"]);
+    }
+
+    // malformed lines found by feeding truncated and mangled records to the parser, none of them
+    // may panic
+    const MALFORMED: &[&[u8]] = &[
+        b"",
+        b"\t",
+        b"1",
+        b"1\tsrc\texon",
+        b"1\tsrc\texon\t10",
+        b"1\tsrc\texon\t10\t20\t.",
+        b"1\tsrc\texon\t10\t20\t.\t+\t.",
+        b"1\tsrc\texon\t10\t20\t.\tx\t.\tgene_id \"G\";",
+        b"1\tsrc\texon\tten\t20\t.\t+\t.\tgene_id \"G\";",
+        b"1\tsrc\texon\t0\t20\t.\t+\t.\tgene_id \"G\";",
+        b"1\tsrc\texon\t-9223372036854775808\t20\t.\t+\t.\tgene_id \"G\";",
+        b"1\tsrc\texon\t10\t9223372036854775807\t.\t+\t.\tgene_id \"G\";",
+        b"1\tsrc\texon\t10\t99999999999999999999\t.\t+\t.\tgene_id \"G\";",
+        b"1\tsrc\texon\t10\t20\t.\t+\t.\tgene_id",
+        b"1\tsrc\texon\t10\t20\t.\t+\t.\tgene_id\"G\";",
+        b"1\tsrc\texon\t10\t20\t.\t+\t.\t;;;",
+        b"1\tsrc\texon\t10\t20\t.\t+\t.\tgene_name \"A\";",
+    ];
+
+    #[test]
+    fn malformed_records() {
+        for line in MALFORMED {
+            let record = GtfRecord(line.to_vec());
+            assert!(record.parse_exon(&[b"gene_id"]).is_err(), "accepted {}", record);
+        }
+
+        // odd but valid: empty id, unquoted values, CRLF line endings
+        let record = GtfRecord(b"1\tsrc\texon\t10\t20\t.\t+\t.\tgene_id \"\"; gene_name A;\r\n".to_vec());
+        assert!(matches!(record.parse_exon(&[b"gene_id"]), Ok(Some(r)) if r.id.is_empty() && r.name == Some(b"A")));
+        // a tab within the attributes
+        let record = GtfRecord(b"1\tsrc\texon\t10\t20\t.\t+\t.\tgene_name \"A\";\tgene_id G\n".to_vec());
+        assert!(matches!(record.parse_exon(&[b"gene_id"]), Ok(Some(r)) if r.id == b"G" && r.name == Some(b"A")));
+    }
+
+    #[test]
+    fn gene_id_attributes() {
+        let record = GtfRecord(b"NC_000913.3\tRefSeq\texon\t190\t255\t.\t+\t.\tlocus_tag \"b0001\"; gene \"thrL\";".to_vec());
+        assert!(matches!(record.parse_exon(&[&b"gene_id"[..], b"gene", b"locus_tag"]), Ok(Some(r)) if r.id == b"thrL"));
+        assert!(matches!(record.parse_exon(&[&b"locus_tag"[..], b"gene"]), Ok(Some(r)) if r.id == b"b0001"));
+        let err = record.parse_exon(&[&b"gene_id"[..], b"ID"]).unwrap_err();
+        assert_eq!(err.to_string(), "No gene_id or ID in attributes");
+    }
+
+    #[test]
+    fn skip_blank_lines() {
+        let gtf = "\n#comment\n  \r\n1\tsrc\texon\t10\t20\t.\t+\t.\tgene_id \"G\";\n\n";
+        let mut reader = GtfReader::new(Cursor::new(gtf));
+        let mut record = GtfRecord::new();
+
+        assert!(matches!(reader.read_record(&mut record), Ok(n) if n > 0));
+        assert!(matches!(record.parse_exon(&[b"gene_id"]), Ok(Some(r)) if r.id == b"G"));
+        assert!(matches!(reader.read_record(&mut record), Ok(0)));
+    }
 }
 
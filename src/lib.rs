@@ -0,0 +1,834 @@
+//! Counts reads on genes. The `gensum` binary is a thin wrapper around [`run`]. Other crates can
+//! reuse the counting engine with [`quantify_bam_with`], which passes every assigned read with its
+//! gene to a callback, e.g. to count variants or editing sites without reading the BAM again.
+//! Custom filters, e.g. on tags or barcodes, implement [`ReadFilter`] and are passed to
+//! [`quantify_reader_filtered`].
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+use anyhow::{bail, Context, Result};
+
+mod gtf;
+mod app;
+mod selftest;
+#[doc(hidden)]
+pub mod fuzzing;
+#[doc(hidden)]
+pub mod benchmark;
+
+pub use rust_htslib;
+#[doc(hidden)]
+pub use selftest::{golden_output, golden_sections};
+pub use app::{AnnotFormat, BadExon, Count, FilterDecision, ReadFilter, SCHEMA_VERSION, GeneMap, QuantMethod, ReadAssignments, QcFailure, QcGate, QcMetric, ReadMappings, SingletonPolicy, Strandness, Stratum, SummaryStyle, quantify_bam, quantify_bam_with, quantify_reader_with, quantify_reader_filtered, write_biotype_counts, write_count_matrix};
+
+/// The configuration, parsed from the command line. Library users can create it with
+/// `Args::parse_from` or `Args::try_parse_from` from the `clap::Parser` trait.
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None, max_term_width = 120)]
+#[clap(subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
+pub struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// The bam file to quantify, or a sam or cram file. Use '-' to read from stdin. Remote files
+    /// are read from http(s):// urls, or s3:// when built with the 's3' feature. With several
+    /// files the output is a matrix with a column per sample, named after the file
+    #[clap(short, long, value_name = "FILE", required = true, num_args = 1..)]
+    bam: Vec<PathBuf>,
+
+    /// Count the reads of each flowcell lane, by the PU field of the @RG header lines, and write
+    /// a matrix with a column per lane named '<sample>_<PU>'. Reads without a read group are
+    /// counted in a '<sample>_unassigned_lane' column
+    #[clap(long, conflicts_with = "verify_sample")]
+    split_by_lane: bool,
+
+    /// The sample name in the header line of the output, by default the bam file name without
+    /// the extension. With '--split-by-lane' the prefix of the lane columns
+    #[clap(long, value_name = "NAME")]
+    sample_name: Option<String>,
+
+    /// The reference FASTA to decode a cram file. Without it the reference is found through the
+    /// REF_PATH and REF_CACHE environment variables, or the UR tags of the cram header
+    #[clap(long, value_name = "FASTA")]
+    reference: Option<PathBuf>,
+
+    /// The .gtf reference transcriptome file, or a GFF3, BED, SAF, refFlat, genePred or Picard
+    /// interval_list file. This file may be (b)gzipped. Use '-' to read it from stdin, with
+    /// '--annot-format' for other formats than GTF. Several files, e.g. of a host genome, a virus
+    /// and spike-ins, are merged and the summary reports the reads assigned to each file
+    #[clap(short, long, value_name = "FILE", required = true, num_args = 1..)]
+    gtf: Vec<PathBuf>,
+
+    /// Only load the contigs of the BAM header from a bgzipped and tabix indexed '--gtf' file (with
+    /// a .tbi or .csi index), to save time and memory on large annotations. Genes on other contigs
+    /// are left out of the output. Not for GFF3 files
+    #[clap(long)]
+    indexed_gtf: bool,
+
+    /// The format of the '--gtf' file. 'auto' reads files ending in .gff3 or .gff as GFF3, and
+    /// .bed, .saf, .refflat, .genepred and .interval_list in those formats. The name column of a
+    /// BED file or interval_list, the GeneID of a SAF file and the gene name of a refFlat file are
+    /// the gene ids
+    #[clap(long, value_name = "FORMAT", default_value = "auto")]
+    annot_format: AnnotFormat,
+
+    /// How to handle GTF exons with the end before the start: 'skip' the exon, 'swap' the start
+    /// and end, or 'fail'. The affected exons are reported with their gene_id
+    #[clap(long, value_name = "POLICY", default_value = "skip")]
+    bad_exon: BadExon,
+
+    /// Only load the GTF exons of transcripts with this tag attribute, e.g. 'basic' for the
+    /// GENCODE basic set, or 'Ensembl_canonical'. Genes without such a transcript are left out
+    #[clap(long, value_name = "TAG")]
+    tag_filter: Option<String>,
+
+    /// Only load the GTF exons of transcripts with a transcript_support_level from 1 to N,
+    /// transcripts with level NA or without the attribute are left out
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..=5))]
+    min_tsl: Option<u8>,
+
+    /// Comma separated gene biotypes to load from the GTF, e.g. 'protein_coding,lncRNA'. Genes of
+    /// other biotypes are left out, reads on them are 'nohit'
+    #[clap(long, value_name = "BIOTYPES", value_delimiter = ',')]
+    include_biotype: Vec<String>,
+
+    /// Comma separated gene biotypes to leave out of the GTF, e.g. 'rRNA,Mt_rRNA'
+    #[clap(long, value_name = "BIOTYPES", value_delimiter = ',')]
+    exclude_biotype: Vec<String>,
+
+    /// Comma separated GTF attributes holding the gene id, the first attribute an exon has is
+    /// used, e.g. 'gene_id,gene,locus_tag' for annotations without gene_id. Reads can be counted
+    /// on any key, e.g. '--id-attr gene_name' or '--id-attr transcript_id'. Genes of the same
+    /// name on several chromosomes are counted as one gene, with a warning
+    #[clap(long, alias = "id-attr", value_name = "ATTRS", value_delimiter = ',', default_value = "gene_id")]
+    id_attrs: Vec<String>,
+
+    /// Remove the version from Ensembl and GENCODE gene ids, e.g. ENSG00000141510.17 is counted and
+    /// written as ENSG00000141510. Genes whose ids only differ in the version are merged. Only
+    /// ids of letters followed by digits have a version
+    #[clap(long)]
+    strip_gene_version: bool,
+
+    /// Quantify a bam file aligned to the transcriptome, e.g. by STAR --quantMode
+    /// TranscriptomeSAM. The tab separated table maps the transcript ids of the bam header to
+    /// gene ids of the '--gtf' file. The alignments of a read must be adjacent, coordinate sorted
+    /// files are rejected. A read is assigned when its primary and secondary alignments are on
+    /// transcripts of one gene
+    #[clap(long, value_name = "FILE")]
+    tx2gene: Option<PathBuf>,
+
+    /// The output file (TXT), default: stdout
+    #[clap(short, long, value_name = "FILE")]
+    out: Option<PathBuf>,
+
+    /// Do not count, write the alignments to '--out' as bam with an XF tag holding the gene_id of
+    /// the read or the category it would be reported in, like htseq-count. The mates of a pair are
+    /// tagged separately
+    #[clap(long)]
+    annotate_only: bool,
+
+    /// Name the summary categories like htseq-count or featureCounts, for tools that parse their
+    /// output. All categories of the other tool are written, with 0 where gensum has no
+    /// equivalent. Gensum categories without an equivalent (e.g. qc_failed), the schema comment
+    /// and the optional summary lines are left out. The featureCounts summary starts with a
+    /// 'Status' line naming the samples
+    #[clap(long, value_name = "STYLE", default_value = "gensum")]
+    summary_style: SummaryStyle,
+
+    /// The quantification method, 'strict' or 'union'. 'union' counts all genes that overlap any
+    /// part of the reads, 'strict' requires the read to map within the exon boundaries
+    #[clap(long, short, default_value = "union")]
+    method: QuantMethod,
+
+    /// The RNA library strandness [F]orward, [R]everse or [U]nstranded
+    #[clap(long, short, default_value = "U")]
+    strandness: Strandness,
+
+    /// A table to rename the chromosomes of the GTF to the contigs of the BAM, e.g. for accession
+    /// style names. Two columns separated by a tab: the GTF name and the BAM name
+    #[clap(long, value_name = "FILE")]
+    chr_alias: Option<PathBuf>,
+
+    /// Only match BAM contigs and GTF chromosomes with the same name. By default a contig that is
+    /// not in the GTF is matched by its UCSC or Ensembl name, e.g. chr1 and 1, chrM and MT
+    #[clap(long)]
+    exact_chr_names: bool,
+
+    /// The minimum required mapping quality required for a read to be counted
+    #[clap(long, short = 'q', value_name = "0-255", default_value_t = 10)]
+    mapq: u8,
+
+    /// Also count read (pairs) marked as (optical) duplicate, default excludes duplicates.
+    /// Requires a bam files processed with a markduplicates tool
+    #[clap(long, short = 'd')]
+    usedups: bool,
+
+    /// Do not count paired-end reads that have only 1 mapped end (singletons). Default allows one
+    /// mapped end.  Only affects paired-end reads.
+    #[clap(long = "nosingle")]
+    nosingletons: bool,
+
+    /// Comma separated list of contigs (e.g. decoys, chrEBV, spike-ins) whose reads are reported
+    /// as 'contaminant' instead of being counted
+    #[clap(long, value_name = "CONTIGS", value_delimiter = ',')]
+    contaminant_contigs: Vec<String>,
+
+    /// Number of BAM contigs to list when reporting reads on contigs that are not in the GTF
+    #[clap(long, value_name = "N", default_value_t = 5)]
+    notingtf_top: usize,
+
+    /// File with housekeeping genes (gene_id or gene_name, one per line) whose counts and CPMs
+    /// are appended to the summary
+    #[clap(long, value_name = "FILE")]
+    qc_genes: Option<PathBuf>,
+
+    /// Comma separated gene_id prefixes designating spike-ins, e.g. 'ERCC-'. Appends the
+    /// spike-in counts and a size factor to the summary: the spike-in counts of a sample divided
+    /// by their geometric mean over the samples, as the size factors of DESeq, or NA without
+    /// spike-in reads. Dividing gene counts by the size factor normalizes them to the spike-ins
+    #[clap(long, value_name = "PREFIXES", value_delimiter = ',')]
+    spike_ins: Vec<String>,
+
+    /// Append the summed counts of the female and male marker genes to the summary, as a quick
+    /// check for sample swaps
+    #[clap(long)]
+    sex_check: bool,
+
+    /// Comma separated female marker genes for '--sex-check', by gene_id or gene_name
+    #[clap(long, value_name = "GENES", value_delimiter = ',', default_value = "XIST")]
+    female_genes: Vec<String>,
+
+    /// Comma separated male (chrY) marker genes for '--sex-check', by gene_id or gene_name
+    #[clap(long, value_name = "GENES", value_delimiter = ',',
+        default_value = "RPS4Y1,DDX3Y,UTY,KDM5D,EIF1AY,USP9Y,ZFY")]
+    male_genes: Vec<String>,
+
+    /// How to handle paired reads whose mate is not found as a primary alignment, e.g. because
+    /// the mate was filtered on mapping quality or is only present as a secondary alignment
+    #[clap(long, value_name = "POLICY", default_value = "skip")]
+    singleton_policy: SingletonPolicy,
+
+    /// Distribute ambiguous reads over the genes they overlap, in proportion to the aligned bases
+    /// on the exons of each gene, instead of counting them as 'ambiguous'. Gene counts are then
+    /// written with two decimals
+    #[clap(long)]
+    weight_by_overlap: bool,
+
+    /// Write the uncertainty of the counts of genes with a share of ambiguous reads from
+    /// '--weight-by-overlap': the ambiguous fraction of the count, a standard deviation and a 95%
+    /// interval
+    #[clap(long, value_name = "FILE", requires = "weight_by_overlap")]
+    uncertainty_report: Option<PathBuf>,
+
+    /// Write the reads left after each filter stage, from all alignment records through the
+    /// unmapped, secondary, duplicate, mapping quality and other read filters to the assigned
+    /// reads, with percentages. Written as JSON when the file name ends in .json
+    #[clap(long, value_name = "FILE")]
+    funnel: Option<PathBuf>,
+
+    /// Write the observed and expected distributions of the GC percentage of the first 50 bases of
+    /// the assigned reads, the expected from 50 base windows along the genes in the '--reference'
+    /// FASTA, and add the 'gc_bias' distance between them to the summary
+    #[clap(long, value_name = "FILE", requires = "reference")]
+    gc_bias: Option<PathBuf>,
+
+    /// Fail with exit code 3 when the sample matches a QC expression, e.g. 'assigned_frac<0.5 ||
+    /// rrna_frac>0.4'. Conditions compare total, assigned, assigned_frac, unmapped_frac,
+    /// dup_frac, low_mapq_frac, rrna_frac or mito_frac with a number and are combined with '&&'
+    /// and '||'. The outputs are written first, the failed conditions are printed on stderr
+    #[clap(long, value_name = "EXPR")]
+    fail_if: Option<QcGate>,
+
+    /// Assign read pairs as one fragment using the aligned segments of both mates, instead of
+    /// requiring both mates to be assigned to the same gene. Pairs where one mate is outside the
+    /// exons are then counted, and ambiguous fragments are counted as 'ambiguous'. The ambiguity
+    /// resolution and report options are not applied to merged fragments
+    #[clap(long)]
+    merge_mates: bool,
+
+    /// Assign reads that are ambiguous between overlapping genes to the gene with the shortest
+    /// genomic span, e.g. a snoRNA instead of its host gene
+    #[clap(long)]
+    prefer_shorter_gene: bool,
+
+    /// Assign reads that are ambiguous between overlapping genes to the gene whose exons fully
+    /// contain the read, if there is exactly one such gene. Only affects '--method union'
+    #[clap(long)]
+    prefer_containing: bool,
+
+    /// Count reads on the full gene locus, from the first to the last exon base, instead of on the
+    /// exons, for nascent RNA (GRO-seq, PRO-seq, TT-seq). Use with a stranded '--strandness'
+    #[clap(long)]
+    nascent: bool,
+
+    /// Exclude N bases at the 5' end of the gene locus with '--nascent', e.g. to leave out
+    /// promoter-proximal paused polymerase
+    #[clap(long = "exclude-5p", value_name = "N", default_value_t = 0, requires = "nascent",
+        value_parser = clap::value_parser!(i64).range(0..))]
+    exclude_5p: i64,
+
+    /// Exclude N bases at the 3' end of the gene locus with '--nascent'
+    #[clap(long = "exclude-3p", value_name = "N", default_value_t = 0, requires = "nascent",
+        value_parser = clap::value_parser!(i64).range(0..))]
+    exclude_3p: i64,
+
+    /// Count reads on a window of N bases up- and downstream of the transcription start site of
+    /// each gene instead of on its exons, e.g. to measure promoter accessibility in ATAC-seq. The
+    /// TSS is the first base of the gene. Use '--merge-mates' to count paired-end fragments
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(i64).range(0..))]
+    tss_window: Option<i64>,
+
+    /// Count the gene body and the '--tss-window' in one pass, and write both counts as two
+    /// columns per gene. The summary categories refer to the gene body counts
+    #[clap(long, requires = "tss_window")]
+    dual_counts: bool,
+
+    /// Write the assigned reads per gene as an extra column next to the fragment counts, a counted
+    /// pair adds both mates. For tools that expect read level counts of paired-end data
+    #[clap(long)]
+    read_counts: bool,
+
+    /// Write the GC fraction of the exons of each gene, from the '--reference' FASTA, as the last
+    /// column. For GC bias correction, e.g. with cqn. Genes without sequence get NA
+    #[clap(long, requires = "reference")]
+    gc_content: bool,
+
+    /// Report reads that do not overlap any exon but lie within the span of a gene as 'intronic'
+    /// instead of 'nohit'
+    #[clap(long)]
+    intronic: bool,
+
+    /// Write the adjacent genes on the same strand that are bridged by read pairs with a mate
+    /// assigned to each gene (readthrough transcription). These pairs are counted as ambiguous_pair
+    #[clap(long, value_name = "FILE")]
+    readthrough_report: Option<PathBuf>,
+
+    /// Write a ranked list of gene pairs linked by read pairs with a mate on different chromosomes
+    /// or far apart on the same chromosome, with the supporting read names, as fusion candidates
+    #[clap(long, value_name = "FILE")]
+    fusion_report: Option<PathBuf>,
+
+    /// Minimum distance between two genes on the same chromosome for '--fusion-report'
+    #[clap(long, value_name = "BASES", default_value_t = 100_000)]
+    fusion_min_distance: i64,
+
+    /// Limit the memory used to buffer paired reads until their mate is found, including the mates
+    /// kept for '--fusion-report'. When the limit is exceeded the unpaired reads are spilled to
+    /// temporary files
+    #[clap(long, value_name = "MB")]
+    max_memory: Option<usize>,
+
+    /// Minimum base quality of the bases counted by '--min-hq-overlap'
+    #[clap(long, value_name = "Q", default_value_t = 20)]
+    min_baseq: u8,
+
+    /// Require at least N aligned bases with a base quality of at least '--min-baseq' within the
+    /// exons of the assigned gene. Reads with fewer are reported as 'low_hq_overlap'. Default 0
+    /// disables the check. Not applied to '--merge-mates' fragments
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    min_hq_overlap: u32,
+
+    /// Reject reads with an intron (N in the cigar) longer than N bases, e.g. aligner artifacts
+    /// like 1M1000000N1M that would reach a distant gene. The reads are reported as 'long_intron'
+    #[clap(long, value_name = "N")]
+    max_intron_span: Option<u32>,
+
+    /// Check that the introns (N in the cigar) of an assigned read are not longer than the longest
+    /// intron of the transcripts of its gene, plus '--intron-tolerance'. Reads with a longer intron
+    /// are reported as 'intron_mismatch', e.g. spurious splice junctions of noisy long reads.
+    /// Genes without a transcript_id or without introns are not checked. Not applied to
+    /// '--merge-mates' fragments
+    #[clap(long)]
+    validate_introns: bool,
+
+    /// The allowed excess for '--validate-introns', as a fraction of the longest intron of the
+    /// gene, from 0 to 10
+    #[clap(long, value_name = "FRACTION", default_value_t = 0.1, requires = "validate_introns", value_parser = intron_tolerance)]
+    intron_tolerance: f64,
+
+    /// Write the assigned counts summed per gene biotype (gene_biotype or gene_type attribute),
+    /// with a column per sample
+    #[clap(long, value_name = "FILE")]
+    biotype_counts: Option<PathBuf>,
+
+    /// Write the assigned counts summed per gene source, the gene_source attribute or the source
+    /// column of the GTF, e.g. to see the counts on custom additions to the annotation
+    #[clap(long, value_name = "FILE")]
+    source_counts: Option<PathBuf>,
+
+    /// A table of genes and groups, e.g. gene families or paralog clusters. Two columns separated
+    /// by a tab: gene_id or gene_name, and the group
+    #[clap(long, value_name = "FILE", requires = "group_counts")]
+    group_table: Option<PathBuf>,
+
+    /// Write the assigned counts summed per group of '--group-table'
+    #[clap(long, value_name = "FILE", requires = "group_table")]
+    group_counts: Option<PathBuf>,
+
+    /// Write a per gene report of the exon boundaries violated by reads that were rejected in
+    /// strict mode, split by the 5' and 3' side of the exon. Only used with '--method strict'
+    #[clap(long, value_name = "FILE")]
+    boundary_report: Option<PathBuf>,
+
+    /// Write the coverage of the counted reads along each gene in '--coverage-bins' bins from 5' to
+    /// 3', as mean depth per million assigned reads, for plotting gene body profiles
+    #[clap(long, value_name = "FILE")]
+    coverage_profile: Option<PathBuf>,
+
+    /// Number of bins along the gene span for '--coverage-profile'
+    #[clap(long, value_name = "N", default_value_t = 100, value_parser = clap::value_parser!(u16).range(1..))]
+    coverage_bins: u16,
+
+    /// Write the reads per window along each gene, from 5' to 3'. The windows divide the
+    /// collapsed exon model of the gene, its exons merged and joined without the introns, e.g. to
+    /// find degradation, internal priming or truncated transcripts
+    #[clap(long, value_name = "FILE")]
+    gene_windows: Option<PathBuf>,
+
+    /// The size of the '--gene-windows' in bases of the collapsed exon model
+    #[clap(long, value_name = "N", default_value_t = 500, value_parser = clap::value_parser!(u32).range(1..))]
+    gene_window_size: u32,
+
+    /// Write the genes with a much higher fraction of antisense reads than the rest of the sample,
+    /// e.g. genes annotated on the wrong strand in a custom GTF. Requires a stranded library
+    #[clap(long, value_name = "FILE")]
+    strand_report: Option<PathBuf>,
+
+    /// Write the counts per gene split on the '--stratify' properties as a long table, to compare
+    /// filter settings after a single pass. Only counted reads are included, use '--usedups' and
+    /// '-q 0' to include the duplicates and all mapping qualities
+    #[clap(long, value_name = "FILE", requires = "stratify")]
+    stratified_counts: Option<PathBuf>,
+
+    /// Comma separated read properties for '--stratified-counts': 'dup' (duplicate flag),
+    /// 'strand' (sense or antisense to the gene) and 'mapq' (mapping quality bucket)
+    #[clap(long, value_name = "PROPERTIES", value_delimiter = ',', requires = "stratified_counts")]
+    stratify: Vec<Stratum>,
+
+    /// Write the counted reads and their aligned bases on the exons of each gene, e.g. for
+    /// normalization or capture efficiency QC that is less sensitive to the read length
+    #[clap(long, value_name = "FILE")]
+    exonic_bases: Option<PathBuf>,
+
+    /// Write the number of counted reads on the first, internal and last exons of each gene, from
+    /// the exon_number attribute, as a signal of alternative TSS and polyA site usage
+    #[clap(long, value_name = "FILE")]
+    exon_usage: Option<PathBuf>,
+
+    /// Write the deduplicated exon intervals reads are counted on as BED, e.g. to load in IGV next
+    /// to the BAM file. With '--nascent' or '--tss-window' these are the gene regions counted on
+    #[clap(long, value_name = "FILE")]
+    export_model: Option<PathBuf>,
+
+    /// Count N genes again from the indexed bam file, fetching only the reads on each gene, and
+    /// stop with an error if the counts differ from the counts of the full pass. The genes are a
+    /// fixed pseudo-random sample. Requires a position sorted and indexed bam file
+    #[clap(long, value_name = "N")]
+    verify_sample: Option<usize>,
+
+    /// Write the position, name and gene of every counted read, sorted by position. A file name
+    /// ending in .gz is bgzip compressed and gets a tabix index. The reads are kept in memory
+    /// until they are written, or in sorted temporary files beyond '--max-memory'
+    #[clap(long, value_name = "FILE")]
+    read_assignments: Option<PathBuf>,
+
+    /// Compare the gene counts with a table of expected counts, gene_id or gene_name and the
+    /// count separated by a tab, e.g. of a simulation. Reports the deviations and exits with an
+    /// error when a gene deviates more than '--truth-tolerance'
+    #[clap(long, value_name = "FILE")]
+    truth: Option<PathBuf>,
+
+    /// The maximum deviation of a gene from '--truth', relative to its expected count
+    #[clap(long, value_name = "FRACTION", default_value_t = 0.05, requires = "truth")]
+    truth_tolerance: f64,
+
+    /// Assign mapped reads without a cigar ('*') as an ungapped alignment of the read length
+    /// starting at the read position. By default these reads are counted as 'malformed_cigar'
+    #[clap(long)]
+    cigar_fallback: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Quantify a bundled synthetic dataset with several option combinations and compare the
+    /// results with the expected output, to verify the installation
+    Selftest,
+    /// Write the genes and exons of an annotation file as a binary index. Pass the index to
+    /// '--gtf' to skip parsing the annotation, e.g. in batch runs. '--exon-usage' and
+    /// '--validate-introns' need the annotation file
+    Index {
+        /// The annotation file, see '--gtf' of the main command
+        #[clap(short, long, value_name = "FILE")]
+        gtf: PathBuf,
+
+        /// The index file to write
+        #[clap(short, long, value_name = "FILE")]
+        out: PathBuf,
+
+        /// The format of the '--gtf' file, see '--annot-format' of the main command
+        #[clap(long, value_name = "FORMAT", default_value = "auto")]
+        annot_format: AnnotFormat,
+
+        /// How to handle exons with the end before the start, see '--bad-exon' of the main command
+        #[clap(long, value_name = "POLICY", default_value = "skip")]
+        bad_exon: BadExon,
+    },
+    /// Write the records of the expressed genes of a GTF file, the genes with at least
+    /// '--min-count' reads in a gensum output, e.g. for a smaller reference for assembly or
+    /// visualization
+    SubsetGtf {
+        /// The GTF file, may be (b)gzipped
+        #[clap(short, long, value_name = "FILE")]
+        gtf: PathBuf,
+
+        /// The gensum output with the gene counts, or a count matrix of several samples
+        #[clap(short, long, value_name = "FILE")]
+        counts: PathBuf,
+
+        /// The minimum number of reads of an expressed gene, in any sample of a count matrix
+        #[clap(long, value_name = "N", default_value_t = 1.0)]
+        min_count: f64,
+
+        /// The GTF attributes of the gene ids of the counts, see '--id-attrs' of the main command
+        #[clap(long, alias = "id-attr", value_name = "ATTRS", value_delimiter = ',', default_value = "gene_id")]
+        id_attrs: Vec<String>,
+
+        /// The gene ids of the counts are without version, see '--strip-gene-version' of the main
+        /// command
+        #[clap(long)]
+        strip_gene_version: bool,
+
+        /// The GTF file to write, default: stdout
+        #[clap(short, long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+    /// Check an annotation file and list every problem with its line number: records that can not
+    /// be parsed, zero length or inverted exons, duplicated exons of a transcript, genes on several
+    /// chromosomes or strands and GTF attribute problems. Fails when there are problems
+    ValidateGtf {
+        /// The annotation file, see '--gtf' of the main command
+        #[clap(short, long, value_name = "FILE")]
+        gtf: PathBuf,
+
+        /// The format of the '--gtf' file, see '--annot-format' of the main command
+        #[clap(long, value_name = "FORMAT", default_value = "auto")]
+        annot_format: AnnotFormat,
+
+        /// The problem list to write, default: stdout
+        #[clap(short, long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+    /// Write the exons of each gene split into numbered disjoint exon bins, like the flattened
+    /// annotation of DEXSeq. Count the bins of the GTF with '--id-attr exon_id', or write them as SAF
+    Flatten {
+        /// The annotation file, see '--gtf' of the main command
+        #[clap(short, long, value_name = "FILE")]
+        gtf: PathBuf,
+
+        /// The format of the '--gtf' file, see '--annot-format' of the main command
+        #[clap(long, value_name = "FORMAT", default_value = "auto")]
+        annot_format: AnnotFormat,
+
+        /// How to handle exons with the end before the start, see '--bad-exon' of the main command
+        #[clap(long, value_name = "POLICY", default_value = "skip")]
+        bad_exon: BadExon,
+
+        /// Write the exon bins as SAF, with the bin ids as GeneID, instead of GTF
+        #[clap(long)]
+        saf: bool,
+
+        /// The file to write, default: stdout
+        #[clap(short, long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+}
+
+/// Parse the '--intron-tolerance', a fraction of the longest intron of a gene from 0 to 10
+fn intron_tolerance(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(f) if (0.0..=10.0).contains(&f) => Ok(f),
+        Ok(_) => Err("must be a fraction from 0 to 10".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Run gensum with the command line arguments of the process
+pub fn run() -> Result<()> {
+    run_with(Args::parse())
+}
+
+/// Run gensum with parsed arguments, e.g. of a selftest case
+fn run_with(args: Args) -> Result<()> {
+    let bam = match (&args.command, args.bam.first(), args.gtf.is_empty()) {
+        (Some(Command::Selftest), _, _) => return selftest::run(),
+        (Some(Command::Index { gtf, out, annot_format, bad_exon }), _, _) => {
+            let gm = GeneMap::from_annotation(gtf, *annot_format, *bad_exon)?;
+            return gm.write_index(File::create(out)?);
+        },
+        (Some(Command::SubsetGtf { gtf, counts, min_count, id_attrs, strip_gene_version, out }), _, _) => {
+            return match out {
+                Some(f) => app::subset_gtf(gtf, counts, *min_count, id_attrs, *strip_gene_version, File::create(f)?),
+                None => app::subset_gtf(gtf, counts, *min_count, id_attrs, *strip_gene_version, io::stdout().lock()),
+            };
+        },
+        (Some(Command::ValidateGtf { gtf, annot_format, out }), _, _) => {
+            let problems = match out {
+                Some(f) => app::validate_annotation(gtf, *annot_format, File::create(f)?)?,
+                None => app::validate_annotation(gtf, *annot_format, io::stdout().lock())?,
+            };
+            if problems > 0 {
+                bail!("{} has {} problems", gtf.display(), problems);
+            }
+            return Ok(());
+        },
+        (Some(Command::Flatten { gtf, annot_format, bad_exon, saf, out }), _, _) => {
+            let gm = GeneMap::from_annotation(gtf, *annot_format, *bad_exon)?;
+            return match out {
+                Some(f) => gm.write_flattened(File::create(f)?, *saf),
+                None => gm.write_flattened(io::stdout().lock(), *saf),
+            };
+        },
+        (None, Some(bam), false) => bam,
+        _ => bail!("--bam and --gtf are required without a subcommand"),
+    };
+    let stdin = Path::new("-");
+    if args.gtf.iter().any(|g| g == stdin) && args.bam.iter().any(|b| b == stdin) {
+        bail!("--bam and --gtf can not both be read from stdin");
+    }
+    if args.verify_sample.is_some() && args.bam.iter().any(|b| b == stdin) {
+        bail!("--verify-sample fetches the genes from an indexed bam file, it can not be read from stdin");
+    }
+    if args.fail_if.as_ref().is_some_and(|gate| gate.uses(QcMetric::RrnaFrac)) && args.exclude_biotype.iter().any(|b| b.contains("rRNA")) {
+        eprintln!("Warning: --exclude-biotype removes rRNA genes, rrna_frac of --fail-if is 0");
+    }
+    if args.tx2gene.is_some() {
+        let genomic = [
+            ("--indexed-gtf", args.indexed_gtf),
+            ("--merge-mates", args.merge_mates),
+            ("--weight-by-overlap", args.weight_by_overlap),
+            ("--prefer-shorter-gene", args.prefer_shorter_gene),
+            ("--prefer-containing", args.prefer_containing),
+            ("--nascent", args.nascent),
+            ("--tss-window", args.tss_window.is_some()),
+            ("--intronic", args.intronic),
+            ("--min-hq-overlap", args.min_hq_overlap > 0),
+            ("--validate-introns", args.validate_introns),
+            ("--read-assignments", args.read_assignments.is_some()),
+            ("--readthrough-report", args.readthrough_report.is_some()),
+            ("--fusion-report", args.fusion_report.is_some()),
+            ("--boundary-report", args.boundary_report.is_some()),
+            ("--coverage-profile", args.coverage_profile.is_some()),
+            ("--gene-windows", args.gene_windows.is_some()),
+            ("--exon-usage", args.exon_usage.is_some()),
+            ("--exonic-bases", args.exonic_bases.is_some()),
+            ("--strand-report", args.strand_report.is_some()),
+            ("--verify-sample", args.verify_sample.is_some()),
+        ];
+        if let Some((name, _)) = genomic.iter().find(|r| r.1) {
+            bail!("{} needs genomic alignments, it can not be used with --tx2gene", name);
+        }
+    }
+    // tables are checked before the annotation is loaded and the reads are counted
+    let group_table = args.group_table.as_ref().map(app::read_gene_table).transpose()
+        .context("Invalid --group-table")?;
+    let truth = args.truth.as_ref().map(app::read_truth).transpose()
+        .context("Invalid --truth")?;
+    let gm = GeneMap::with_config_merged(&args.gtf, &args)?;
+    if let Some(table) = group_table.as_ref() {
+        if !table.iter().any(|(gene, _)| gm.find_gene(gene.as_bytes()).is_some()) {
+            bail!("None of the genes of --group-table is in the GTF");
+        }
+    }
+    if let Some(truth) = truth.as_ref() {
+        let missing: Vec<_> = truth.iter().filter(|(gene, _)| gm.find_gene(gene.as_bytes()).is_none()).map(|(gene, _)| gene.as_str()).collect();
+        if !missing.is_empty() {
+            bail!("{} genes of --truth are not in the GTF, e.g. {}", missing.len(), missing[..missing.len().min(3)].join(", "));
+        }
+    }
+    if let Some(f) = args.export_model.as_ref() {
+        gm.write_model(File::create(f)?)?;
+    }
+    if args.annotate_only {
+        if args.bam.len() > 1 || args.tx2gene.is_some() {
+            bail!("--annotate-only tags the alignments of one genomic bam file");
+        }
+        return app::annotate_bam(bam, &args, &gm, args.out.as_deref());
+    }
+    if args.bam.len() > 1 && args.sample_name.is_some() {
+        bail!("--sample-name names the sample of one --bam file, several files are named after their file names");
+    }
+    if args.bam.len() > 1 || args.split_by_lane {
+        return run_samples(&args, &gm);
+    }
+
+    let mut assignments = ReadAssignments::with_config(&args);
+    let res = if args.read_assignments.is_some() {
+        let mut spill_error = None;
+        let res = quantify_bam_with(bam, &args, &gm, |r, gene| {
+            if let Err(e) = assignments.push(r, gene, &gm) {
+                spill_error.get_or_insert(e);
+            }
+        })?;
+        if let Some(e) = spill_error {
+            return Err(e);
+        }
+        res
+    } else {
+        quantify_bam(bam, &args, &gm)?
+    };
+    // the reports of several samples take a list of named samples
+    let samples = [(app::sample_name(bam, &args), res)];
+    let res = &samples[0].1;
+    if let Some(n) = args.verify_sample {
+        app::verify_sample(bam, &args, &gm, res, n)?;
+    }
+
+    if let Some(f) = args.out.as_ref() {
+        let o = File::create(f)?;
+        res.write(o, &gm, &args)?;
+    } else {
+        let stdout = io::stdout();
+        let stdout = stdout.lock();
+        res.write(stdout, &gm, &args)?;
+    }
+
+    if let Some(f) = args.read_assignments.as_ref() {
+        assignments.write(f, &gm)?;
+    }
+
+    if let Some(f) = args.biotype_counts.as_ref() {
+        write_biotype_counts(File::create(f)?, &samples, &gm)?;
+    }
+
+    if let Some(f) = args.source_counts.as_ref() {
+        res.write_source_counts(File::create(f)?, &gm)?;
+    }
+
+    if let (Some(table), Some(f)) = (group_table.as_ref(), args.group_counts.as_ref()) {
+        res.write_group_counts(File::create(f)?, &gm, table)?;
+    }
+
+    if let Some(f) = args.readthrough_report.as_ref() {
+        res.write_readthrough_report(File::create(f)?, &gm)?;
+    }
+
+    if let Some(f) = args.fusion_report.as_ref() {
+        res.write_fusion_report(File::create(f)?, &gm)?;
+    }
+
+    if let Some(f) = args.boundary_report.as_ref() {
+        res.write_boundary_report(File::create(f)?, &gm)?;
+    }
+
+    if let Some(f) = args.coverage_profile.as_ref() {
+        res.write_coverage_profile(File::create(f)?, &gm)?;
+    }
+
+    if let Some(f) = args.gene_windows.as_ref() {
+        res.write_gene_windows(File::create(f)?, &gm)?;
+    }
+
+    if let Some(f) = args.exon_usage.as_ref() {
+        res.write_exon_usage(File::create(f)?, &gm)?;
+    }
+
+    if let Some(f) = args.exonic_bases.as_ref() {
+        res.write_exonic_bases(File::create(f)?, &gm)?;
+    }
+
+    if let Some(f) = args.stratified_counts.as_ref() {
+        res.write_stratified_counts(File::create(f)?, &gm, &args.stratify)?;
+    }
+
+    if let Some(f) = args.strand_report.as_ref() {
+        res.write_strand_report(File::create(f)?, &gm)?;
+    }
+
+    if let Some(f) = args.uncertainty_report.as_ref() {
+        res.write_uncertainty_report(File::create(f)?, &gm)?;
+    }
+
+    if let Some(f) = args.funnel.as_ref() {
+        let json = matches!(f.extension(), Some(e) if e == "json");
+        res.write_funnel(File::create(f)?, &args, json)?;
+    }
+
+    if let Some(f) = args.gc_bias.as_ref() {
+        res.write_gc_bias(File::create(f)?, &gm)?;
+        let bias = res.gc_bias(&gm);
+        if bias > app::GC_BIAS_WARNING {
+            eprintln!("Warning: strong GC bias {:.3}, the GC content of the reads differs from the expressed genes", bias);
+        }
+    }
+
+    if let Some(truth) = truth.as_ref() {
+        res.compare_truth(&gm, truth, args.truth_tolerance)?;
+    }
+
+    if let Some(reason) = args.fail_if.as_ref().and_then(|gate| res.qc_failure(gate, &gm)) {
+        return Err(QcFailure(vec![(samples[0].0.clone(), reason)]).into());
+    }
+
+    Ok(())
+}
+
+/// Quantify several bam files with the same GTF, and write the counts as a matrix
+fn run_samples(args: &Args, gm: &GeneMap) -> Result<()> {
+    let reports = [
+        ("--read-assignments", args.read_assignments.is_some()),
+        ("--source-counts", args.source_counts.is_some()),
+        ("--group-counts", args.group_counts.is_some()),
+        ("--readthrough-report", args.readthrough_report.is_some()),
+        ("--fusion-report", args.fusion_report.is_some()),
+        ("--boundary-report", args.boundary_report.is_some()),
+        ("--coverage-profile", args.coverage_profile.is_some()),
+        ("--gene-windows", args.gene_windows.is_some()),
+        ("--exon-usage", args.exon_usage.is_some()),
+        ("--exonic-bases", args.exonic_bases.is_some()),
+        ("--stratified-counts", args.stratified_counts.is_some()),
+        ("--strand-report", args.strand_report.is_some()),
+        ("--uncertainty-report", args.uncertainty_report.is_some()),
+        ("--funnel", args.funnel.is_some()),
+        ("--gc-bias", args.gc_bias.is_some()),
+        ("--truth", args.truth.is_some()),
+    ];
+    if let Some((name, _)) = reports.iter().find(|r| r.1) {
+        bail!("{} writes a single sample, it can not be used with several --bam files or --split-by-lane", name);
+    }
+
+    let mut samples = Vec::with_capacity(args.bam.len());
+    for bam in &args.bam {
+        if args.split_by_lane {
+            samples.extend(app::quantify_lanes(bam, args, gm)?);
+            continue;
+        }
+        let res = quantify_bam(bam, args, gm)?;
+        if let Some(n) = args.verify_sample {
+            app::verify_sample(bam, args, gm, &res, n)?;
+        }
+        samples.push((app::sample_name(bam, args), res));
+    }
+
+    if let Some(f) = args.out.as_ref() {
+        write_count_matrix(File::create(f)?, &samples, gm, args)?;
+    } else {
+        let stdout = io::stdout();
+        write_count_matrix(stdout.lock(), &samples, gm, args)?;
+    }
+
+    if let Some(f) = args.biotype_counts.as_ref() {
+        write_biotype_counts(File::create(f)?, &samples, gm)?;
+    }
+
+    if let Some(gate) = args.fail_if.as_ref() {
+        let failed: Vec<_> = samples.iter()
+            .filter_map(|(name, res)| res.qc_failure(gate, gm).map(|reason| (name.clone(), reason)))
+            .collect();
+        if !failed.is_empty() {
+            return Err(QcFailure(failed).into());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+
+use crate::Args;
+
+// The bundled dataset and expected outputs, shared with the integration tests in tests/golden.rs
+const GTF: &str = include_str!("../tests/data/mini.gtf");
+const SAM: &str = include_str!("../tests/data/mini.sam");
+const GOLDEN: &str = include_str!("../tests/golden/mini.txt");
+
+/// Split a golden file into (options, expected output) sections. Each section starts with a
+/// '### <options>' line, followed by the summary gensum writes with those options. When the
+/// options have an `{out}` placeholder for the file of a report, the contents of that file follow
+/// the summary after a '## {out}' line. Also used by the integration tests in tests/golden.rs.
+pub fn golden_sections(s: &str) -> Vec<(&str, String)> {
+    let mut sections: Vec<(&str, String)> = Vec::new();
+    for line in s.lines() {
+        if let Some(opts) = line.strip_prefix("### ") {
+            sections.push((opts.trim(), String::new()));
+        } else if let Some((_, out)) = sections.last_mut() {
+            if !line.is_empty() {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+    }
+    sections
+}
+
+/// The output of a golden section as in `golden_sections`, from the summary and the contents of
+/// the report of the `{out}` placeholder, if any
+pub fn golden_output(mut summary: String, report: Option<&str>) -> String {
+    if let Some(report) = report {
+        summary.push_str("## {out}\n");
+        for line in report.lines().filter(|l| !l.is_empty()) {
+            summary.push_str(line);
+            summary.push('\n');
+        }
+    }
+    summary
+}
+
+/// Quantify the bundled dataset with every option combination of the golden file and compare
+/// the summaries and reports with the expected output
+pub fn run() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("gensum-selftest-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let res = run_in(&dir);
+    let _ = fs::remove_dir_all(&dir);
+    let failed = res?;
+
+    if failed > 0 {
+        return Err(anyhow!("selftest: {} case(s) failed", failed));
+    }
+    eprintln!("selftest: all cases passed");
+    Ok(())
+}
+
+fn run_in(dir: &std::path::Path) -> Result<usize> {
+    let gtf = dir.join("mini.gtf");
+    let sam = dir.join("mini.sam");
+    fs::write(&gtf, GTF)?;
+    fs::write(&sam, SAM)?;
+
+    let summary = dir.join("summary.txt");
+    let report = dir.join("report.txt");
+    let mut failed = 0;
+    for (opts, expected) in golden_sections(GOLDEN) {
+        let mut argv = vec!["gensum".into(), "-b".into(), sam.clone().into_os_string(),
+            "-g".into(), gtf.clone().into_os_string(), "-o".into(), summary.clone().into_os_string()];
+        argv.extend(opts.split_whitespace().map(|o| if o == "{out}" { report.clone().into_os_string() } else { o.into() }));
+        crate::run_with(Args::try_parse_from(argv)?)?;
+
+        let report = if opts.contains("{out}") { Some(fs::read_to_string(&report)?) } else { None };
+        let out = golden_output(fs::read_to_string(&summary)?, report.as_deref());
+
+        if out == expected {
+            eprintln!("ok\t{}", opts);
+        } else {
+            eprintln!("FAILED\t{}", opts);
+            failed += 1;
+        }
+    }
+    Ok(failed)
+}
@@ -0,0 +1,32 @@
+//! The library API: every counted read is passed to the callback of `quantify_bam_with`.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use clap::Parser;
+use gensum::{Args, GeneMap, quantify_bam_with};
+
+#[test]
+fn assigned_read_callback() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    let args = Args::try_parse_from(["gensum", "-b", "mini.sam", "-g", "mini.gtf"]).unwrap();
+    let gm = GeneMap::with_config(dir.join("mini.gtf"), &args).unwrap();
+
+    // count the fragments passed to the callback per gene
+    let mut names: HashMap<Vec<u8>, Vec<Vec<u8>>> = HashMap::new();
+    let res = quantify_bam_with(dir.join("mini.sam"), &args, &gm, |r, gene| {
+        names.entry(gm.hit_name(gene).unwrap().clone()).or_default().push(r.qname().to_vec());
+    }).unwrap();
+
+    let mut out = Vec::new();
+    res.write(&mut out, &gm, &args).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.lines().next(), Some(format!("#gensum_schema={}", gensum::SCHEMA_VERSION).as_str()));
+    for line in out.lines().filter(|l| l.starts_with('G')) {
+        let (gene, count) = line.split_once('\t').unwrap();
+        let mut fragments = names.remove(gene.as_bytes()).unwrap_or_default();
+        fragments.dedup();
+        assert_eq!(fragments.len().to_string(), count, "gene {}", gene);
+    }
+    assert!(names.is_empty());
+}
@@ -0,0 +1,973 @@
+//! Golden output tests on the bundled synthetic dataset in tests/data.
+//!
+//! Every '### <options>' section in tests/golden/mini.txt is run through the gensum binary and the
+//! summary is compared with the section contents, followed by the report written to the `{out}`
+//! placeholder of the options. After an intended behaviour change, regenerate
+//! the expected output with `GENSUM_BLESS=1 cargo test --test golden` and review the diff.
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn data(f: &str) -> String {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(f).to_string_lossy().into_owned()
+}
+
+/// A directory for the files of one test, removed at the end of the test, also when it fails
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("gensum-test-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn gensum(opts: &str) -> String {
+    gensum_with("tests/data/mini.gtf", opts)
+}
+
+fn gensum_with(gtf: &str, opts: &str) -> String {
+    let out = run_with(gtf, &opts.split_whitespace().collect::<Vec<_>>());
+    assert!(out.status.success(), "gensum {} failed: {}", opts, String::from_utf8_lossy(&out.stderr));
+    String::from_utf8(out.stdout).unwrap()
+}
+
+/// Run gensum on the bundled reads and annotation with the options, successful or not
+fn run(opts: &[&str]) -> Output {
+    run_with("tests/data/mini.gtf", opts)
+}
+
+/// Run gensum on the bundled reads with an annotation, relative to the crate or absolute
+fn run_with(gtf: &str, opts: &[&str]) -> Output {
+    run_args(&[&["-b", &data("tests/data/mini.sam"), "-g", &data(gtf)], opts].concat())
+}
+
+/// Run gensum with the arguments, e.g. of a subcommand
+fn run_args(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_gensum")).args(args).output().expect("failed to run gensum")
+}
+
+/// The output without the `assigned_<annotation>` lines, which are named after the annotation file
+fn without_annotation_names(summary: &str) -> String {
+    summary.lines().filter(|l| !l.starts_with("assigned_")).map(|l| format!("{}\n", l)).collect()
+}
+
+/// Run a golden section, with the report of the `{out}` placeholder of its options in `dir`
+fn golden_run(opts: &str, dir: &Path) -> String {
+    let report = dir.join("report.txt");
+    let summary = gensum(&opts.replace("{out}", &report.to_string_lossy()));
+    let report = opts.contains("{out}").then(|| fs::read_to_string(&report).unwrap());
+    gensum::golden_output(summary, report.as_deref())
+}
+
+#[test]
+fn golden_summaries() {
+    let golden = data("tests/golden/mini.txt");
+    let expected = fs::read_to_string(&golden).unwrap();
+
+    let sections = gensum::golden_sections(&expected);
+    assert!(!sections.is_empty());
+
+    let dir = TempDir::new("golden");
+    if std::env::var_os("GENSUM_BLESS").is_some() {
+        let mut blessed = String::new();
+        for (opts, _) in &sections {
+            blessed.push_str(&format!("### {}\n{}\n", opts, golden_run(opts, &dir)));
+        }
+        fs::write(&golden, blessed).unwrap();
+        return;
+    }
+
+    for (opts, out) in &sections {
+        assert_eq!(&golden_run(opts, &dir), out, "summary differs for options '{}'", opts);
+    }
+}
+
+#[test]
+fn gff3_annotation() {
+    // mini.gff3 has the genes of mini.gtf in a feature hierarchy
+    for opts in ["", "--strandness F --method strict", "--merge-mates --biotype-counts /dev/null"] {
+        assert_eq!(gensum_with("tests/data/mini.gff3", opts), gensum(opts), "options '{}'", opts);
+    }
+}
+
+#[test]
+fn compressed_annotation() {
+    use std::io::Write;
+    use gensum::rust_htslib::bgzf;
+
+    // bgzip output is a series of gzip members, the format is detected from the magic bytes
+    let dir = TempDir::new("compressed");
+    for name in ["mini.gtf", "mini.gff3"] {
+        let gz = dir.join(format!("{}.gz", name));
+        let mut w = bgzf::Writer::from_path(&gz).unwrap();
+        w.write_all(&fs::read(data(&format!("tests/data/{}", name))).unwrap()).unwrap();
+        drop(w);
+        assert_eq!(gensum_with(&gz.to_string_lossy(), ""), gensum(""), "{}", name);
+    }
+}
+
+#[test]
+fn indexed_annotation() {
+    use std::io::Write;
+    use gensum::rust_htslib::{bgzf, htslib};
+
+    // a gene on a contig that is not in the BAM header is not loaded from the index
+    let dir = TempDir::new("indexed");
+    let gz = dir.join("indexed.gtf.gz");
+    let mut w = bgzf::Writer::from_path(&gz).unwrap();
+    w.write_all(&fs::read(data("tests/data/mini.gtf")).unwrap()).unwrap();
+    w.write_all(b"9\tgensum\texon\t101\t200\t.\t+\t.\tgene_id \"GZ\";\n").unwrap();
+    drop(w);
+    let c_path = std::ffi::CString::new(gz.to_string_lossy().as_bytes()).unwrap();
+    assert_eq!(unsafe { htslib::tbx_index_build(c_path.as_ptr(), 0, &htslib::tbx_conf_gff) }, 0);
+
+    let all = gensum_with(&gz.to_string_lossy(), "");
+    let indexed = gensum_with(&gz.to_string_lossy(), "--indexed-gtf");
+
+    assert!(all.contains("\nGZ\t0\n"));
+    assert_eq!(without_annotation_names(&indexed), without_annotation_names(&gensum("")));
+}
+
+#[test]
+fn binary_index() {
+    let dir = TempDir::new("index");
+    let index = dir.join("mini.idx");
+    let out = run_args(&["index", "-g", &data("tests/data/mini.gtf"), "-o", &index.to_string_lossy()]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    for opts in ["", "--strandness F --method strict", "--intronic --tss-window 50 --dual-counts", "--biotype-counts /dev/null"] {
+        assert_eq!(gensum_with(&index.to_string_lossy(), opts), gensum(opts), "options '{}'", opts);
+    }
+    let out = run_with(&index.to_string_lossy(), &["--exon-usage", "/dev/null"]);
+    assert!(!out.status.success());
+    let out = run_with(&index.to_string_lossy(), &["--indexed-gtf"]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("--indexed-gtf"), "{}", String::from_utf8_lossy(&out.stderr));
+
+    // an index of another version is reported as such, not parsed as a GTF
+    let mut old = fs::read(&index).unwrap();
+    old[7] = 1;
+    fs::write(&index, old).unwrap();
+    let out = run_with(&index.to_string_lossy(), &[]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("create it again"), "{}", String::from_utf8_lossy(&out.stderr));
+}
+
+#[test]
+fn interval_annotation() {
+    // mini.bed has the exons of mini.gtf, converted to SAF here
+    let dir = TempDir::new("intervals");
+    let saf = dir.join("mini.saf");
+    let mut table = String::from("GeneID\tChr\tStart\tEnd\tStrand\n");
+    for line in fs::read_to_string(data("tests/data/mini.bed")).unwrap().lines().skip(1) {
+        let f: Vec<&str> = line.split('\t').collect();
+        table.push_str(&format!("{}\t{}\t{}\t{}\t{}\n", f[3], f[0], f[1].parse::<i64>().unwrap() + 1, f[2], f[5]));
+    }
+    fs::write(&saf, table).unwrap();
+
+    // and to refFlat, one transcript per gene
+    let refflat = saf.with_extension("txt");
+    let mut genes: Vec<(String, Vec<Vec<String>>)> = Vec::new();
+    for line in fs::read_to_string(data("tests/data/mini.bed")).unwrap().lines().skip(1) {
+        let f: Vec<String> = line.split('\t').map(String::from).collect();
+        match genes.last_mut() {
+            Some((id, exons)) if *id == f[3] => exons.push(f),
+            _ => genes.push((f[3].clone(), vec![f])),
+        }
+    }
+    let table: String = genes.iter().map(|(id, exons)| {
+        let (starts, ends): (Vec<_>, Vec<_>) = exons.iter().map(|e| (format!("{},", e[1]), format!("{},", e[2]))).unzip();
+        format!("{}\tT{}\t{}\t{}\t0\t0\t0\t0\t{}\t{}\t{}\n", id, id, exons[0][0], exons[0][5], exons.len(), starts.concat(), ends.concat())
+    }).collect();
+    fs::write(&refflat, table).unwrap();
+
+    for opts in ["", "--strandness F --method strict"] {
+        assert_eq!(gensum_with("tests/data/mini.bed", opts), gensum(opts), "options '{}'", opts);
+        assert_eq!(gensum_with(&saf.to_string_lossy(), opts), gensum(opts), "options '{}'", opts);
+        let refflat_opts = format!("{} --annot-format refflat", opts);
+        assert_eq!(gensum_with(&refflat.to_string_lossy(), &refflat_opts), gensum(opts), "options '{}'", opts);
+    }
+}
+
+#[test]
+fn cram_input() {
+    use gensum::rust_htslib::bam::{self, Read};
+
+    let dir = TempDir::new("cram");
+    let fasta = dir.join("ref.fa");
+    let seq = "ACGT".repeat(2500);
+    fs::write(&fasta, format!(">1\n{}\n>2\n{}\n", seq, seq)).unwrap();
+
+    let cram = dir.join("mini.cram");
+    let mut sam = bam::Reader::from_path(data("tests/data/mini.sam")).unwrap();
+    let header = bam::Header::from_template(sam.header());
+    {
+        let mut w = bam::Writer::from_path(&cram, &header, bam::Format::Cram).unwrap();
+        w.set_reference(&fasta).unwrap();
+        for r in sam.records() {
+            w.write(&r.unwrap()).unwrap();
+        }
+    }
+
+    let out = run_args(&["-b", &cram.to_string_lossy(), "-g", &data("tests/data/mini.gtf"), "--reference", &fasta.to_string_lossy()]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), gensum(""));
+}
+
+#[test]
+fn stdin_input() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_gensum"))
+        .args(["-b", "-", "-g", &data("tests/data/mini.gtf"), "--sample-name", "mini"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(&fs::read(data("tests/data/mini.sam")).unwrap()).unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), gensum(""));
+}
+
+#[test]
+fn stdin_annotation() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    // a GTF file is read once, also for the introns of '--validate-introns', a GFF3 file twice,
+    // and the exon usage needs another pass
+    for (name, opts) in [("mini.gtf", vec![]), ("mini.gtf", vec!["--validate-introns"]), ("mini.gtf", vec!["--exon-usage", "/dev/null"]),
+        ("mini.gff3", vec!["--annot-format", "gff3"])] {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_gensum"))
+            .args(["-b", &data("tests/data/mini.sam"), "-g", "-"])
+            .args(&opts)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&fs::read(data(&format!("tests/data/{}", name))).unwrap()).unwrap();
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert_eq!(without_annotation_names(&String::from_utf8(out.stdout).unwrap()), without_annotation_names(&gensum("")), "{}", name);
+    }
+}
+
+#[test]
+fn read_assignments() {
+    use gensum::rust_htslib::tbx::{self, Read};
+
+    let dir = TempDir::new("assignments");
+    let (plain, gz) = (dir.join("reads.txt"), dir.join("reads.txt.gz"));
+    gensum(&format!("--read-assignments {}", plain.display()));
+    gensum(&format!("--read-assignments {}", gz.display()));
+
+    let lines: Vec<String> = fs::read_to_string(&plain).unwrap().lines().skip(1).map(String::from).collect();
+    let starts: Vec<i64> = lines.iter().map(|l| l.split('\t').nth(1).unwrap().parse().unwrap()).collect();
+    assert!(starts.windows(2).all(|w| w[0] <= w[1]));
+
+    // the reads on exon 2 of GA, from the index
+    let mut reader = tbx::Reader::from_path(&gz).unwrap();
+    let tid = reader.tid("1").unwrap();
+    reader.fetch(tid, 300, 400).unwrap();
+    let mut fetched = Vec::new();
+    let mut line = Vec::new();
+    while reader.read(&mut line).unwrap() {
+        fetched.push(String::from_utf8(line.clone()).unwrap());
+    }
+
+    let expected: Vec<&String> = lines.iter().filter(|l| {
+        let f: Vec<i64> = l.split('\t').skip(1).take(2).map(|v| v.parse().unwrap()).collect();
+        f[0] < 400 && f[1] > 300
+    }).collect();
+    assert!(!expected.is_empty());
+    assert_eq!(fetched.iter().collect::<Vec<_>>(), expected);
+
+    // without memory every read is spilled to a file of its own, and merged again
+    let spilled = dir.join("spilled.txt");
+    gensum(&format!("--read-assignments {} --max-memory 0", spilled.display()));
+    assert_eq!(fs::read_to_string(&spilled).unwrap(), fs::read_to_string(&plain).unwrap());
+}
+
+#[test]
+fn url_input() {
+    // remote files are read through htslib, a file:// url takes the same path
+    let url = format!("file://{}", data("tests/data/mini.sam"));
+    let out = run_args(&["-b", &url, "-g", &data("tests/data/mini.gtf")]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), gensum(""));
+}
+
+#[test]
+fn truth_comparison() {
+    let dir = TempDir::new("truth");
+    let truth = dir.join("truth.txt");
+    let genes: Vec<String> = gensum("").lines().filter(|l| l.starts_with('G')).map(String::from).collect();
+    let compare = |table: &str| {
+        fs::write(&truth, table).unwrap();
+        run(&["--truth", &truth.to_string_lossy()])
+    };
+
+    let exact = compare(&(genes.join("\n") + "\n"));
+    // GA has 9 reads, 10 expected is a 10% deviation
+    let off = compare(&genes.iter().map(|l| if l.starts_with("GA\t") { "GA\t10" } else { l }).collect::<Vec<_>>().join("\n"));
+
+    assert!(exact.status.success(), "{}", String::from_utf8_lossy(&exact.stderr));
+    assert!(!off.status.success());
+    assert!(String::from_utf8_lossy(&off.stderr).contains("truth: GA\texpected 10\tobserved 9"));
+
+    // the table is checked before the reads are counted
+    for (table, error) in [("GA\tten\n", "Invalid expected count for GA: ten"), ("GA\t9\nGX\t1\n", "1 genes of --truth are not in the GTF, e.g. GX")] {
+        let out = compare(table);
+        assert!(!out.status.success());
+        assert!(out.stdout.is_empty());
+        assert!(String::from_utf8_lossy(&out.stderr).contains(error), "{}", String::from_utf8_lossy(&out.stderr));
+    }
+}
+
+#[test]
+fn qc_gate() {
+    // 18 of the 41 primary records are assigned, 43 with the secondary alignments
+    let pass = run(&["--fail-if", "assigned_frac<0.4 || total<10 && dup_frac>0"]);
+    let fail = run(&["--fail-if", "rrna_frac>0.5 || assigned_frac<0.5 && assigned>10"]);
+    let invalid = run(&["--fail-if", "assigned_fraction<0.5"]);
+
+    assert!(pass.status.success(), "{}", String::from_utf8_lossy(&pass.stderr));
+    assert_eq!(fail.status.code(), Some(3));
+    assert_eq!(fail.stdout, pass.stdout);
+    let stderr = String::from_utf8_lossy(&fail.stderr);
+    assert!(stderr.lines().any(|l| l.starts_with("qc_fail\tmini\tassigned_frac=0.439") && l.ends_with(" && assigned=18>10")), "{}", stderr);
+    assert_eq!(invalid.status.code(), Some(2));
+
+    let excluded = run(&["--fail-if", "rrna_frac>0.1", "--exclude-biotype", "rRNA,Mt_rRNA"]);
+    assert!(String::from_utf8_lossy(&excluded.stderr).contains("rrna_frac of --fail-if is 0"));
+}
+
+#[test]
+fn count_matrix() {
+    let dir = TempDir::new("matrix");
+    let other = dir.join("other.sam");
+    fs::copy(data("tests/data/mini.sam"), &other).unwrap();
+    let single = gensum("--dual-counts --tss-window 50");
+    let matrix = gensum(&format!("-b {} --dual-counts --tss-window 50", other.display()));
+    let mut lines = matrix.lines();
+    assert_eq!(lines.next(), single.lines().next());
+    assert_eq!(lines.next(), Some("gene_id\tmini\tmini_tss\tother\tother_tss"));
+    for (m, s) in lines.zip(single.lines().skip(2)) {
+        let (key, value) = s.split_once('\t').unwrap();
+        assert_eq!(m, format!("{}\t{}\t{}", key, value, value));
+    }
+    assert_eq!(matrix.lines().count(), single.lines().count());
+
+    // the same file twice would give two columns of the same name
+    let out = run(&["-b", &data("tests/data/mini.sam")]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("more than once"));
+}
+
+#[test]
+fn biotype_matrix() {
+    let dir = TempDir::new("biotype-matrix");
+    let (other, single, matrix) = (dir.join("other.sam"), dir.join("single.txt"), dir.join("matrix.txt"));
+    fs::copy(data("tests/data/mini.sam"), &other).unwrap();
+    gensum(&format!("--biotype-counts {}", single.display()));
+    gensum(&format!("-b {} --biotype-counts {}", other.display(), matrix.display()));
+
+    // a column per sample, both samples have the counts of the single sample
+    let (single, matrix) = (fs::read_to_string(single).unwrap(), fs::read_to_string(matrix).unwrap());
+    let mut lines = matrix.lines();
+    assert_eq!(single.lines().next(), Some("biotype\tmini"));
+    assert_eq!(lines.next(), Some("biotype\tmini\tother"));
+    for (m, s) in lines.zip(single.lines().skip(1)) {
+        let (biotype, count) = s.split_once('\t').unwrap();
+        assert_eq!(m, format!("{}\t{}\t{}", biotype, count, count));
+    }
+    assert_eq!(matrix.lines().count(), single.lines().count());
+    assert!(single.contains("\nlincRNA\t"), "{}", single);
+}
+
+#[test]
+fn lane_split() {
+    let dir = TempDir::new("lanes");
+    let sam = dir.join("lanes.sam");
+    let mini = fs::read_to_string(data("tests/data/mini.sam")).unwrap();
+    // the mates of a pair have the same name and lane, some reads have no read group
+    let lines: Vec<_> = mini.lines().map(|l| match l.split_once('\t') {
+        _ if l.starts_with("@HD") => format!("{}\n@RG\tID:a\tPU:FC1.1\n@RG\tID:b\tPU:FC1.2", l),
+        Some((name, _)) if name.len() % 3 == 0 => l.to_string(),
+        Some((name, _)) if !l.starts_with('@') => format!("{}\tRG:Z:{}", l, if name.len() % 2 == 0 { "a" } else { "b" }),
+        _ => l.to_string(),
+    }).collect();
+    fs::write(&sam, lines.join("\n") + "\n").unwrap();
+    let out = run_args(&["-b", &sam.to_string_lossy(), "-g", &data("tests/data/mini.gtf"), "--split-by-lane", "--sample-name", "S1"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let matrix = String::from_utf8(out.stdout).unwrap();
+    let single = gensum("");
+    let mut lines = matrix.lines().skip(1);
+    assert_eq!(lines.next(), Some("gene_id\tS1_FC1.1\tS1_FC1.2\tS1_unassigned_lane"));
+    // the lanes add up to the counts and summary of the whole sample
+    let mut unassigned = 0;
+    for line in lines {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let sum: u64 = fields[1..].iter().map(|f| f.parse::<u64>().unwrap()).sum();
+        assert!(single.contains(&format!("\n{}\t{}\n", fields[0], sum)), "{}", line);
+        unassigned += fields[3].parse::<u64>().unwrap();
+    }
+    assert!(unassigned > 0);
+}
+
+#[test]
+fn stratified_counts() {
+    let dir = TempDir::new("strata");
+    let table = dir.join("strata.txt");
+    let opts = "--usedups -q 0 --stratify dup,strand,mapq";
+    let summary = gensum(&format!("{} --stratified-counts {}", opts, table.display()));
+    let strata = fs::read_to_string(&table).unwrap();
+
+    let mut lines = strata.lines();
+    assert_eq!(lines.next(), Some("gene_id\tdup\tstrand\tmapq\tcount"));
+    let rows: Vec<Vec<&str>> = lines.map(|l| l.split('\t').collect()).collect();
+    assert!(rows.iter().any(|r| r[1] == "dup"));
+    // the strata of a gene add up to its count
+    for line in summary.lines().filter(|l| l.starts_with('G')) {
+        let (gene, count) = line.split_once('\t').unwrap();
+        let sum: u64 = rows.iter().filter(|r| r[0] == gene).map(|r| r[4].parse::<u64>().unwrap()).sum();
+        assert_eq!(sum.to_string(), count, "{}", gene);
+    }
+}
+
+#[test]
+fn uncertainty_report() {
+    let dir = TempDir::new("uncertainty");
+    let table = dir.join("uncertainty.txt");
+    let summary = gensum(&format!("--weight-by-overlap --uncertainty-report {}", table.display()));
+    let report = fs::read_to_string(&table).unwrap();
+
+    let mut lines = report.lines();
+    assert_eq!(lines.next(), Some("gene_id\tcount\tunique\tambiguous_reads\tambiguous_fraction\tsd\tlow\thigh"));
+    let rows: Vec<Vec<&str>> = lines.map(|l| l.split('\t').collect()).collect();
+    assert!(!rows.is_empty());
+    for r in rows {
+        // the counts of the summary, with an interval around them
+        assert!(summary.contains(&format!("\n{}\t{}\n", r[0], r[1])), "{}", r[0]);
+        let v: Vec<f64> = r[1..].iter().map(|v| v.parse().unwrap()).collect();
+        assert!(v[1] <= v[5] && v[5] <= v[0] && v[0] <= v[6] && v[6] <= v[1] + v[2], "{:?}", r);
+    }
+}
+
+#[test]
+fn filter_funnel() {
+    let dir = TempDir::new("funnel");
+    let table = dir.join("funnel.txt");
+    let summary = gensum(&format!("--read-counts --funnel {}", table.display()));
+    let funnel = fs::read_to_string(&table).unwrap();
+
+    let mut lines = funnel.lines();
+    assert_eq!(lines.next(), Some("stage\treads\tpercent"));
+    let stages: Vec<(&str, u64)> = lines.map(|l| {
+        let fields: Vec<_> = l.split('\t').collect();
+        (fields[0], fields[1].parse().unwrap())
+    }).collect();
+    let records = fs::read_to_string(data("tests/data/mini.sam")).unwrap().lines().filter(|l| !l.starts_with('@')).count();
+    assert_eq!(stages[0], ("total", records as u64));
+    assert!(stages.windows(2).all(|s| s[0].1 >= s[1].1), "{:?}", stages);
+    // the assigned reads are the read column of the genes
+    let reads: u64 = summary.lines().filter(|l| l.starts_with('G')).map(|l| l.rsplit('\t').next().unwrap().parse::<u64>().unwrap()).sum();
+    assert_eq!(stages.last(), Some(&("assigned", reads)));
+}
+
+#[test]
+fn annotate_only() {
+    use gensum::rust_htslib::bam::{self, Read};
+    let dir = TempDir::new("tagged");
+    let tagged = dir.join("tagged.bam");
+    let summary = gensum(&format!("--annotate-only -o {}", tagged.display()));
+    assert!(summary.is_empty());
+    let mut tags: Vec<(String, String)> = Vec::new();
+    for r in bam::Reader::from_path(&tagged).unwrap().records() {
+        let r = r.unwrap();
+        let tag = match r.aux(b"XF") {
+            Ok(bam::record::Aux::String(s)) => s.to_string(),
+            other => panic!("no XF tag: {:?}", other),
+        };
+        tags.push((String::from_utf8_lossy(r.qname()).into_owned(), tag));
+    }
+
+    let tag = |name: &str| tags.iter().find(|t| t.0 == name).map(|t| t.1.as_str());
+    assert_eq!(tags.len(), fs::read_to_string(data("tests/data/mini.sam")).unwrap().lines().filter(|l| !l.starts_with('@')).count());
+    assert_eq!(tag("se_ga_fwd"), Some("GA"));
+    assert_eq!(tag("se_dup"), Some("marked_duplicated"));
+    assert_eq!(tag("se_lowq"), Some("low_mapq"));
+    assert_eq!(tag("se_secondary"), Some("secondary_alignments"));
+    // rejected reads are tagged with the category they are counted in
+    let counted = gensum("");
+    for category in ["unmapped", "low_mapq", "secondary_alignments", "marked_duplicated"] {
+        let n = tags.iter().filter(|t| t.1 == category).count();
+        assert!(counted.contains(&format!("\n{}\t{}\n", category, n)), "{}", category);
+    }
+}
+
+#[test]
+fn annotate_cigar_fallback() {
+    use gensum::rust_htslib::bam::{self, Read};
+    let dir = TempDir::new("fallback");
+    let (input, tagged) = (dir.join("nocigar.bam"), dir.join("tagged.bam"));
+    // htslib reads a mapped SAM record without a cigar as unmapped, write it as BAM
+    let mut header = bam::Header::new();
+    header.push_record(bam::header::HeaderRecord::new(b"SQ").push_tag(b"SN", "1").push_tag(b"LN", 10000));
+    {
+        let mut w = bam::Writer::from_path(&input, &header, bam::Format::Bam).unwrap();
+        let mut r = bam::Record::new();
+        r.set(b"nocigar", None, &[b'A'; 50], &[30; 50]);
+        r.set_tid(0);
+        r.set_pos(110);
+        r.set_mapq(60);
+        r.set_mtid(-1);
+        r.set_mpos(-1);
+        r.unset_unmapped();
+        w.write(&r).unwrap();
+    }
+    let out = run_args(&["-b", &input.to_string_lossy(), "-g", &data("tests/data/mini.gtf"), "--cigar-fallback",
+        "--annotate-only", "-o", &tagged.to_string_lossy()]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    // the read is tagged with the gene of its ungapped alignment, without getting its cigar
+    let r = bam::Reader::from_path(&tagged).unwrap().records().next().unwrap().unwrap();
+    assert_eq!(r.cigar_len(), 0);
+    assert!(matches!(r.aux(b"XF"), Ok(bam::record::Aux::String("GA"))));
+}
+
+#[test]
+fn subset_gtf() {
+    let dir = TempDir::new("subset");
+    let (counts, subset) = (dir.join("counts.txt"), dir.join("subset.gtf"));
+    fs::write(&counts, gensum("")).unwrap();
+    let out = run_args(&["subset-gtf", "-g", &data("tests/data/mini.gtf"), "-c", &counts.to_string_lossy(), "--min-count", "4",
+        "-o", &subset.to_string_lossy()]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let gtf = fs::read_to_string(&subset).unwrap();
+    let summary = gensum_with(&subset.to_string_lossy(), "");
+
+    // GA has 9 reads and GB 4, the gene record of GA is kept
+    let gtf: Vec<&str> = gtf.lines().collect();
+    assert!(gtf[0].starts_with("#!genome-build"));
+    assert_eq!(gtf.len(), 6);
+    assert!(gtf[1..].iter().all(|l| l.contains("gene_id \"GA\"") || l.contains("gene_id \"GB\"")));
+    assert!(summary.contains("\nGA\t9\nGB\t4\nqc_failed"));
+
+    // the read counts are not a count column, the genes are found by gene_name
+    fs::write(&counts, gensum("--read-counts --id-attr gene_name")).unwrap();
+    let out = run_args(&["subset-gtf", "-g", &data("tests/data/mini.gtf"), "-c", &counts.to_string_lossy(), "--min-count", "9",
+        "--id-attr", "gene_name"]);
+    let gtf = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(gtf.lines().count(), 4);
+    assert!(gtf.lines().skip(1).all(|l| l.contains("gene_name \"A\"")));
+}
+
+#[test]
+fn flatten() {
+    let dir = TempDir::new("flatten");
+    let (gtf, saf) = (dir.join("flat.gtf"), dir.join("flat.saf"));
+    for (out, opts) in [(&gtf, &[][..]), (&saf, &["--saf"][..])] {
+        let status = Command::new(env!("CARGO_BIN_EXE_gensum"))
+            .args(["flatten", "-g", &data("tests/data/mini.gtf"), "-o", &out.to_string_lossy()])
+            .args(opts)
+            .output()
+            .unwrap();
+        assert!(status.status.success(), "{}", String::from_utf8_lossy(&status.stderr));
+    }
+    let genes = gensum_with(&gtf.to_string_lossy(), "");
+    let gtf_bins = gensum_with(&gtf.to_string_lossy(), "--id-attr exon_id");
+    let saf_bins = gensum_with(&saf.to_string_lossy(), "");
+    let saf = fs::read_to_string(&saf).unwrap();
+
+    // merging the exons of a gene does not change its counts
+    assert_eq!(without_annotation_names(&genes), without_annotation_names(&gensum("")));
+    assert!(saf.starts_with("GeneID\tChr\tStart\tEnd\tStrand\nGA:E001\t1\t101\t200\t+\nGA:E002\t1\t301\t400\t+\n"));
+    assert!(gtf_bins.starts_with("#gensum_schema=3\ngene_id\tmini\nGA:E001\t"));
+    assert_eq!(gtf_bins, saf_bins);
+
+    // an exon of a second transcript overlapping the first exon splits it into three parts
+    let mini = fs::read_to_string(data("tests/data/mini.gtf")).unwrap();
+    let isoform = dir.join("isoform.gtf");
+    fs::write(&isoform, mini + "1\tgensum\texon\t151\t250\t.\t+\t.\tgene_id \"GA\"; transcript_id \"TA2\";\n").unwrap();
+    let out = run_args(&["flatten", "--saf", "-g", &isoform.to_string_lossy()]);
+    assert!(String::from_utf8_lossy(&out.stdout).starts_with(
+        "GeneID\tChr\tStart\tEnd\tStrand\nGA:E001\t1\t101\t150\t+\nGA:E002\t1\t151\t200\t+\nGA:E003\t1\t201\t250\t+\nGA:E004\t1\t301\t400\t+\n"));
+}
+
+#[test]
+fn validate_gtf() {
+    let validate = |gtf: &str| run_args(&["validate-gtf", "-g", gtf]);
+    let out = validate(&data("tests/data/mini.gtf"));
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stdout));
+    assert!(out.stdout.is_empty());
+
+    let dir = TempDir::new("bad");
+    let bad = dir.join("bad.gtf");
+    let gtf = fs::read_to_string(data("tests/data/mini.gtf")).unwrap();
+    let mut lines: Vec<String> = gtf.lines().map(String::from).collect();
+    // line 3 is the first exon of GA: repeat it, invert it, move it and break it
+    let exon = lines[2].clone();
+    lines.push(exon.clone());
+    lines.push(exon.replacen("\t101\t200\t", "\t201\t200\t", 1).replace("TA1", "TA2"));
+    lines.push(exon.replacen('1', "2", 1).replace("TA1", "TA3"));
+    lines.push(exon.replacen("\t101\t", "\tten\t", 1));
+    lines.push(exon.replace(" transcript_id \"TA1\";", " basic;"));
+    // line 2 is the gene GA: break its frame, its end and its columns
+    let gene = lines[1].clone();
+    lines.push(gene.replacen("\t.\t+\t.\t", "\t.\t+\t3\t", 1));
+    lines.push(gene.replacen("\t400\t", "\t50\t", 1));
+    lines.push(gene.replacen("\t.\t+\t.\t", "\t", 1));
+    fs::write(&bad, lines.join("\n")).unwrap();
+    let out = validate(&bad.to_string_lossy());
+
+    assert!(!out.status.success());
+    let problems: Vec<(usize, String)> = String::from_utf8_lossy(&out.stdout).lines()
+        .map(|l| {
+            let f: Vec<&str> = l.split('\t').collect();
+            (f[0].parse().unwrap(), f[1].to_string())
+        })
+        .collect();
+    let expected = [(11, "duplicate_exon"), (12, "inverted_exon"), (13, "gene_chromosomes"), (14, "malformed"), (15, "attribute"), (15, "attribute"),
+        (16, "columns"), (17, "columns"), (18, "columns")];
+    assert_eq!(problems, expected.map(|(l, k)| (l, k.to_string())));
+
+    // a GFF3 file is checked the same way
+    let gff3 = dir.join("bad.gff3");
+    let mut lines: Vec<String> = fs::read_to_string(data("tests/data/mini.gff3")).unwrap().lines().map(String::from).collect();
+    // line 4 is the gene GA, the sequences at the end of the file are not checked
+    let gene = lines[3].replacen("\t.\t+\t", "\thigh\t+\t", 1);
+    lines.insert(4, gene);
+    fs::write(&gff3, lines.join("\n")).unwrap();
+    let out = validate(&gff3.to_string_lossy());
+    assert!(!out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "5\tcolumns\tinvalid score 'high'\n");
+}
+
+#[test]
+fn chr_aliases() {
+    let dir = TempDir::new("ucsc");
+    let ucsc = dir.join("ucsc.gtf");
+    let gtf = fs::read_to_string(data("tests/data/mini.gtf")).unwrap();
+    fs::write(&ucsc, gtf.lines().map(|l| if l.starts_with('#') { l.to_string() } else { format!("chr{}", l) }).collect::<Vec<_>>().join("\n")).unwrap();
+    let aliased = gensum_with(&ucsc.to_string_lossy(), "");
+    let exact = gensum_with(&ucsc.to_string_lossy(), "--exact-chr-names");
+
+    assert_eq!(without_annotation_names(&aliased), without_annotation_names(&gensum("")));
+    assert!(exact.lines().filter(|l| l.starts_with('G')).all(|l| l.ends_with("\t0")));
+}
+
+#[test]
+fn chr_alias_file() {
+    let dir = TempDir::new("alias");
+    let (gtf, aliases) = (dir.join("accession.gtf"), dir.join("aliases.txt"));
+    let mini = fs::read_to_string(data("tests/data/mini.gtf")).unwrap();
+    fs::write(&gtf, mini.lines().map(|l| l.replacen("1\t", "NC_000001.1\t", 1)).collect::<Vec<_>>().join("\n")).unwrap();
+    fs::write(&aliases, "NC_000001.1\t1\n").unwrap();
+    let renamed = gensum_with(&gtf.to_string_lossy(), &format!("--chr-alias {}", aliases.display()));
+    let unnamed = gensum_with(&gtf.to_string_lossy(), "");
+
+    assert_eq!(without_annotation_names(&renamed), without_annotation_names(&gensum("")));
+    assert!(unnamed.contains("\nGA\t0\n"));
+}
+
+#[test]
+fn counting_key() {
+    // every gene has one transcript and a gene_name
+    let mut by_id = gensum("");
+    for g in ["A", "B", "C", "D", "E", "F"] {
+        by_id = by_id.replace(&format!("\nG{}\t", g), &format!("\n{}\t", g));
+    }
+    assert_eq!(gensum("--id-attr gene_name"), by_id);
+    let by_transcript = gensum("--id-attr transcript_id");
+    assert!(by_transcript.contains("\nTA1\t9\n"), "{}", by_transcript);
+
+    // a gene of the same name on another chromosome is counted as one gene, with a warning
+    let dir = TempDir::new("key");
+    let gtf = dir.join("names.gtf");
+    let mini = fs::read_to_string(data("tests/data/mini.gtf")).unwrap();
+    fs::write(&gtf, mini + "2\tgensum\texon\t101\t200\t.\t+\t.\tgene_id \"GZ\"; transcript_id \"TZ1\"; gene_name \"A\";\n").unwrap();
+    let out = run_with(&gtf.to_string_lossy(), &["--id-attr", "gene_name"]);
+    assert!(out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("1 gene ids have exons on several chromosomes"));
+    // the single-end read on chromosome 2 adds to the 9 reads of GA
+    assert!(String::from_utf8_lossy(&out.stdout).contains("\nA\t10\n"));
+}
+
+#[test]
+fn transcript_selection() {
+    let dir = TempDir::new("select");
+    let gtf = dir.join("tagged.gtf");
+    let mini = fs::read_to_string(data("tests/data/mini.gtf")).unwrap();
+    // all transcripts are basic with level 2, except those of GB
+    let tagged: Vec<_> = mini.lines().map(|l| match l.contains("\texon\t") && !l.contains("\"GB\"") {
+        true => format!("{} tag \"CCDS\"; tag \"basic\"; transcript_support_level \"2\";", l),
+        false => format!("{} transcript_support_level \"NA\";", l),
+    }).collect();
+    fs::write(&gtf, tagged.join("\n")).unwrap();
+    let basic = gensum_with(&gtf.to_string_lossy(), "--tag-filter basic");
+    let supported = gensum_with(&gtf.to_string_lossy(), "--min-tsl 2");
+    let all = gensum_with(&gtf.to_string_lossy(), "");
+
+    assert_eq!(without_annotation_names(&all), without_annotation_names(&gensum("")));
+    assert!(!basic.contains("\nGB\t"));
+    assert!(basic.contains("\nGC\t"));
+    assert_eq!(basic, supported);
+}
+
+#[test]
+fn gc_content() {
+    let dir = TempDir::new("gc");
+    let fasta = dir.join("mini.fa");
+    // exon 1 of GA is G, the exons of GC and GD are N, the rest is A; named like UCSC
+    let mut seq = vec![b'A'; 10000];
+    seq[100..200].fill(b'G');
+    seq[2000..2300].fill(b'N');
+    let lines: Vec<_> = seq.chunks(60).map(|l| String::from_utf8_lossy(l).into_owned()).collect();
+    fs::write(&fasta, format!(">chr1 synthetic\n{}\n", lines.join("\n"))).unwrap();
+    let out = gensum_with("tests/data/mini.gtf", &format!("--gc-content --reference {}", fasta.display()));
+
+    let gc = |gene: &str| out.lines().find_map(|l| l.strip_prefix(gene)).unwrap().split('\t').nth(1).unwrap().to_string();
+    assert_eq!(gc("GA\t"), "0.5000");
+    assert_eq!(gc("GB\t"), "0.0000");
+    assert_eq!(gc("GC\t"), "NA");
+    assert_eq!(gc("GD\t"), "NA");
+    // the summary rows have an empty GC column, every row has the width of the header
+    let nohit: Vec<_> = out.lines().find(|l| l.starts_with("nohit\t")).unwrap().split('\t').collect();
+    assert_eq!(nohit.len(), 3);
+    assert_eq!(nohit[2], "");
+}
+
+#[test]
+fn gc_bias() {
+    let dir = TempDir::new("gcbias");
+    let (fasta, sam, report) = (dir.join("mini.fa"), dir.join("gc.sam"), dir.join("gc_bias.txt"));
+    // exon 1 of GA is G, the rest is A
+    let mut seq = vec![b'A'; 10000];
+    seq[100..200].fill(b'G');
+    fs::write(&fasta, format!(">1\n{}\n", String::from_utf8(seq).unwrap())).unwrap();
+    // three G reads on GA and an A read on GB
+    let mut records = String::from("@SQ\tSN:1\tLN:10000\n");
+    for (name, pos, base) in [("g1", 111, "G"), ("g2", 121, "G"), ("g3", 131, "G"), ("a1", 1011, "A")] {
+        records.push_str(&format!("{}\t0\t1\t{}\t60\t50M\t*\t0\t0\t{}\t*\n", name, pos, base.repeat(50)));
+    }
+    fs::write(&sam, records).unwrap();
+    let out = run_args(&["-b", &sam.to_string_lossy(), "-g", &data("tests/data/mini.gtf"), "--reference", &fasta.to_string_lossy(),
+        "--gc-bias", &report.to_string_lossy()]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let report = fs::read_to_string(&report).unwrap();
+
+    // GA has 51 windows of 50 G, 51 of 0 G and one of each count in between, GB 51 of 0 G
+    let rows: Vec<&str> = report.lines().collect();
+    assert_eq!(rows.len(), 52);
+    assert_eq!(rows[1], "0\t0.2500\t0.5033\t0.497");
+    assert_eq!(rows[51], "100\t0.7500\t0.2533\t2.961");
+    assert!(String::from_utf8_lossy(&out.stdout).contains("\ngc_bias\t0.4967\n"));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("strong GC bias"));
+}
+
+#[test]
+fn biotype_selection() {
+    let without_lincrna = gensum_with("tests/data/mini.gtf", "--exclude-biotype lincRNA");
+    let coding = gensum_with("tests/data/mini.gtf", "--include-biotype protein_coding,antisense");
+    assert_eq!(without_lincrna, coding);
+    assert!(!coding.contains("\nGB\t"));
+    assert!(coding.contains("\nGD\t"));
+    // the reads on GB are no longer assigned
+    let assigned = |summary: &str| summary.lines().filter(|l| l.starts_with('G')).map(|l| l.split('\t').nth(1).unwrap().parse::<u64>().unwrap()).sum::<u64>();
+    assert_eq!(assigned(&coding), assigned(&gensum("")) - 4);
+
+    // a biotype that is not in the annotation is reported
+    let out = run(&["--include-biotype", "protein_coding,lncRNA"]);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("--include-biotype lncRNA matches no gene") && !stderr.contains("protein_coding matches"), "{}", stderr);
+}
+
+#[test]
+fn merged_annotations() {
+    let dir = TempDir::new("merged");
+    let gtf = fs::read_to_string(data("tests/data/mini.gtf")).unwrap();
+    let (host, virus) = (dir.join("host.gtf"), dir.join("virus.gtf"));
+    let is_host = |l: &&str| l.contains("\"GA\"") || l.contains("\"GB\"") || l.contains("\"GC\"");
+    fs::write(&host, gtf.lines().filter(is_host).collect::<Vec<_>>().join("\n")).unwrap();
+    fs::write(&virus, gtf.lines().filter(|l| !is_host(l)).collect::<Vec<_>>().join("\n")).unwrap();
+    let merged = gensum_with(&host.to_string_lossy(), &format!("-g {}", virus.display()));
+
+    let single = gensum("");
+    assert!(single.ends_with(&format!("\nassigned_mini\t{}\n", single.lines().filter(|l| l.starts_with('G')).map(|l| l.split('\t').nth(1).unwrap().parse::<u64>().unwrap()).sum::<u64>())));
+    let mut lines = merged.lines();
+    for line in single.lines().filter(|l| !l.starts_with("assigned_")) {
+        assert_eq!(lines.next(), Some(line));
+    }
+    let count = |gene: &str| single.lines().find_map(|l| l.strip_prefix(gene)).unwrap().trim().parse::<u64>().unwrap();
+    assert_eq!(lines.next(), Some(format!("assigned_host\t{}", count("GA\t") + count("GB\t") + count("GC\t"))).as_deref());
+    assert_eq!(lines.next(), Some(format!("assigned_virus\t{}", count("GD\t") + count("GE\t") + count("GF\t"))).as_deref());
+    assert_eq!(lines.next(), None);
+
+    // files with the same name get unique lines
+    fs::create_dir(dir.join("other")).unwrap();
+    let other = dir.join("other").join("host.gtf");
+    fs::rename(&virus, &other).unwrap();
+    let merged = gensum_with(&host.to_string_lossy(), &format!("-g {}", other.display()));
+    assert!(merged.contains("\nassigned_host\t") && merged.contains("\nassigned_host_2\t"), "{}", merged);
+}
+
+#[test]
+fn transcriptome_alignments() {
+    let run = |opts: &str| {
+        let out = Command::new(env!("CARGO_BIN_EXE_gensum"))
+            .args(["-b", &data("tests/data/mini_tx.sam"), "-g", &data("tests/data/mini.gtf")])
+            .args(["--tx2gene", &data("tests/data/mini.tx2gene")])
+            .args(opts.split_whitespace())
+            .output()
+            .unwrap();
+        (out.status.success(), String::from_utf8(out.stdout).unwrap())
+    };
+    let line = |out: &str, key: &str| out.lines().find(|l| l.split('\t').next() == Some(key)).map(String::from);
+
+    // isoforms of one gene are assigned, the pair is counted once
+    let (ok, out) = run("-q 0");
+    assert!(ok);
+    for (key, value) in [("GA", 3), ("GB", 1), ("GE", 0), ("ambiguous", 1), ("chr_not_in_gtf", 1), ("secondary_alignments", 2)] {
+        assert_eq!(line(&out, key), Some(format!("{}\t{}", key, value)));
+    }
+    let (_, stranded) = run("-q 0 --strandness F");
+    assert_eq!(line(&stranded, "GB").as_deref(), Some("GB\t0"));
+    assert_eq!(line(&stranded, "wrong_strand").as_deref(), Some("wrong_strand\t1"));
+
+    assert!(!run("--intronic").0);
+
+    // the alignments of a read are not adjacent in coordinate sorted files
+    let dir = TempDir::new("transcriptome");
+    let sorted = dir.join("sorted.sam");
+    fs::write(&sorted, fs::read_to_string(data("tests/data/mini_tx.sam")).unwrap().replacen("SO:unsorted", "SO:coordinate", 1)).unwrap();
+    let out = run_args(&["-b", &sorted.to_string_lossy(), "-g", &data("tests/data/mini.gtf"), "--tx2gene", &data("tests/data/mini.tx2gene")]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("coordinate sorted"));
+}
+
+#[test]
+fn selftest() {
+    let out = run_args(&["selftest"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+}
+
+#[test]
+fn featurecounts_summary() {
+    // the summary of a featureCounts 2.0 run, its header and categories in the order it writes them
+    let summary = fs::read_to_string(data("tests/data/featurecounts.summary")).unwrap();
+    let expected: Vec<&str> = summary.lines().map(|l| l.split('\t').next().unwrap()).collect();
+
+    let opts = "--intronic --max-intron-span 100000 --validate-introns --min-hq-overlap 1 --weight-by-overlap";
+    let out = gensum(&format!("--summary-style featurecounts {}", opts));
+    let lines: Vec<&str> = out.lines().skip_while(|l| !l.starts_with("Status\t")).collect();
+    assert_eq!(lines[0], "Status\tmini");
+    assert_eq!(lines.iter().map(|l| l.split('\t').next().unwrap()).collect::<Vec<_>>(), expected);
+
+    // Assigned includes the weighted fractions of the ambiguous reads
+    let value = |l: &str| l.split('\t').nth(1).unwrap().parse::<f64>().unwrap();
+    let genes: f64 = out.lines().take_while(|l| !l.starts_with("Status\t")).map(value).sum();
+    assert_eq!(lines[1], format!("Assigned\t{}", genes.round()));
+
+    // every read of the gensum summary is in a category, except the qc failed reads and the
+    // malformed cigars that featureCounts does not report
+    let gensum_out = gensum(opts);
+    let reads: f64 = gensum_out.lines()
+        .filter(|l| !l.starts_with('#') && !l.starts_with("gene_id\t") && !l.starts_with("assigned_"))
+        .filter(|l| !l.starts_with("qc_failed\t") && !l.starts_with("malformed_cigar\t"))
+        .map(value)
+        .sum();
+    assert_eq!(lines[1..].iter().map(|l| value(l)).sum::<f64>(), reads.round());
+}
+
+#[test]
+fn weighted_totals() {
+    let dir = TempDir::new("weighted");
+    let (biotypes, table, groups, qc) = (dir.join("biotypes.txt"), dir.join("table.txt"), dir.join("groups.txt"), dir.join("qc.txt"));
+    fs::write(&table, "GA\tall\nGB\tall\nGC\tall\nGD\tall\nGE\tall\nGF\tall\n").unwrap();
+    fs::write(&qc, "GC\n").unwrap();
+    let summary = gensum(&format!("--weight-by-overlap --biotype-counts {} --group-table {} --group-counts {} --qc-genes {}",
+        biotypes.display(), table.display(), groups.display(), qc.display()));
+
+    // the totals include the fractions of the ambiguous reads
+    let genes: f64 = summary.lines().filter(|l| l.starts_with('G')).map(|l| l.split('\t').nth(1).unwrap().parse::<f64>().unwrap()).sum();
+    let total = format!("{:.2}", genes);
+    assert!(summary.contains(&format!("\nassigned_mini\t{}\n", total)), "{}", summary);
+    let biotypes: f64 = fs::read_to_string(&biotypes).unwrap().lines().skip(1).map(|l| l.split('\t').nth(1).unwrap().parse::<f64>().unwrap()).sum();
+    assert_eq!(format!("{:.2}", biotypes), total);
+    assert_eq!(fs::read_to_string(&groups).unwrap(), format!("group\tgenes\tcount\nall\t6\t{}\n", total));
+    let gc = summary.lines().find_map(|l| l.strip_prefix("GC\t")).unwrap().parse::<f64>().unwrap();
+    assert!(summary.contains(&format!("\nqc_GC\t{:.2}\nqc_GC_cpm\t{:.2}\n", gc, gc * 1e6 / genes)), "{}", summary);
+}
+
+#[test]
+fn group_table_checked() {
+    let dir = TempDir::new("group-table");
+    let (table, groups, out) = (dir.join("table.txt"), dir.join("groups.txt"), dir.join("out.txt"));
+    let count = || run(&["-o", &out.to_string_lossy(), "--group-table", &table.to_string_lossy(), "--group-counts", &groups.to_string_lossy()]);
+
+    // the table is checked before the reads are counted, nothing is written
+    fs::write(&table, "GA\tfamily\nGB family\n").unwrap();
+    let res = count();
+    assert!(!res.status.success());
+    assert!(String::from_utf8_lossy(&res.stderr).contains("Line 2 does not have a gene and a second column"), "{}", String::from_utf8_lossy(&res.stderr));
+    assert!(!out.exists() && !groups.exists());
+
+    fs::write(&table, "ENSG00000141510\tfamily\n").unwrap();
+    let res = count();
+    assert!(!res.status.success());
+    assert!(String::from_utf8_lossy(&res.stderr).contains("None of the genes of --group-table"));
+    assert!(!out.exists());
+
+    // gene names are found too
+    fs::write(&table, "GA\tfamily\nB\tfamily\nGX\tother\n").unwrap();
+    let res = count();
+    assert!(res.status.success(), "{}", String::from_utf8_lossy(&res.stderr));
+    assert_eq!(fs::read_to_string(&groups).unwrap(), "group\tgenes\tcount\nfamily\t2\t13\nother\t0\t0\n");
+}
+
+#[test]
+fn verify_sample_stdin() {
+    // the genes are fetched from an index, rejected before the reads are counted
+    let res = Command::new(env!("CARGO_BIN_EXE_gensum"))
+        .args(["-b", "-", "-g", &data("tests/data/mini.gtf"), "--verify-sample", "2"])
+        .stdin(fs::File::open(data("tests/data/mini.sam")).unwrap())
+        .output()
+        .unwrap();
+    assert!(!res.status.success());
+    assert!(String::from_utf8_lossy(&res.stderr).contains("--verify-sample fetches the genes"));
+    assert!(res.stdout.is_empty());
+}
+
+#[test]
+fn fuzz_entry_points() {
+    // the fuzz targets run on the bundled data, e.g. as the seed corpus
+    for name in ["mini.gtf", "mini.gff3", "mini.bed", "mini.tx2gene"] {
+        gensum::fuzzing::annotation(&fs::read(data(&format!("tests/data/{}", name))).unwrap());
+    }
+    for line in fs::read_to_string(data("tests/data/mini.sam")).unwrap().lines().filter(|l| !l.starts_with('@')) {
+        gensum::fuzzing::sam_record(line.as_bytes());
+    }
+    gensum::fuzzing::sam_record(b"r\t0\t1\t100\t60\t0M5N10M\t*\t0\t0\tACGTACGTAC\t*");
+}